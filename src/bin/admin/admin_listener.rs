@@ -1,52 +1,350 @@
-use super::node_store::NodeStore;
+use super::audit::{self, Actor, AuditFilter, AuditLog, Outcome};
+use super::auth_guard::AuthGuard;
+use super::node_store::{
+    AccessError, DEFAULT_CHAIN_LENGTH, MAX_CHAIN_LENGTH, MIN_CHAIN_LENGTH, Node, NodeStore,
+};
+use super::password_gen::generate_password;
+use super::password_hash::{hash_password, is_password_hash};
+use super::session_token::SessionTokens;
+use crate::pool::connection_guard::{self, ConnectionGuard, IpRules, Limits};
+use crate::pool::port_pool::PortPool;
+use crate::pool::traffic::{self, Bandwidth, TrafficStore};
+use crate::reverse_proxy::routing_table::RoutingTable;
+use crate::session::session_registry::SessionRegistry;
+use serde_json::{Value, json};
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, OffsetDateTime};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
-pub async fn start_admin_listener(node_store: Arc<NodeStore>) {
-    let listener = TcpListener::bind("127.0.0.1:6969").await.unwrap();
-    println!("Admin API listening on 127.0.0.1:6969");
+//No command comes anywhere near this, a longer line ends the connection
+const MAX_LINE: usize = 16 * 1024;
+//Time for the TLS handshake, and then to send the AUTH-ADMIN line
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+//An admin connection with no command for this long is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Everything the admin commands can look at or change.
+pub struct AdminContext {
+    pub node_store: Arc<NodeStore>,
+    pub port_pool: Arc<PortPool>,
+    pub guard: Arc<ConnectionGuard>,
+    pub traffic: Arc<TrafficStore>,
+    pub auth_guard: Arc<AuthGuard>,
+    pub tokens: Arc<SessionTokens>,
+    pub audit_log: Arc<AuditLog>,
+    pub routing_table: Arc<RoutingTable>,
+    pub sessions: Arc<SessionRegistry>,
+}
+
+/// Serve the admin commands on `addr`.
+///
+/// With `tls` the connection is encrypted, and with `token` every connection has to start
+/// with `AUTH-ADMIN <token>` before any command is run. Both are required for an address
+/// other than loopback, see the server's `ADMIN_LISTEN_ADDR`.
+pub async fn start_admin_listener(
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    token: Option<String>,
+    ctx: Arc<AdminContext>,
+) {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    println!(
+        "Admin API listening on {}{}",
+        addr,
+        if tls.is_some() { " (TLS)" } else { "" }
+    );
+    //compared as hashes, which compare in constant time
+    let token = token.map(|token| blake3::hash(token.as_bytes()));
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        let store = node_store.clone();
+        let (stream, admin_addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Accept error on the admin port {}: {:?}", addr, e);
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let ctx = ctx.clone();
         tokio::spawn(async move {
-            handle_admin(stream, store).await;
+            match tls {
+                Some(acceptor) => {
+                    match tokio::time::timeout(AUTH_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => handle_admin(stream, admin_addr, token, ctx).await,
+                        Ok(Err(e)) => {
+                            eprintln!("Admin TLS handshake with {} failed: {}", admin_addr, e)
+                        }
+                        Err(_) => eprintln!("Admin TLS handshake with {} timed out", admin_addr),
+                    }
+                }
+                None => handle_admin(stream, admin_addr, token, ctx).await,
+            }
         });
     }
 }
 
-async fn handle_admin(stream: TcpStream, node_store: Arc<NodeStore>) {
-    let (reader, mut writer) = stream.into_split();
+async fn handle_admin<S>(
+    stream: S,
+    admin_addr: SocketAddr,
+    token: Option<blake3::Hash>,
+    ctx: Arc<AdminContext>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let AdminContext {
+        node_store,
+        port_pool,
+        guard,
+        traffic,
+        auth_guard,
+        tokens,
+        audit_log,
+        routing_table,
+        sessions,
+    } = &*ctx;
+    let (reader, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
     let mut line = String::new();
-    while reader.read_line(&mut line).await.unwrap() > 0 {
-        let parts: Vec<&str> = line.trim().splitn(2, ' ').collect();
-        let mut out = String::new();
+    if let Some(token) = token {
+        let authorized = matches!(
+            read_line(&mut reader, &mut line, AUTH_TIMEOUT).await,
+            Ok(true)
+        ) && line
+            .trim()
+            .strip_prefix("AUTH-ADMIN ")
+            .is_some_and(|given| blake3::hash(given.as_bytes()) == token);
+        if !authorized {
+            audit_log.record(
+                "admin_auth_failed",
+                Actor::Admin(admin_addr),
+                None,
+                Outcome::Failure,
+                "invalid admin token",
+            );
+            let _ = writer
+                .write_all(b"ERR: Invalid admin token\n--END--\n")
+                .await;
+            return;
+        }
+        let _ = writer.write_all(b"OK: Welcome back sir\n--END--\n").await;
+        line.clear();
+    }
+    loop {
+        match read_line(&mut reader, &mut line, IDLE_TIMEOUT).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("Closing admin connection {}: {}", admin_addr, e);
+                let _ = writer
+                    .write_all(format!("ERR: {}\n--END--\n", e).as_bytes())
+                    .await;
+                break;
+            }
+        }
+        //`json <command>` runs the command and answers with one JSON object instead, for scripts
+        let (json, command) = match line.trim_start().strip_prefix("json ") {
+            Some(command) => (true, command),
+            None => (false, line.as_str()),
+        };
+        let mut data = Value::Null;
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        let mut out = Reply::new();
         match parts.as_slice() {
-            ["add" | "create", node_id] => {
-                let new_password = node_store.add_node(node_id.to_string());
-                out.push_str("OK: Sir, node has been added\n");
-                out.push_str(&format!("Node ID: {}\n", node_id));
-                out.push_str(&format!("Password: {}\n", new_password));
-                out.push_str(
-                    "Please give this to your client node so that they can enter our dugeon.\n",
-                );
+            ["add" | "create", node_id, "password"] => {
+                let password = generate_password();
+                match hash_password(&password) {
+                    Err(e) => out.err(&e.to_string()),
+                    Ok(hash) => match node_store.add_password_node(node_id.to_string(), hash) {
+                        Err(e) => {
+                            out.conflict(&format!("Sir, {}: {}, remove it first", e, node_id))
+                        }
+                        Ok(()) => {
+                            data = json!({ "node_id": node_id, "password": password });
+                            out.ok("Sir, password node has been added");
+                            out.push_str(&format!("Node ID: {}\n", node_id));
+                            out.push_str(&format!("Password: {}\n", password));
+                            out.push_str(
+                                "Only its hash is kept, this is the last time anyone sees it.\n",
+                            );
+                            out.push_str(&format!(
+                                "The node logs in with: client --node-id {} (the password is prompted for)\n",
+                                node_id
+                            ));
+                        }
+                    },
+                }
+                out.push_str("--END--\n");
+            }
+            ["add" | "create", node_id, phc] if phc.starts_with("$argon2id$") => {
+                //the admin hashed the password themselves, e.g. with `hash <password>`
+                if !is_password_hash(phc) {
+                    out.err("That is not a valid Argon2id hash");
+                } else if let Err(e) =
+                    node_store.add_password_node(node_id.to_string(), phc.to_string())
+                {
+                    out.conflict(&format!("Sir, {}: {}, remove it first", e, node_id));
+                } else {
+                    data = json!({ "node_id": node_id });
+                    out.ok("Sir, password node has been added");
+                    out.push_str(&format!(
+                        "The node logs in with: client --node-id {}\n",
+                        node_id
+                    ));
+                }
+                out.push_str("--END--\n");
+            }
+            ["add" | "create", node_id, rest @ ..] if rest.len() <= 1 => {
+                let chain_length = match rest {
+                    [] => Some(DEFAULT_CHAIN_LENGTH),
+                    [length] => length
+                        .replace('_', "")
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|n| (MIN_CHAIN_LENGTH..=MAX_CHAIN_LENGTH).contains(n)),
+                    _ => None,
+                };
+                match chain_length
+                    .map(|length| (length, node_store.add_node(node_id.to_string(), length)))
+                {
+                    Some((_, Err(e))) => {
+                        out.conflict(&format!("Sir, {}: {}, remove it first", e, node_id))
+                    }
+                    Some((chain_length, Ok(token))) => {
+                        data = json!({
+                            "node_id": node_id,
+                            "chain_length": chain_length,
+                            "enrollment_token": token,
+                        });
+                        out.ok("Sir, node has been added");
+                        out.push_str(&format!("Node ID: {}\n", node_id));
+                        out.push_str(&format!("Chain Length: {} logins\n", chain_length));
+                        out.push_str(&format!("Enrollment Token: {}\n", token));
+                        out.push_str(
+                            "Please give this to your client node so that they can enter our dugeon: client --enroll <token>\n",
+                        );
+                        out.push_str("The token works once, within 24 hours.\n");
+                    }
+                    None => out.err(&format!(
+                        "Chain length must be a number from {} to {}",
+                        MIN_CHAIN_LENGTH, MAX_CHAIN_LENGTH
+                    )),
+                }
+                out.push_str("--END--\n");
+            }
+            ["enroll", node_id] => {
+                match node_store.issue_enrollment(node_id) {
+                    None if node_store
+                        .get_node(node_id.to_string())
+                        .is_some_and(|node| node.password_hash.is_some()) =>
+                    {
+                        out.err("A password node has no hash chain to enroll")
+                    }
+                    Some(token) => {
+                        out.ok("New enrollment token issued, the current chain works until it is redeemed");
+                        out.push_str(&format!("Enrollment Token: {}\n", token));
+                    }
+                    None => out.not_found("I cannot find the node sir..."),
+                }
                 out.push_str("--END--\n");
             }
             ["remove" | "delete" | "destroy", node_id] => {
-                node_store.remove_node(node_id.to_string());
-                out.push_str("OK: Node removed\n");
+                if node_store.remove_node(node_id) {
+                    out.ok("Node removed");
+                    //its ports, listeners and registry entries go with the session
+                    if let Some(handle) = sessions.get(node_id) {
+                        handle.close(b"Node removed by the admin").await;
+                        out.ok("Its live session was closed");
+                    }
+                } else {
+                    out.not_found("I cannot find the node sir...");
+                }
+                //Tokens, leases and traffic are kept in files of their own, so they are cleaned
+                //up even for a node the store doesn't know (anymore), e.g. after a failed
+                //remove. A node added again under the same id starts clean
+                if let Err(e) = tokens.revoke(node_id) {
+                    out.err(&format!(
+                        "Could not save the revocation of its session tokens: {}",
+                        e
+                    ));
+                }
+                if let Err(e) = port_pool.leases().unreserve_node(node_id).await {
+                    out.err(&format!("Could not release leases of the node: {}", e));
+                }
+                if let Err(e) = traffic.remove_node(node_id) {
+                    out.err(&format!("Could not forget the traffic of the node: {}", e));
+                }
                 out.push_str("--END--\n");
             }
             ["view", node_id] => match node_store.get_node(node_id.to_string()) {
                 Some(node) => {
+                    data = node_json(&node);
+                    data["leased_ports"] = json!(port_pool.leases().ports_of(node_id));
                     out.push_str("\n+---------------+--------------------------------------------------------------+\n");
                     out.push_str(&format!("| {:<13} | {:<60} |\n", "Field", "Value"));
                     out.push_str("+---------------+--------------------------------------------------------------+\n");
                     out.push_str(&format!("| {:<13} | {:<60} |\n", "Node ID", node.node_id));
                     out.push_str(&format!(
                         "| {:<13} | {:<60} |\n",
-                        "Anchor Hash", node.anchor
+                        "Status",
+                        node_status(&node)
+                    ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Description", node.description
+                    ));
+                    out.push_str(&format!("| {:<13} | {:<60} |\n", "Owner", node.owner));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Labels",
+                        node.labels
+                            .iter()
+                            .map(|(key, value)| format!("{}={}", key, value))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Expires At",
+                        node.expires_at
+                            .map(|dt| dt.to_string())
+                            .unwrap_or_else(|| "(Never)".to_string())
+                    ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Anchor Hash",
+                        if node.password_hash.is_some() {
+                            "(None, logs in with a password)"
+                        } else if node.anchor.is_empty() {
+                            "(Not enrolled yet)"
+                        } else {
+                            node.anchor.as_str()
+                        }
+                    ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Hash Chain",
+                        if node.password_hash.is_some() {
+                            "(Password node)".to_string()
+                        } else {
+                            format!(
+                                "{} of {} logins left{}",
+                                node.current_index,
+                                node.chain_length,
+                                if node.pending.is_some() {
+                                    ", next chain proposed"
+                                } else {
+                                    ""
+                                }
+                            )
+                        }
                     ));
                     out.push_str(&format!(
                         "| {:<13} | {:<60} |\n",
@@ -59,73 +357,941 @@ async fn handle_admin(stream: TcpStream, node_store: Arc<NodeStore>) {
                             .map(|dt| dt.to_string())
                             .unwrap_or_else(|| "(Never)".to_string())
                     ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Port Quota", node.port_quota
+                    ));
+                    let leased = port_pool.leases().ports_of(node_id);
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Leased Ports",
+                        if leased.is_empty() {
+                            "(None)".to_string()
+                        } else {
+                            leased
+                                .iter()
+                                .map(|p| p.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        }
+                    ));
+                    let record = traffic.get(node_id).unwrap_or_default();
+                    let usage = |used: u64, quota: u64| {
+                        if quota == 0 {
+                            format!("{} (no quota)", traffic::format_size(used))
+                        } else {
+                            format!(
+                                "{} of {}",
+                                traffic::format_size(used),
+                                traffic::format_size(quota)
+                            )
+                        }
+                    };
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Bandwidth",
+                        format!(
+                            "in {}, out {}",
+                            traffic::format_rate(record.bandwidth.ingress_bps),
+                            traffic::format_rate(record.bandwidth.egress_bps)
+                        )
+                    ));
+                    for (service, bandwidth) in &record.services {
+                        out.push_str(&format!(
+                            "| {:<13} | {:<60} |\n",
+                            "",
+                            format!(
+                                "{}: in {}, out {}",
+                                service,
+                                traffic::format_rate(bandwidth.ingress_bps),
+                                traffic::format_rate(bandwidth.egress_bps)
+                            )
+                        ));
+                    }
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Traffic Today",
+                        usage(record.day_bytes, record.daily_quota)
+                    ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Traffic Month",
+                        usage(record.month_bytes, record.monthly_quota)
+                    ));
+                    out.push_str(&format!(
+                        "| {:<13} | {:<60} |\n",
+                        "Quota State",
+                        match traffic.quota_state(node_id) {
+                            traffic::QuotaState::Available => "ok".to_string(),
+                            traffic::QuotaState::Exhausted { period, .. } =>
+                                format!("{} quota used up, no traffic is relayed", period),
+                        }
+                    ));
                     out.push_str("+---------------+--------------------------------------------------------------+\n");
                     out.push_str("--END--\n");
                 }
                 None => {
-                    out.push_str(
-                        "I cannot find the node sir. Are you sure about the id of the node?\n",
+                    out.not_found(
+                        "I cannot find the node sir. Are you sure about the id of the node?",
                     );
                     out.push_str("--END--\n");
                 }
             },
-            ["list"] => {
-                //this is our table header
-                let id_width = 18;
-                let hash_width = 32;
-                let created_width = 22;
-                let last_login_width = 22;
+            ["list", selector @ ..] => match parse_labels(selector) {
+                Ok(selector) => {
+                    //this is our table header
+                    let id_width = 18;
+                    let status_width = 8;
+                    let owner_width = 16;
+                    let hash_width = 32;
+                    let created_width = 22;
+                    let last_login_width = 22;
+
+                    out.push_str(&format!(
+                        "{:<id_width$} | {:<status_width$} | {:<owner_width$} | {:<hash_width$} | {:<created_width$} | {:<last_login_width$}\n",
+                        "Node ID", "Status", "Owner", "Password Hash", "Created At", "Last Login",
+                    ));
+                    out.push_str(&format!(
+                        "{:-<id_width$}-+-{:-<status_width$}-+-{:-<owner_width$}-+-{:-<hash_width$}-+-{:-<created_width$}-+-{:-<last_login_width$}\n",
+                        "", "", "", "", "", "",
+                    ));
+
+                    //`list team=payments env=dev` shows the nodes carrying all of these labels
+                    let mut nodes: Vec<Node> = node_store
+                        .list_nodes()
+                        .into_iter()
+                        .filter(|node| node.has_labels(&selector))
+                        .collect();
+                    nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+                    data = Value::Array(nodes.iter().map(node_json).collect());
+                    for node in nodes {
+                        //This is to handle case when hash is too long
+                        let hash_display = if node.anchor.len() > hash_width {
+                            format!("{}...", &node.anchor[..(hash_width - 3)])
+                        } else {
+                            node.anchor.clone()
+                        };
+                        let last_login_display = node
+                            .last_login
+                            .map(|dt| dt.to_string())
+                            .unwrap_or_else(|| "(Never)".to_string());
 
+                        out.push_str(&format!(
+                            "{:<id_width$} | {:<status_width$} | {:<owner_width$} | {:<hash_width$} | {:<created_width$} | {:<last_login_width$}\n",
+                            node.node_id,
+                            node_status(&node),
+                            node.owner,
+                            hash_display,
+                            node.created_at,
+                            last_login_display,
+                        ));
+                    }
+                    out.push_str("--END--\n");
+                }
+                Err(e) => {
+                    out.err(&e.to_string());
+                    out.push_str("--END--\n");
+                }
+            },
+            ["describe", node_id, description @ ..] => {
+                if node_store.set_description(node_id, description.join(" ")) {
+                    out.ok(&format!("Description of {} updated", node_id));
+                } else {
+                    out.not_found("I cannot find the node sir...");
+                }
+                out.push_str("--END--\n");
+            }
+            ["owner", node_id, owner @ ..] if owner.len() <= 1 => {
+                let owner = owner.first().copied().unwrap_or_default();
+                if node_store.set_owner(node_id, owner.to_string()) {
+                    out.ok(&format!("Owner of {} updated", node_id));
+                } else {
+                    out.not_found("I cannot find the node sir...");
+                }
+                out.push_str("--END--\n");
+            }
+            ["label", node_id, labels @ ..] if !labels.is_empty() => {
+                match parse_labels(labels) {
+                    Ok(_) if node_store.get_node(node_id.to_string()).is_none() => {
+                        out.not_found("I cannot find the node sir...")
+                    }
+                    Ok(labels) => {
+                        for (key, value) in labels {
+                            node_store.set_label(node_id, key, Some(value));
+                        }
+                        out.ok(&format!("Labels of {} updated", node_id));
+                    }
+                    Err(e) => out.err(&e.to_string()),
+                }
+                out.push_str("--END--\n");
+            }
+            ["unlabel", node_id, keys @ ..] if !keys.is_empty() => {
+                if node_store.get_node(node_id.to_string()).is_some() {
+                    for key in keys {
+                        node_store.set_label(node_id, key, None);
+                    }
+                    out.ok(&format!("Labels of {} updated", node_id));
+                } else {
+                    out.not_found("I cannot find the node sir...");
+                }
+                out.push_str("--END--\n");
+            }
+            ["expire", node_id, when] => {
+                match parse_expiry(when) {
+                    Some(expires_at) => {
+                        if node_store.set_expiry(node_id, expires_at) {
+                            match expires_at {
+                                Some(expires_at) => out.ok(&format!(
+                                    "{} can log in until {}",
+                                    node_id, expires_at
+                                )),
+                                None => {
+                                    out.ok(&format!("{} never expires", node_id))
+                                }
+                            }
+                        } else {
+                            out.not_found("I cannot find the node sir...");
+                        }
+                    }
+                    None => out.err(&format!(
+                        "'{}' is neither a date (2026-12-31), an RFC 3339 time, a span like 30d nor 'never'",
+                        when
+                    )),
+                }
+                out.push_str("--END--\n");
+            }
+            [verb @ ("enable" | "disable"), node_id] => {
+                let enabled = *verb == "enable";
+                if node_store.set_enabled(node_id, enabled) {
+                    out.ok(&format!(
+                        "{} {}",
+                        node_id,
+                        if enabled {
+                            "may log in again"
+                        } else {
+                            "is disabled, its next login is refused"
+                        }
+                    ));
+                    if !enabled && let Some(handle) = sessions.get(node_id) {
+                        handle.close(b"Node disabled by the admin").await;
+                        out.ok("Its live session was closed");
+                    }
+                } else {
+                    out.not_found("I cannot find the node sir...");
+                }
+                out.push_str("--END--\n");
+            }
+            ["reserve", node_id, port] => match port.parse::<u16>() {
+                Ok(port) => {
+                    if node_store.get_node(node_id.to_string()).is_none() {
+                        out.not_found(
+                            "I cannot find the node sir. Are you sure about the id of the node?",
+                        );
+                    } else {
+                        match port_pool.reserve_port(node_id, port).await {
+                            Ok(()) => out.ok(&format!(
+                                "Port {} is now reserved for node {}",
+                                port, node_id
+                            )),
+                            Err(e) => out.err(&format!("Cannot reserve port {}: {}", port, e)),
+                        }
+                    }
+                    out.push_str("--END--\n");
+                }
+                Err(_) => {
+                    out.err(&format!("'{}' is not a valid port", port));
+                    out.push_str("--END--\n");
+                }
+            },
+            ["unreserve", port] => match port.parse::<u16>() {
+                Ok(port) => {
+                    match port_pool.leases().unreserve(port).await {
+                        Ok(Some(node_id)) => out.ok(&format!(
+                            "Port {} is no longer reserved for node {}",
+                            port, node_id
+                        )),
+                        Ok(None) => out.err(&format!("Port {} is not reserved", port)),
+                        Err(e) => out.err(&format!("Cannot unreserve port {}: {}", port, e)),
+                    }
+                    out.push_str("--END--\n");
+                }
+                Err(_) => {
+                    out.err(&format!("'{}' is not a valid port", port));
+                    out.push_str("--END--\n");
+                }
+            },
+            ["quota", node_id, quota] => match quota.parse::<usize>() {
+                Ok(quota) => {
+                    if node_store.set_port_quota(node_id, quota) {
+                        out.ok(&format!(
+                            "Node {} may now open up to {} public ports",
+                            node_id, quota
+                        ));
+                    } else {
+                        out.not_found(
+                            "I cannot find the node sir. Are you sure about the id of the node?",
+                        );
+                    }
+                    out.push_str("--END--\n");
+                }
+                Err(_) => {
+                    out.err(&format!("'{}' is not a valid quota", quota));
+                    out.push_str("--END--\n");
+                }
+            },
+            ["leases"] => {
+                out.push_str(&format!("{:<8} | {:<18}\n", "Port", "Node ID"));
+                out.push_str(&format!("{:-<8}-+-{:-<18}\n", "", ""));
+                let mut leases = port_pool.leases().list();
+                leases.sort_by_key(|lease| lease.port);
+                for lease in leases {
+                    out.push_str(&format!("{:<8} | {:<18}\n", lease.port, lease.node_id));
+                }
+                out.push_str("--END--\n");
+            }
+            [
+                verb @ ("allow" | "deny" | "unallow" | "undeny"),
+                network,
+                scope @ ..,
+            ] if scope.len() <= 1 => {
+                //No node id means the rule applies to every public listener
+                let node_id = scope.first().copied();
+                match connection_guard::parse_network(network) {
+                    Ok(network) => {
+                        let allow = matches!(*verb, "allow" | "unallow");
+                        let list = if allow { "allow" } else { "deny" };
+                        let target = node_id.unwrap_or("every node");
+                        if verb.starts_with("un") {
+                            if guard.remove_rule(node_id, allow, network) {
+                                out.ok(&format!(
+                                    "{} removed from the {} list of {}",
+                                    network, list, target
+                                ));
+                            } else {
+                                out.err(&format!(
+                                    "{} is not on the {} list of {}",
+                                    network, list, target
+                                ));
+                            }
+                        } else {
+                            guard.add_rule(node_id, allow, network);
+                            out.ok(&format!(
+                                "{} added to the {} list of {}",
+                                network, list, target
+                            ));
+                        }
+                    }
+                    Err(e) => out.err(&e.to_string()),
+                }
+                out.push_str("--END--\n");
+            }
+            ["limit", rate, max, prefix @ ..] if prefix.len() <= 1 => {
+                let ipv6_prefix = match prefix.first() {
+                    Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= 128),
+                    None => Some(guard.limits().ipv6_prefix),
+                };
+                match (rate.parse::<u32>(), max.parse::<u32>(), ipv6_prefix) {
+                    (Ok(rate_per_minute), Ok(max_per_ip), Some(ipv6_prefix)) => {
+                        guard.set_limits(Limits {
+                            rate_per_minute,
+                            max_per_ip,
+                            ipv6_prefix,
+                        });
+                        out.ok(&format!(
+                            "Each IPv4 address and IPv6 /{} may now open {} connections per minute and keep {} open (0 = no limit)",
+                            ipv6_prefix, rate_per_minute, max_per_ip
+                        ));
+                    }
+                    (_, _, None) => out.err("The IPv6 prefix must be a length from 0 to 128"),
+                    _ => out.err("Limits must be whole numbers, 0 turns a limit off"),
+                }
+                out.push_str("--END--\n");
+            }
+            ["firewall"] => {
+                let describe = |rules: &IpRules| {
+                    let join = |list: &[ipnet::IpNet]| {
+                        if list.is_empty() {
+                            "(Any)".to_string()
+                        } else {
+                            list.iter()
+                                .map(|n| n.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        }
+                    };
+                    format!(
+                        "allow {} | deny {}",
+                        join(&rules.allow),
+                        if rules.deny.is_empty() {
+                            "(None)".to_string()
+                        } else {
+                            join(&rules.deny)
+                        }
+                    )
+                };
+                out.push_str(&format!("Global: {}\n", describe(&guard.global_rules())));
+                for (node_id, rules) in guard.node_rules() {
+                    out.push_str(&format!("Node {}: {}\n", node_id, describe(&rules)));
+                }
+                let limits = guard.limits();
                 out.push_str(&format!(
-                    "{:<id_width$} | {:<hash_width$} | {:<created_width$} | {:<last_login_width$}\n",
-                    "Node ID", "Password Hash", "Created At", "Last Login",
-                    id_width = id_width, hash_width = hash_width, created_width = created_width, last_login_width = last_login_width,
+                    "Limits: {} connections/min, {} concurrent per IPv4 address or IPv6 /{} (0 = no limit)\n",
+                    limits.rate_per_minute, limits.max_per_ip, limits.ipv6_prefix
                 ));
+                let counters = &guard.counters;
                 out.push_str(&format!(
-                    "{:-<id_width$}-+-{:-<hash_width$}-+-{:-<created_width$}-+-{:-<last_login_width$}\n",
-                    "", "", "", "",
-                    id_width = id_width, hash_width = hash_width, created_width = created_width, last_login_width = last_login_width,
+                    "Accepted: {} | Denied: {} | Rate limited: {} | Over concurrency cap: {}\n",
+                    counters.accepted.load(Ordering::Relaxed),
+                    counters.denied.load(Ordering::Relaxed),
+                    counters.rate_limited.load(Ordering::Relaxed),
+                    counters.too_many.load(Ordering::Relaxed),
                 ));
-
-                for node in node_store.list_nodes() {
-                    //This is to handle case when hash is too long
-                    let hash_display = if node.anchor.len() > hash_width {
-                        format!("{}...", &node.anchor[..(hash_width - 3)])
+                out.push_str(&format!("{:<43} | {:<8}\n", "Busiest source", "Open"));
+                out.push_str(&format!("{:-<43}-+-{:-<8}\n", "", ""));
+                for (source, open) in guard.busiest(10) {
+                    //a single address reads better without its /32 or /128
+                    let source = if source.prefix_len() == source.max_prefix_len() {
+                        source.addr().to_string()
                     } else {
-                        node.anchor.clone()
+                        source.to_string()
                     };
-                    let last_login_display = node
-                        .last_login
-                        .map(|dt| dt.to_string())
-                        .unwrap_or_else(|| "(Never)".to_string());
-
+                    out.push_str(&format!("{:<43} | {:<8}\n", source, open));
+                }
+                out.push_str("--END--\n");
+            }
+            ["bandwidth", node_id, ingress, egress, service @ ..] if service.len() <= 1 => {
+                //Sizes per second, e.g. `bandwidth laptop_1 1M 512k web`, 0 for unlimited
+                match (traffic::parse_size(ingress), traffic::parse_size(egress)) {
+                    (Some(ingress_bps), Some(egress_bps)) => {
+                        let service = service.first().copied();
+                        let bandwidth = Bandwidth {
+                            ingress_bps,
+                            egress_bps,
+                        };
+                        match traffic.set_bandwidth(node_id, service, bandwidth) {
+                            Ok(()) => out.ok(&format!(
+                                "Bandwidth of {}{} is now in {}, out {}",
+                                node_id,
+                                service
+                                    .map(|s| format!(" service {}", s))
+                                    .unwrap_or_default(),
+                                traffic::format_rate(ingress_bps),
+                                traffic::format_rate(egress_bps)
+                            )),
+                            Err(e) => out.err(&e.to_string()),
+                        }
+                    }
+                    _ => out.err("Bandwidth must be a size like 1048576, 512k or 10M"),
+                }
+                out.push_str("--END--\n");
+            }
+            ["traffic", node_id, daily, monthly] => {
+                match (traffic::parse_size(daily), traffic::parse_size(monthly)) {
+                    (Some(daily), Some(monthly)) => {
+                        match traffic.set_quota(node_id, daily, monthly) {
+                            Ok(()) => out.ok(&format!(
+                                "Node {} may now move {} a day and {} a month (0 = no quota)",
+                                node_id,
+                                traffic::format_size(daily),
+                                traffic::format_size(monthly)
+                            )),
+                            Err(e) => out.err(&e.to_string()),
+                        }
+                    }
+                    _ => out.err("Quotas must be sizes like 1073741824, 500M or 10G"),
+                }
+                out.push_str("--END--\n");
+            }
+            ["unlock", node_id_or_ip] => {
+                if auth_guard.unlock(node_id_or_ip) {
+                    out.ok(&format!("{} may try to authenticate again", node_id_or_ip));
+                } else {
+                    out.err(&format!("{} has no failed attempts", node_id_or_ip));
+                }
+                out.push_str("--END--\n");
+            }
+            ["revoke", node_id] => {
+                if node_store.get_node(node_id.to_string()).is_some() {
+                    match tokens.revoke(node_id) {
+                        Ok(()) => out.ok(&format!(
+                            "Session tokens of {} revoked, it has to log in with its chain or password again",
+                            node_id
+                        )),
+                        Err(e) => out.err(&format!(
+                            "Session tokens of {} are revoked until the server restarts, saving failed: {}",
+                            node_id, e
+                        )),
+                    }
+                } else {
+                    out.not_found("I cannot find the node sir...");
+                }
+                out.push_str("--END--\n");
+            }
+            ["hash", password] => {
+                //For edge basic auth: the route keeps the hash, never the password
+                match hash_password(password) {
+                    Ok(hash) => out.ok(&hash.to_string()),
+                    Err(e) => out.err(&e.to_string()),
+                }
+                out.push_str("--END--\n");
+            }
+            ["audit", filters @ ..] => {
+                match parse_audit_filters(filters) {
+                    Ok((filter, limit)) => match audit_log.query(&filter, limit) {
+                        Ok(entries) => {
+                            data = serde_json::to_value(&entries).unwrap_or_default();
+                            out.push_str(&format!(
+                                "{:<20} | {:<18} | {:<26} | {:<18} | {:<7} | {}\n",
+                                "Time", "Event", "Actor", "Node ID", "Outcome", "Detail"
+                            ));
+                            out.push_str(&format!(
+                                "{:-<20}-+-{:-<18}-+-{:-<26}-+-{:-<18}-+-{:-<7}-+-{:-<20}\n",
+                                "", "", "", "", "", ""
+                            ));
+                            for entry in entries {
+                                out.push_str(&format!(
+                                    "{:<20} | {:<18} | {:<26} | {:<18} | {:<7} | {}\n",
+                                    entry.ts,
+                                    entry.event,
+                                    if entry.actor.is_empty() {
+                                        entry.ip.map(|ip| ip.to_string()).unwrap_or_default()
+                                    } else {
+                                        entry.actor
+                                    },
+                                    entry.node_id.as_deref().unwrap_or("-"),
+                                    entry.outcome.map(|o| o.to_string()).unwrap_or_default(),
+                                    entry.detail
+                                ));
+                            }
+                        }
+                        Err(e) => out.err(&format!("Cannot read the audit log: {}", e)),
+                    },
+                    Err(e) => out.err(&e.to_string()),
+                }
+                out.push_str("--END--\n");
+            }
+            ["routes"] => {
+                let mut rules = routing_table.list_rules();
+                rules.sort_by(|a, b| (&a.host, &a.path).cmp(&(&b.host, &b.path)));
+                data = rules
+                    .iter()
+                    .map(|rule| {
+                        json!({
+                            "host": rule.host,
+                            "path": rule.path,
+                            "backend": rule.backend_addr,
+                        })
+                    })
+                    .collect();
+                out.push_str(&format!(
+                    "{:<36} | {:<20} | {}\n",
+                    "Host", "Path", "Backend"
+                ));
+                out.push_str(&format!("{:-<36}-+-{:-<20}-+-{:-<20}\n", "", "", ""));
+                for rule in rules {
+                    out.push_str(&format!(
+                        "{:<36} | {:<20} | {}\n",
+                        rule.host, rule.path, rule.backend_addr
+                    ));
+                }
+                out.push_str("--END--\n");
+            }
+            ["route", "add", host, path, backend] => {
+                //`~<pattern>` is a regex host, `*.example.com` a wildcard
+                match host.strip_prefix('~') {
+                    Some(pattern) => match routing_table.insert_regex_rule(
+                        pattern,
+                        path.to_string(),
+                        backend.to_string(),
+                    ) {
+                        Ok(()) => out.ok(&format!("{}{} now goes to {}", host, path, backend)),
+                        Err(e) => out.err(&format!("Invalid host pattern: {}", e)),
+                    },
+                    None => {
+                        routing_table.insert_rule(
+                            host.to_string(),
+                            path.to_string(),
+                            backend.to_string(),
+                        );
+                        out.ok(&format!("{}{} now goes to {}", host, path, backend));
+                    }
+                }
+                out.push_str("--END--\n");
+            }
+            ["route", "rm" | "remove", host, path] => {
+                let removed = match host.strip_prefix('~') {
+                    Some(pattern) => {
+                        let found = routing_table
+                            .list_rules()
+                            .iter()
+                            .any(|rule| rule.host == *host && rule.path == *path);
+                        routing_table.remove_regex_rule(pattern, path);
+                        found
+                    }
+                    None => routing_table.remove_rule(host.to_string(), path.to_string()),
+                };
+                if removed {
+                    out.ok(&format!("Route {}{} removed", host, path));
+                } else {
+                    out.err(&format!("There is no route {}{}", host, path));
+                }
+                out.push_str("--END--\n");
+            }
+            ["sessions"] => {
+                let mut handles = sessions.list();
+                handles.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+                data = handles
+                    .iter()
+                    .map(|handle| {
+                        json!({
+                            "node_id": handle.node_id,
+                            "session_id": handle.id,
+                            "remote_addr": handle.conn.remote_address().to_string(),
+                            "rtt_ms": handle.conn.rtt().as_millis() as u64,
+                        })
+                    })
+                    .collect();
+                out.push_str(&format!(
+                    "{:<18} | {:<10} | {:<40} | {}\n",
+                    "Node ID", "Session", "Remote Address", "RTT"
+                ));
+                out.push_str(&format!(
+                    "{:-<18}-+-{:-<10}-+-{:-<40}-+-{:-<8}\n",
+                    "", "", "", ""
+                ));
+                for handle in handles {
                     out.push_str(&format!(
-                        "{:<id_width$} | {:<hash_width$} | {:<created_width$} | {:<last_login_width$}\n",
-                        node.node_id,
-                        hash_display,
-                        node.created_at,
-                        last_login_display,
-                        id_width = id_width, hash_width = hash_width, created_width = created_width, last_login_width = last_login_width,
+                        "{:<18} | {:<10} | {:<40} | {}ms\n",
+                        handle.node_id,
+                        handle.id,
+                        handle.conn.remote_address(),
+                        handle.conn.rtt().as_millis()
                     ));
                 }
                 out.push_str("--END--\n");
             }
+            ["kick", node_id] => {
+                match sessions.get(node_id) {
+                    Some(handle) => {
+                        handle.close(b"Closed by the admin").await;
+                        out.ok(&format!(
+                            "Session of {} closed, it may reconnect unless you disable or revoke it",
+                            node_id
+                        ));
+                    }
+                    None => out.err(&format!("{} has no live session", node_id)),
+                }
+                out.push_str("--END--\n");
+            }
             ["help"] => {
                 out.push_str("Common spell you would like to use:\n");
-                out.push_str("add/create <node_id>\n");
+                out.push_str("add/create <node_id> [chain_length]\n");
+                out.push_str("add/create <node_id> password|<argon2id hash>\n");
+                out.push_str("enroll <node_id>\n");
                 out.push_str("remove/delete/destroy <node_id>\n");
                 out.push_str("view <node_id>\n");
-                out.push_str("list\n");
+                out.push_str("list [label=value...]\n");
+                out.push_str("describe <node_id> [text]\n");
+                out.push_str("owner <node_id> [owner]\n");
+                out.push_str("label <node_id> <key=value...>\n");
+                out.push_str("unlabel <node_id> <key...>\n");
+                out.push_str("expire <node_id> <date|30d|never>\n");
+                out.push_str("enable|disable <node_id>\n");
+                out.push_str("reserve <node_id> <port>\n");
+                out.push_str("unreserve <port>\n");
+                out.push_str("leases\n");
+                out.push_str("quota <node_id> <max_ports>\n");
+                out.push_str("allow|deny <cidr> [node_id]\n");
+                out.push_str("unallow|undeny <cidr> [node_id]\n");
+                out.push_str("limit <conns_per_min> <max_conns_per_ip> [ipv6_prefix]\n");
+                out.push_str("firewall\n");
+                out.push_str("bandwidth <node_id> <in_per_sec> <out_per_sec> [service]\n");
+                out.push_str("traffic <node_id> <daily_quota> <monthly_quota>\n");
+                out.push_str("unlock <node_id|ip>\n");
+                out.push_str("revoke <node_id>\n");
+                out.push_str("hash <password>\n");
+                out.push_str("routes\n");
+                out.push_str("route add <host|*.domain|~regex> <path> <node_id:service>\n");
+                out.push_str("route rm <host|*.domain|~regex> <path>\n");
+                out.push_str("sessions\n");
+                out.push_str("kick <node_id>\n");
+                out.push_str(
+                    "audit [node=<node_id>] [event=<type>] [since=<time>] [until=<time>] [limit=<n>]\n",
+                );
                 out.push_str("Cast 'exit' or 'quit' to quit.\n");
                 out.push_str("Cast 'help' to see what inside your magic book.\n");
                 out.push_str("--END--\n");
             }
             _ => {
-                out.push_str("ERR: I'm afraid... Wrong spell sir. Try again or cast 'help'\n");
+                out.err("I'm afraid... Wrong spell sir. Try again or cast 'help'");
                 out.push_str("--END--\n");
             }
         }
-        writer.write_all(out.as_bytes()).await.unwrap();
+        //node changes are written out before the admin hears they worked
+        if let Err(e) = node_store.save_if_changed().await {
+            let end = out
+                .text
+                .strip_suffix("--END--\n")
+                .map_or(out.text.len(), str::len);
+            out.text.truncate(end);
+            out.err(&format!(
+                "Sir, the change is live but could not be saved: {}",
+                e
+            ));
+            out.push_str("--END--\n");
+        }
+        let status = out.status;
+        //every change the admin makes ends up in the audit log, whether it worked or not
+        if let Some((event, node_id, detail)) = admin_action(&parts) {
+            let outcome = if status == Status::Ok {
+                Outcome::Success
+            } else {
+                Outcome::Failure
+            };
+            audit_log.record(event, Actor::Admin(admin_addr), node_id, outcome, &detail);
+        }
+        if json {
+            let text = out.text.strip_suffix("--END--\n").unwrap_or(&out.text);
+            let reply = json!({
+                "status": status.as_str(),
+                "message": out.message.as_deref().unwrap_or_default(),
+                "output": text,
+                "data": data,
+            });
+            out.text = format!("{}\n--END--\n", reply);
+        }
+        if writer.write_all(out.text.as_bytes()).await.is_err() {
+            break;
+        }
         line.clear();
     }
 }
+
+//Read one line of at most MAX_LINE bytes within `timeout`, false once the admin hung up.
+//Anyone who can reach the port gets this far before the token is checked.
+async fn read_line<R>(reader: &mut R, line: &mut String, timeout: Duration) -> io::Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut limited = (&mut *reader).take(MAX_LINE as u64);
+    let read = limited.read_line(line);
+    let n = tokio::time::timeout(timeout, read).await.map_err(|_| {
+        io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for a command")
+    })??;
+    if n >= MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Command line too long",
+        ));
+    }
+    Ok(n > 0)
+}
+
+/// How a command went, `status` of a `json` reply.
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Error,
+    NotFound,
+    Conflict,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Error => "error",
+            Status::NotFound => "not_found",
+            Status::Conflict => "conflict",
+        }
+    }
+}
+
+//The answer to one command: its text, how it went and the line that says so. The first
+//error or not-found decides the status, listings with neither are ok.
+struct Reply {
+    text: String,
+    status: Status,
+    message: Option<String>,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self {
+            text: String::new(),
+            status: Status::Ok,
+            message: None,
+        }
+    }
+
+    fn push_str(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    fn ok(&mut self, message: &str) {
+        self.text.push_str(&format!("OK: {}\n", message));
+        if self.status == Status::Ok && self.message.is_none() {
+            self.message = Some(message.to_string());
+        }
+    }
+
+    fn err(&mut self, message: &str) {
+        self.text.push_str(&format!("ERR: {}\n", message));
+        self.fail(Status::Error, message);
+    }
+
+    //the command would overwrite something that is already there
+    fn conflict(&mut self, message: &str) {
+        self.text.push_str(&format!("ERR: {}\n", message));
+        self.fail(Status::Conflict, message);
+    }
+
+    fn not_found(&mut self, message: &str) {
+        self.text.push_str(&format!("{}\n", message));
+        self.fail(Status::NotFound, message);
+    }
+
+    fn fail(&mut self, status: Status, message: &str) {
+        if self.status == Status::Ok {
+            self.status = status;
+            self.message = Some(message.to_string());
+        }
+    }
+}
+
+//The audit event of a command that changes something, with the node it targets and the
+//command itself as detail. Read-only commands (and `hash`, whose argument is a password) give None.
+fn admin_action<'a>(parts: &[&'a str]) -> Option<(&'static str, Option<&'a str>, String)> {
+    let event = match parts {
+        ["add" | "create", _, ..] => "node_added",
+        ["enroll", _] => "enrollment_issued",
+        ["remove" | "delete" | "destroy", _] => "node_removed",
+        ["reserve", _, _] => "port_reserved",
+        ["unreserve", _] => "port_unreserved",
+        ["quota", _, _] => "port_quota_set",
+        ["allow" | "deny", ..] => "firewall_rule_added",
+        ["unallow" | "undeny", ..] => "firewall_rule_removed",
+        ["limit", ..] => "limits_set",
+        ["bandwidth", ..] => "bandwidth_set",
+        ["traffic", ..] => "traffic_quota_set",
+        ["unlock", _] => "lockout_cleared",
+        ["revoke", _] => "tokens_revoked",
+        ["describe" | "owner" | "label" | "unlabel" | "expire", _, ..] => "node_updated",
+        ["enable", _] => "node_enabled",
+        ["disable", _] => "node_disabled",
+        ["route", "add", ..] => "route_added",
+        ["route", "rm" | "remove", ..] => "route_removed",
+        ["kick", _] => "session_kicked",
+        _ => return None,
+    };
+    let node_id = match parts {
+        ["unreserve", ..] | ["limit", ..] | ["route", ..] => None,
+        ["allow" | "deny" | "unallow" | "undeny", _, node_id] => Some(*node_id),
+        ["allow" | "deny" | "unallow" | "undeny", ..] => None,
+        [_, node_id, ..] => Some(*node_id),
+        _ => None,
+    };
+    let detail = parts
+        .iter()
+        .map(|part| {
+            if part.starts_with("$argon2id$") {
+                "<argon2id hash>"
+            } else {
+                part
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some((event, node_id, detail))
+}
+
+//A node as `json view` / `json list` report it, without anything secret
+fn node_json(node: &Node) -> Value {
+    let time = |ts: OffsetDateTime| ts.format(&Rfc3339).ok();
+    json!({
+        "node_id": node.node_id,
+        "status": node_status(node),
+        "description": node.description,
+        "owner": node.owner,
+        "labels": node.labels,
+        "expires_at": node.expires_at.and_then(time),
+        "auth": if node.password_hash.is_some() { "password" } else { "hash_chain" },
+        "enrolled": node.password_hash.is_some() || !node.anchor.is_empty(),
+        "logins_left": node.password_hash.is_none().then_some(node.current_index),
+        "chain_length": node.password_hash.is_none().then_some(node.chain_length),
+        "rotation_pending": node.pending.is_some(),
+        "port_quota": node.port_quota,
+        "created_at": time(node.created_at),
+        "last_login": node.last_login.and_then(time),
+    })
+}
+
+//What `list` and `view` show as the status of a node
+fn node_status(node: &Node) -> &'static str {
+    match node.check_access() {
+        Ok(()) => "enabled",
+        Err(AccessError::Disabled) => "disabled",
+        Err(AccessError::Expired) => "expired",
+    }
+}
+
+//`team=payments env=dev`
+fn parse_labels<'a>(pairs: &[&'a str]) -> Result<Vec<(&'a str, &'a str)>, String> {
+    pairs
+        .iter()
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => Ok((key, value)),
+            _ => Err(format!("'{}' is not a key=value label", pair)),
+        })
+        .collect()
+}
+
+//When a node expires: a date (at 00:00 UTC), an RFC 3339 time, a span from now like `30d`,
+//or `never`, which gives Some(None)
+fn parse_expiry(value: &str) -> Option<Option<OffsetDateTime>> {
+    if value == "never" {
+        return Some(None);
+    }
+    if let Ok(ts) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some(Some(ts));
+    }
+    let date_format = time::format_description::parse("[year]-[month]-[day]").ok()?;
+    if let Ok(date) = Date::parse(value, &date_format) {
+        return Some(Some(date.midnight().assume_utc()));
+    }
+    Some(Some(OffsetDateTime::now_utc() + audit::parse_span(value)?))
+}
+
+//`audit` filters are key=value pairs, e.g. `audit node=laptop_1 event=auth since=24h limit=100`
+fn parse_audit_filters(filters: &[&str]) -> Result<(AuditFilter, usize), String> {
+    let mut filter = AuditFilter::default();
+    let mut limit = 50;
+    for pair in filters {
+        let Some((key, value)) = pair.split_once('=') else {
+            return Err(format!("'{}' is not a key=value filter", pair));
+        };
+        let time = || {
+            audit::parse_time(value).ok_or_else(|| {
+                format!(
+                    "'{}' is neither an RFC 3339 time nor a span like 30m, 12h or 7d",
+                    value
+                )
+            })
+        };
+        match key {
+            "node" => filter.node_id = Some(value.to_string()),
+            "event" => filter.event = Some(value.to_string()),
+            "since" => filter.since = Some(time()?),
+            "until" => filter.until = Some(time()?),
+            "limit" => {
+                limit = value
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("'{}' is not a valid limit", value))?
+            }
+            _ => return Err(format!("Unknown filter '{}'", key)),
+        }
+    }
+    Ok((filter, limit))
+}
@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+/// Who did what an audit entry records.
+pub enum Actor {
+    /// The server on its own, e.g. committing a chain rotation.
+    Server,
+    /// Whoever is connected to the admin port.
+    Admin(SocketAddr),
+    /// A (would-be) node connecting from this address.
+    Client(IpAddr),
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Actor::Server => write!(f, "server"),
+            Actor::Admin(addr) => write!(f, "admin@{}", addr),
+            Actor::Client(ip) => write!(f, "client@{}", ip.to_canonical()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// Turned down before anything was checked, e.g. while locked out.
+    Refused,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failure => write!(f, "failure"),
+            Outcome::Refused => write!(f, "refused"),
+        }
+    }
+}
+
+/// One line of the audit log, e.g.
+/// `{"ts":"2026-10-19T08:15:02Z","event":"auth_failed","actor":"client@203.0.113.7","node_id":"laptop_1","ip":"203.0.113.7","outcome":"failure","detail":"invalid preimage"}`
+///
+/// Entries written before actors and outcomes were recorded simply lack those fields.
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub ts: String,
+    pub event: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+    pub detail: String,
+}
+
+/// What `AuditLog::query` looks for. Every filter left `None` matches everything.
+#[derive(Default)]
+pub struct AuditFilter {
+    pub node_id: Option<String>,
+    pub event: Option<String>,
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        if self
+            .node_id
+            .as_ref()
+            .is_some_and(|node_id| entry.node_id.as_ref() != Some(node_id))
+        {
+            return false;
+        }
+        //`auth` matches auth_failed, auth_lockout...
+        if self
+            .event
+            .as_ref()
+            .is_some_and(|event| !entry.event.starts_with(event.as_str()))
+        {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        let Ok(ts) = OffsetDateTime::parse(&entry.ts, &Rfc3339) else {
+            return false;
+        };
+        self.since.is_none_or(|since| ts >= since) && self.until.is_none_or(|until| ts <= until)
+    }
+}
+
+/// Append-only log of security relevant events, one JSON object per line.
+///
+/// Entries never carry secrets: no seeds, anchors, preimages, passwords or tokens.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        event: &str,
+        actor: Actor,
+        node_id: Option<&str>,
+        outcome: Outcome,
+        detail: &str,
+    ) {
+        //whole seconds, the order of the lines tells which came first
+        let now = OffsetDateTime::now_utc();
+        let entry = AuditEntry {
+            ts: now
+                .replace_nanosecond(0)
+                .unwrap_or(now)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            event: event.to_string(),
+            actor: actor.to_string(),
+            node_id: node_id.map(str::to_string),
+            ip: match actor {
+                Actor::Client(ip) => Some(ip.to_canonical()),
+                Actor::Admin(addr) => Some(addr.ip().to_canonical()),
+                Actor::Server => None,
+            },
+            outcome: Some(outcome),
+            detail: detail.to_string(),
+        };
+        let Ok(mut line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        line.push('\n');
+        //one write per entry under the lock, so concurrent entries never interleave
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("Failed to write audit entry: {}", e);
+        }
+    }
+
+    /// The last `limit` entries matching `filter`, oldest first. Lines that aren't valid
+    /// entries, e.g. one cut short by a crash, are skipped.
+    pub fn query(&self, filter: &AuditFilter, limit: usize) -> anyhow::Result<Vec<AuditEntry>> {
+        //hold the lock so we never read a line that is half written
+        let _writing = self.file.lock().unwrap();
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut entries = std::collections::VecDeque::with_capacity(limit);
+        for line in reader.lines() {
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?) else {
+                continue;
+            };
+            if filter.matches(&entry) {
+                if entries.len() == limit {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+        }
+        Ok(entries.into())
+    }
+}
+
+/// A point in time for the `audit` filters: RFC 3339 (`2026-10-19T08:00:00Z`), or a span
+/// back from now such as `30m`, `12h` or `7d`.
+pub fn parse_time(value: &str) -> Option<OffsetDateTime> {
+    if let Ok(ts) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some(ts);
+    }
+    Some(OffsetDateTime::now_utc() - parse_span(value)?)
+}
+
+/// A span like `90s`, `30m`, `12h` or `7d`.
+pub fn parse_span(value: &str) -> Option<Duration> {
+    let split = value.len().checked_sub(1)?;
+    let amount: i64 = value.get(..split)?.parse().ok()?;
+    match value.get(split..)? {
+        "s" => Some(Duration::seconds(amount)),
+        "m" => Some(Duration::minutes(amount)),
+        "h" => Some(Duration::hours(amount)),
+        "d" => Some(Duration::days(amount)),
+        _ => None,
+    }
+}
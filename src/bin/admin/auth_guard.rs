@@ -0,0 +1,293 @@
+use super::audit::{Actor, AuditLog, Outcome};
+use dashmap::{DashMap, DashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+//Failed attempts allowed before the first lockout
+const FREE_ATTEMPTS: u32 = 3;
+//The first lockout, doubled with every further failure up to MAX_LOCKOUT
+const BASE_LOCKOUT: Duration = Duration::from_secs(2);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+//A counter nobody failed on for this long starts over
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Failed AUTH lines a single QUIC connection may send before the server closes it.
+pub const MAX_FAILURES_PER_CONNECTION: u32 = 3;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn remaining_lockout(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+/// Failure counters with an exponential lockout: after `FREE_ATTEMPTS` failures a key is
+/// locked out for `BASE_LOCKOUT`, doubled with every further failure up to `MAX_LOCKOUT`.
+/// A key that didn't fail for `FORGET_AFTER` starts over.
+pub struct FailureCounter<K: Eq + Hash> {
+    counters: DashMap<K, Failures>,
+}
+
+impl<K: Eq + Hash> Default for FailureCounter<K> {
+    fn default() -> Self {
+        Self {
+            counters: DashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> FailureCounter<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long `key` is still locked out.
+    pub fn lockout(&self, key: &K) -> Option<Duration> {
+        let now = Instant::now();
+        self.counters
+            .get(key)
+            .and_then(|failures| failures.remaining_lockout(now))
+    }
+
+    /// Count a failure, returns the lockout it starts if there is one.
+    pub fn failed(&self, key: K) -> Option<Duration> {
+        self.failed_at(key, Instant::now())
+    }
+
+    fn failed_at(&self, key: K, now: Instant) -> Option<Duration> {
+        //Counters of whoever stopped failing long ago are only dead weight
+        if self.counters.len() > 10_000 {
+            self.counters
+                .retain(|_, failures| now.duration_since(failures.last) < FORGET_AFTER);
+        }
+        let mut failures = self.counters.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.last) >= FORGET_AFTER {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if failures.count <= FREE_ATTEMPTS {
+            return None;
+        }
+        let doublings = (failures.count - FREE_ATTEMPTS - 1).min(16);
+        let lockout = (BASE_LOCKOUT * 2u32.pow(doublings)).min(MAX_LOCKOUT);
+        failures.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    /// Forget the failures of `key`, returns whether it had any.
+    pub fn clear(&self, key: &K) -> bool {
+        self.counters.remove(key).is_some()
+    }
+
+    /// Forget the failures of every key `matches` picks, returns whether there were any.
+    pub fn clear_matching(&self, matches: impl Fn(&K) -> bool) -> bool {
+        let before = self.counters.len();
+        self.counters.retain(|key, _| !matches(key));
+        self.counters.len() != before
+    }
+}
+
+/// Why an attempt is refused before its preimage is even looked at.
+pub enum Refusal {
+    /// The node or the source address failed too often, retry after this long.
+    LockedOut(Duration),
+    /// Another connection is authenticating as this node right now. A preimage is only good
+    /// once, so two attempts at the same time are at best a replay.
+    InProgress,
+}
+
+/// Brute-force protection of node authentication.
+///
+/// Failures are counted per source IP and per node id from that IP. After `FREE_ATTEMPTS`
+/// failures the IP, or the node from that IP, is locked out for `BASE_LOCKOUT`, doubled with
+/// every further failure up to `MAX_LOCKOUT`. A node is never locked out for everybody:
+/// anyone who knows its id could keep it locked forever. A successful login clears both
+/// counters. Every failure and lockout is written to the audit log.
+pub struct AuthGuard {
+    audit: Arc<AuditLog>,
+    nodes: FailureCounter<(String, IpAddr)>,
+    ips: FailureCounter<IpAddr>,
+    in_flight: DashSet<String>,
+}
+
+/// One node authentication in progress, see `AuthGuard::begin`.
+pub struct Attempt<'a> {
+    guard: &'a AuthGuard,
+    node_id: String,
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        self.guard.in_flight.remove(&self.node_id);
+    }
+}
+
+impl AuthGuard {
+    pub fn new(audit: Arc<AuditLog>) -> Self {
+        Self {
+            audit,
+            nodes: FailureCounter::new(),
+            ips: FailureCounter::new(),
+            in_flight: DashSet::new(),
+        }
+    }
+
+    /// How long this address is still locked out, checked before the QUIC handshake.
+    pub fn ip_lockout(&self, ip: IpAddr) -> Option<Duration> {
+        self.ips.lockout(&ip.to_canonical())
+    }
+
+    /// Start authenticating `node_id` from `ip`, unless one of them is locked out or the node
+    /// is already being authenticated. The attempt holds the node until it is dropped.
+    pub fn begin(&self, node_id: &str, ip: IpAddr) -> Result<Attempt<'_>, Refusal> {
+        let node_lockout = self
+            .nodes
+            .lockout(&(node_id.to_string(), ip.to_canonical()));
+        if let Some(remaining) = node_lockout.or_else(|| self.ip_lockout(ip)) {
+            return Err(Refusal::LockedOut(remaining));
+        }
+        if !self.in_flight.insert(node_id.to_string()) {
+            return Err(Refusal::InProgress);
+        }
+        Ok(Attempt {
+            guard: self,
+            node_id: node_id.to_string(),
+        })
+    }
+
+    /// Count a failure against the source address and, when it names a known node, that node
+    /// from this address.
+    pub fn failed(&self, node_id: Option<&str>, ip: IpAddr, reason: &str) {
+        self.audit.record(
+            "auth_failed",
+            Actor::Client(ip),
+            node_id,
+            Outcome::Failure,
+            reason,
+        );
+        if let Some(lockout) = self.ips.failed(ip.to_canonical()) {
+            self.audit.record(
+                "auth_lockout",
+                Actor::Client(ip),
+                None,
+                Outcome::Refused,
+                &format!("address locked out for {}s", lockout.as_secs()),
+            );
+        }
+        if let Some(node_id) = node_id
+            && let Some(lockout) = self.nodes.failed((node_id.to_string(), ip.to_canonical()))
+        {
+            self.audit.record(
+                "auth_lockout",
+                Actor::Client(ip),
+                Some(node_id),
+                Outcome::Refused,
+                &format!(
+                    "node locked out for {}s from this address",
+                    lockout.as_secs()
+                ),
+            );
+        }
+    }
+
+    pub fn succeeded(&self, node_id: &str, ip: IpAddr) {
+        self.nodes.clear(&(node_id.to_string(), ip.to_canonical()));
+        self.ips.clear(&ip.to_canonical());
+    }
+
+    /// Lift the lockout of a node id (from every address) or of an address, e.g. after an
+    /// attack on a legit node. Returns whether there was anything to forget.
+    pub fn unlock(&self, node_id_or_ip: &str) -> bool {
+        let ip = node_id_or_ip
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| ip.to_canonical());
+        let node = self
+            .nodes
+            .clear_matching(|(node_id, from)| node_id == node_id_or_ip || Some(*from) == ip);
+        let ip = ip.is_some_and(|ip| self.ips.clear(&ip));
+        node || ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_after_the_free_attempts() {
+        let counter = FailureCounter::new();
+        let start = Instant::now();
+        for attempt in 0..FREE_ATTEMPTS {
+            assert_eq!(
+                counter.failed_at("a", start + Duration::from_secs(attempt as u64)),
+                None
+            );
+        }
+        let expected = [2, 4, 8, 16, 32, 64, 128, 256, 512, 900, 900];
+        for (n, seconds) in expected.into_iter().enumerate() {
+            let now = start + Duration::from_secs(10 + n as u64);
+            assert_eq!(
+                counter.failed_at("a", now),
+                Some(Duration::from_secs(seconds)),
+                "failure {}",
+                FREE_ATTEMPTS as usize + n + 1
+            );
+        }
+        assert!(counter.lockout(&"a").is_some());
+        assert_eq!(counter.lockout(&"b"), None);
+    }
+
+    #[test]
+    fn counters_start_over() {
+        let counter = FailureCounter::new();
+        let start = Instant::now();
+        for _ in 0..=FREE_ATTEMPTS {
+            counter.failed_at("a", start);
+        }
+        //after FORGET_AFTER without a failure the next one is free again
+        assert_eq!(counter.failed_at("a", start + FORGET_AFTER), None);
+
+        assert!(counter.clear(&"a"));
+        assert!(!counter.clear(&"a"));
+        assert_eq!(counter.lockout(&"a"), None);
+    }
+
+    #[test]
+    fn failures_from_one_address_dont_lock_the_node_out_for_others() {
+        let path = std::env::temp_dir().join(format!("auth_guard_test_{}.log", std::process::id()));
+        let guard = AuthGuard::new(Arc::new(AuditLog::open(&path).unwrap()));
+        let attacker: IpAddr = "203.0.113.7".parse().unwrap();
+        let node: IpAddr = "198.51.100.1".parse().unwrap();
+
+        for _ in 0..=FREE_ATTEMPTS {
+            guard.failed(Some("node1"), attacker, "invalid preimage");
+        }
+        assert!(matches!(
+            guard.begin("node1", attacker),
+            Err(Refusal::LockedOut(_))
+        ));
+        assert!(guard.ip_lockout(attacker).is_some());
+        assert!(guard.begin("node1", node).is_ok());
+
+        assert!(guard.unlock("node1"));
+        assert!(guard.ip_lockout(attacker).is_some());
+        assert!(guard.unlock("203.0.113.7"));
+        assert!(guard.begin("node1", attacker).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,38 +1,162 @@
-use super::node_store::NodeStore;
+use super::auth_guard::{AuthGuard, Refusal};
+use super::node_store::{EnrollError, NodeStore};
+use super::password_hash;
 use blake3;
+use quinn::{Connection, SendStream};
+use std::net::IpAddr;
+use std::time::Duration;
+
+//How many preimages a client may be ahead of the anchor, e.g. because it spent one and
+//crashed or lost the connection before the server saw it
+const RESYNC_WINDOW: usize = 8;
+//How long the client gets to grow its next chain and send the anchor after a ROTATE.
+//Growing a chain of MAX_CHAIN_LENGTH takes a few seconds, the rest is slack for slow links
+const ROTATION_TIMEOUT: Duration = Duration::from_secs(60);
 
 ///new implementation verify node using reverse hash chain preimage
-pub fn verify_node(
-    node_store: &NodeStore,
-    node_id: &str,
-    preimage: &str,
-) -> (bool, Option<String>) {
+///Returns whether the node is authorized and whether its chain should be rotated.
+pub fn verify_node(node_store: &NodeStore, node_id: &str, preimage: &str) -> (bool, bool) {
     if let Some(node) = node_store.get_node(node_id.to_string()) {
-        let anchor = &node.anchor;
-
         let preimage_bytes = match hex::decode(preimage) {
             Ok(bytes) => bytes,
             Err(_) => {
                 eprintln!("Invalid hex preimage from client!");
-                return (false, None);
+                return (false, false);
             }
         };
-        let computed = blake3::hash(&preimage_bytes);
-        let computed_hex = hex::encode(computed.as_bytes());
-        println!("Server computed: {}", computed_hex);
-        println!("Server anchor:   {}", anchor);
 
-        let authenticated = &computed_hex == anchor;
+        //Hash forward until we hit the anchor: one step is the expected preimage, every further
+        //step is one the client spent that never reached us
+        let mut hash = preimage_bytes;
+        let mut skipped = None;
+        for steps in 0..=RESYNC_WINDOW {
+            hash = blake3::hash(&hash).as_bytes().to_vec();
+            let computed_hex = hex::encode(&hash);
+            if computed_hex == node.anchor {
+                skipped = Some(steps);
+                break;
+            }
+            //A preimage of the proposed chain means the client switched over to it
+            if node
+                .pending
+                .as_ref()
+                .is_some_and(|pending| pending.anchor == computed_hex)
+                && node_store.commit_rotation(node_id, &computed_hex)
+            {
+                skipped = Some(steps);
+                break;
+            }
+        }
 
-        if authenticated {
+        if let Some(skipped) = skipped {
+            if skipped > 0 {
+                println!(
+                    "Node '{}' was {} preimages ahead, resynchronised its chain",
+                    node_id, skipped
+                );
+            }
             //we go backward: update anchor to be the received preimage
-            let new_seed = node_store.set_anchor(node_id, preimage);
+            let rotate = node_store.set_anchor(node_id, preimage, skipped);
             node_store.set_last_login(node_id);
-            return (true, new_seed);
+            return (true, rotate);
         }
 
-        (false, None)
+        (false, false)
     } else {
-        (false, None)
+        (false, false)
+    }
+}
+
+/// ENROLL <node id> <token secret> <hex anchor>: a new client registers the anchor of the chain
+/// it grew from its own seed, so the seed never leaves the client. Returns the node id on
+/// success, and on failure the reason plus the node id to count it against, if the node exists.
+pub fn redeem_enrollment(
+    line: &str,
+    node_store: &NodeStore,
+    auth_guard: &AuthGuard,
+    remote_ip: IpAddr,
+) -> Result<String, (String, Option<String>)> {
+    let [node_id, secret, anchor] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(("Malformed enroll line".to_string(), None));
+    };
+    if anchor.len() != 64 || hex::decode(anchor).is_err() {
+        return Err(("Invalid anchor".to_string(), None));
+    }
+    //also keeps a second redemption of the same token from racing this one
+    let _attempt = auth_guard.begin(node_id, remote_ip).map_err(|refusal| {
+        let reason = match refusal {
+            Refusal::LockedOut(remaining) => format!(
+                "Too many failed attempts, retry in {}s",
+                remaining.as_secs().max(1)
+            ),
+            Refusal::InProgress => "Authentication already in progress".to_string(),
+        };
+        (reason, None)
+    })?;
+    node_store
+        .enroll(node_id, secret, anchor)
+        .map(|()| node_id.to_string())
+        .map_err(|e| {
+            let known_node = (!matches!(e, EnrollError::UnknownNode)).then(|| node_id.to_string());
+            (e.to_string(), known_node)
+        })
+}
+
+/// Check the password of a password node. Argon2 is slow on purpose, call this off the runtime.
+pub fn verify_password_node(node_store: &NodeStore, node_id: &str, password: &str) -> bool {
+    let Some(phc) = node_store
+        .get_node(node_id.to_string())
+        .and_then(|node| node.password_hash)
+    else {
+        return false;
+    };
+    let authenticated = password_hash::verify_password(password, &phc);
+    if authenticated {
+        node_store.set_last_login(node_id);
+    }
+    authenticated
+}
+
+/// Ask a node whose chain is running out for its next one, during the login handshake.
+///
+/// The server sends `ROTATE`, the client grows a new chain from a seed it generates and
+/// persists itself, and answers on a unidirectional stream with `ROTATED <anchor>`. The anchor
+/// is saved as the node's proposed chain before the server confirms with a `ROTATED` line,
+/// and only then does the client switch over. Anything going wrong just leaves the rotation
+/// for the next login, the old chain stays valid until the node logs in with the new one.
+pub async fn request_rotation(
+    conn: &Connection,
+    control: &mut SendStream,
+    node_store: &NodeStore,
+    node_id: &str,
+    remote_ip: IpAddr,
+) {
+    if control.write_all(b"ROTATE\n").await.is_err() {
+        return;
+    }
+    let report = tokio::time::timeout(ROTATION_TIMEOUT, async {
+        let mut recv_stream = conn.accept_uni().await.ok()?;
+        recv_stream.read_to_end(256).await.ok()
+    })
+    .await;
+    let Ok(Some(report)) = report else {
+        eprintln!("Node '{}' did not propose its next hash chain", node_id);
+        return;
+    };
+    let report = String::from_utf8_lossy(&report);
+    let Some(anchor) = report.trim().strip_prefix("ROTATED ") else {
+        eprintln!("Invalid rotation report from node '{}'", node_id);
+        return;
+    };
+    if !node_store.propose_rotation(node_id, anchor.trim(), remote_ip) {
+        eprintln!("Node '{}' proposed an invalid hash chain", node_id);
+        return;
+    }
+    //the client throws its old chain away once it reads ROTATED, so the new one has to be on disk
+    if let Err(e) = node_store.save_if_changed().await {
+        eprintln!("Failed to save nodes: {}", e);
+        return;
     }
+    println!("Node '{}' proposed its next hash chain", node_id);
+    let _ = control.write_all(b"ROTATED\n").await;
 }
@@ -1,4 +1,8 @@
 pub mod admin_listener;
+pub mod audit;
+pub mod auth_guard;
 pub mod login;
 pub mod node_store;
 pub mod password_gen;
+pub mod password_hash;
+pub mod session_token;
@@ -1,58 +1,341 @@
+use super::audit::{Actor, AuditLog, Outcome};
 use dashmap::DashMap;
-use time::OffsetDateTime;
+use dashmap::mapref::entry::Entry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use time::{Duration, OffsetDateTime};
 use v_distributed_tunnel_v1::common::admin::client_config::ClientConfig;
+use v_distributed_tunnel_v1::common::helper::config::write_atomic;
 
-#[derive(Clone)]
+//How many public ports a node may request per session until the admin says otherwise
+pub const DEFAULT_PORT_QUOTA: usize = 1;
+/// Logins a node's hash chain is good for unless the admin picks another length.
+pub const DEFAULT_CHAIN_LENGTH: usize = 10_000;
+/// Bounds of a chain length chosen at provisioning. Below the minimum the node would rotate
+/// on almost every login, above the maximum provisioning takes too long.
+pub const MIN_CHAIN_LENGTH: usize = 100;
+pub const MAX_CHAIN_LENGTH: usize = 10_000_000;
+//How long an enrollment token can be redeemed
+const ENROLLMENT_TTL: Duration = Duration::hours(24);
+//Once a node has this many preimages left the server asks it for the next chain,
+//so a client that misses the request a few times still rotates before running dry
+const ROTATE_AHEAD: usize = 10;
+
+//On disk it looks like this, one [[node]] table per node:
+// [[node]]
+// node_id = "laptop_1"
+// current_index = 9876
+// chain_length = 10000
+// anchor = "<hex>"
+// created_at = "2026-10-19T08:00:00Z"
+// last_login = "2026-10-19T09:30:00Z"
+// port_quota = 1
+// description = "Alice's work laptop"
+// owner = "alice"
+// enabled = true
+// [node.labels]
+// team = "payments"
+#[derive(Default, Serialize, Deserialize)]
+struct NodeFile {
+    #[serde(default)]
+    node: Vec<Node>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Node {
     pub node_id: String,
-    pub seed: String,
     pub current_index: usize,
-    pub anchor: String,
+    pub chain_length: usize, //every chain of this node, rotated ones too
+    pub anchor: String,      //empty until the node enrolled
+    #[serde(with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
     pub last_login: Option<OffsetDateTime>,
+    pub port_quota: usize,
+    /// The next chain the client proposed after a `ROTATE`, committed on its first login.
+    pub pending: Option<PendingRotation>,
+    /// Set while an enrollment token is out, see `NodeStore::issue_enrollment`.
+    pub enrollment: Option<Enrollment>,
+    /// Argon2id PHC string of a password node, which logs in with `AUTH-PASSWORD` and has no
+    /// hash chain.
+    pub password_hash: Option<String>,
+    /// Free text for the admin, e.g. "Alice's work laptop".
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub owner: String,
+    /// e.g. `team=payments`, `env=dev`, for filtering `list`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// After this the node can't log in anymore, until the admin extends it.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+    /// A disabled node keeps its chain, leases and quotas but can't log in.
+    pub enabled: bool,
+}
+
+impl Node {
+    /// Why the node may not log in right now, if anything stops it.
+    pub fn check_access(&self) -> Result<(), AccessError> {
+        if !self.enabled {
+            return Err(AccessError::Disabled);
+        }
+        if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+        {
+            return Err(AccessError::Expired);
+        }
+        Ok(())
+    }
+
+    /// True when the node carries every one of the `key=value` labels.
+    pub fn has_labels(&self, selector: &[(&str, &str)]) -> bool {
+        selector
+            .iter()
+            .all(|(key, value)| self.labels.get(*key).is_some_and(|v| v == value))
+    }
+}
+
+/// Why a node that proved who it is still isn't let in.
+pub enum AccessError {
+    Disabled,
+    Expired,
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessError::Disabled => write!(f, "node is disabled"),
+            AccessError::Expired => write!(f, "node has expired"),
+        }
+    }
+}
+
+/// A node with this id is already provisioned, adding it again would wipe its chain and
+/// settings. Remove it first.
+#[derive(Debug)]
+pub struct AlreadyExists;
+
+impl std::fmt::Display for AlreadyExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "node already exists")
+    }
+}
+
+/// A one-time token the client redeems with `ENROLL` to register the anchor of the chain it
+/// grew from its own seed. Only the token's hash is kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Enrollment {
+    pub token_hash: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+}
+
+/// Why `NodeStore::enroll` turned a token down.
+pub enum EnrollError {
+    UnknownNode,
+    NoToken,
+    Expired,
+    InvalidToken,
+}
+
+impl std::fmt::Display for EnrollError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnrollError::UnknownNode => write!(f, "unknown node"),
+            EnrollError::NoToken => write!(f, "no enrollment token was issued"),
+            EnrollError::Expired => write!(f, "enrollment token expired"),
+            EnrollError::InvalidToken => write!(f, "invalid enrollment token"),
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingRotation {
+    pub anchor: String,
+}
+
+/// Every provisioned node, kept in memory and written to a TOML file (`NODES_FILE`) so nodes,
+/// their chains and settings survive a restart.
+///
+/// Changes only touch memory and mark the store as changed, `save_if_changed` writes it out.
+/// A login is saved before the node hears it worked: a restart that forgot the new anchor
+/// would accept the spent preimage again.
 pub struct NodeStore {
     nodes: DashMap<String, Node>,
+    audit: Arc<AuditLog>,
+    path: PathBuf,
+    changed: AtomicBool,
+    //one save at a time, so an older snapshot never overwrites a newer one
+    save_lock: tokio::sync::Mutex<()>,
 }
 
 impl NodeStore {
-    pub fn new() -> Self {
-        Self {
-            nodes: DashMap::new(),
-        }
-    }
-    pub fn add_node(&self, node_id: String) -> String {
-        const CHAIN_LENGTH: usize = 100;
-        let seed = ClientConfig::generate_seed();
-        let seed_str = ClientConfig::encode_seed(&seed);
-        let config = ClientConfig::new(
-            node_id.clone(),
-            seed_str.clone(),
-            CHAIN_LENGTH - 1,
-            CHAIN_LENGTH,
-        );
-        ClientConfig::write_toml_file(&config).unwrap();
-        let mut hash = hex::decode(&seed_str).unwrap();
-        for _ in 0..CHAIN_LENGTH {
-            hash = blake3::hash(&hash).as_bytes().to_vec();
+    /// Load nodes from `path`. A missing file just means no node has been added yet.
+    pub fn load(path: impl Into<PathBuf>, audit: Arc<AuditLog>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let nodes = DashMap::new();
+        if path.exists() {
+            let data = fs::read_to_string(&path)?;
+            let file: NodeFile = toml::from_str(&data)?;
+            for node in file.node {
+                nodes.insert(node.node_id.clone(), node);
+            }
+        }
+        Ok(Self {
+            nodes,
+            audit,
+            path,
+            changed: AtomicBool::new(false),
+            save_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Write the nodes out if anything changed since the last save. When this returns Ok,
+    /// every change made before the call is on disk. The write happens off the runtime.
+    pub async fn save_if_changed(&self) -> io::Result<()> {
+        let _saving = self.save_lock.lock().await;
+        if !self.changed.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut node = self.list_nodes();
+        node.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        let saved = match toml::to_string(&NodeFile { node }) {
+            Ok(toml) => {
+                let path = self.path.clone();
+                tokio::task::spawn_blocking(move || write_atomic(&path, toml.as_bytes()))
+                    .await
+                    .unwrap_or_else(|e| Err(io::Error::other(e)))
+            }
+            Err(e) => Err(io::Error::other(e)),
+        };
+        if saved.is_err() {
+            //try again with the next save
+            self.changed.store(true, Ordering::Release);
         }
-        let anchor = hex::encode(&hash);
+        saved
+    }
+
+    fn mark_changed(&self) {
+        self.changed.store(true, Ordering::Release);
+    }
+    /// Provision a node and return its enrollment token, `<node_id>:<chain_length>:<secret>`.
+    ///
+    /// The server never sees the node's seed: the client grows its chain locally and redeems
+    /// the token with the chain's anchor, see `enroll`.
+    pub fn add_node(&self, node_id: String, chain_length: usize) -> Result<String, AlreadyExists> {
         let node = Node {
             node_id: node_id.clone(),
-            seed: seed_str.clone(),
-            anchor: anchor.clone(), //initially it is h_0 = hash(seed). Updated everytime login successfully
-            current_index: CHAIN_LENGTH - 1,
+            anchor: String::new(),
+            current_index: chain_length - 1,
+            chain_length,
             created_at: OffsetDateTime::now_utc(),
             last_login: None,
+            port_quota: DEFAULT_PORT_QUOTA,
+            pending: None,
+            enrollment: None,
+            password_hash: None,
+            description: String::new(),
+            owner: String::new(),
+            labels: BTreeMap::new(),
+            expires_at: None,
+            enabled: true,
         };
-        self.nodes.insert(node_id, node);
-        return seed_str; //For the sake of debugging for this function, we return the seed, but won't be anymore in prod.
+        self.insert_new(node)?;
+        Ok(self.issue_enrollment(&node_id).unwrap())
     }
 
-    pub fn remove_node(&self, node_id: String) {
-        self.nodes.remove(&node_id);
+    /// Provision a node that logs in with a password, given as an Argon2id PHC string.
+    pub fn add_password_node(
+        &self,
+        node_id: String,
+        password_hash: String,
+    ) -> Result<(), AlreadyExists> {
+        let node = Node {
+            node_id: node_id.clone(),
+            anchor: String::new(),
+            current_index: 0,
+            chain_length: 0,
+            created_at: OffsetDateTime::now_utc(),
+            last_login: None,
+            port_quota: DEFAULT_PORT_QUOTA,
+            pending: None,
+            enrollment: None,
+            password_hash: Some(password_hash),
+            description: String::new(),
+            owner: String::new(),
+            labels: BTreeMap::new(),
+            expires_at: None,
+            enabled: true,
+        };
+        self.insert_new(node)
+    }
+
+    fn insert_new(&self, node: Node) -> Result<(), AlreadyExists> {
+        match self.nodes.entry(node.node_id.clone()) {
+            Entry::Occupied(_) => Err(AlreadyExists),
+            Entry::Vacant(entry) => {
+                entry.insert(node);
+                self.mark_changed();
+                Ok(())
+            }
+        }
+    }
+
+    /// Hand out a fresh enrollment token for a node, e.g. one that lost its config. Its current
+    /// chain keeps working until the token is redeemed.
+    pub fn issue_enrollment(&self, node_id: &str) -> Option<String> {
+        let mut entry = self.nodes.get_mut(node_id)?;
+        if entry.password_hash.is_some() {
+            return None; //a password node has no chain to enroll
+        }
+        let secret = ClientConfig::encode_seed(&ClientConfig::generate_seed());
+        entry.enrollment = Some(Enrollment {
+            token_hash: blake3::hash(secret.as_bytes()).to_hex().to_string(),
+            expires_at: OffsetDateTime::now_utc() + ENROLLMENT_TTL,
+        });
+        self.mark_changed();
+        Some(format!("{}:{}:{}", node_id, entry.chain_length, secret))
+    }
+
+    /// Redeem the enrollment token of a node: `anchor` becomes the anchor of its chain, any
+    /// previous chain or proposed rotation is dropped and the token can't be used again.
+    pub fn enroll(&self, node_id: &str, secret: &str, anchor: &str) -> Result<(), EnrollError> {
+        let mut entry = self
+            .nodes
+            .get_mut(node_id)
+            .ok_or(EnrollError::UnknownNode)?;
+        let enrollment = entry.enrollment.as_ref().ok_or(EnrollError::NoToken)?;
+        if enrollment.expires_at < OffsetDateTime::now_utc() {
+            entry.enrollment = None;
+            self.mark_changed();
+            return Err(EnrollError::Expired);
+        }
+        if blake3::hash(secret.as_bytes()).to_hex().as_str() != enrollment.token_hash {
+            return Err(EnrollError::InvalidToken);
+        }
+        entry.enrollment = None;
+        entry.anchor = anchor.to_string();
+        entry.current_index = entry.chain_length - 1;
+        entry.pending = None;
+        self.mark_changed();
+        Ok(())
+    }
+
+    /// Forget a node, returns false when there was no such node.
+    pub fn remove_node(&self, node_id: &str) -> bool {
+        let removed = self.nodes.remove(node_id).is_some();
+        if removed {
+            self.mark_changed();
+        }
+        removed
     }
 
     pub fn get_node(&self, node_id: String) -> Option<Node> {
@@ -63,45 +346,266 @@ impl NodeStore {
         self.nodes.iter().map(|node| node.value().clone()).collect()
     }
     pub fn set_last_login(&self, node_id: &str) {
-        if let Some(mut entry) = self.nodes.get_mut(node_id) {
-            entry.last_login = Some(OffsetDateTime::now_utc());
-        }
+        self.update(node_id, |node| {
+            node.last_login = Some(OffsetDateTime::now_utc())
+        });
+    }
+
+    pub fn set_port_quota(&self, node_id: &str, quota: usize) -> bool {
+        self.update(node_id, |node| node.port_quota = quota)
+    }
+
+    pub fn set_description(&self, node_id: &str, description: String) -> bool {
+        self.update(node_id, |node| node.description = description)
+    }
+
+    pub fn set_owner(&self, node_id: &str, owner: String) -> bool {
+        self.update(node_id, |node| node.owner = owner)
+    }
+
+    /// Set a label, or drop it when `value` is None.
+    pub fn set_label(&self, node_id: &str, key: &str, value: Option<&str>) -> bool {
+        self.update(node_id, |node| match value {
+            Some(value) => {
+                node.labels.insert(key.to_string(), value.to_string());
+            }
+            None => {
+                node.labels.remove(key);
+            }
+        })
+    }
+
+    /// None means the node never expires.
+    pub fn set_expiry(&self, node_id: &str, expires_at: Option<OffsetDateTime>) -> bool {
+        self.update(node_id, |node| node.expires_at = expires_at)
+    }
+
+    pub fn set_enabled(&self, node_id: &str, enabled: bool) -> bool {
+        self.update(node_id, |node| node.enabled = enabled)
+    }
+
+    /// Whether the node may log in, checked before its credential so a refused node is left
+    /// untouched. Unknown nodes pass, they fail the login itself.
+    pub fn check_access(&self, node_id: &str) -> Result<(), AccessError> {
+        self.nodes
+            .get(node_id)
+            .map_or(Ok(()), |node| node.check_access())
     }
 
-    fn rotate_seed(&self, node_id: &str) -> Option<String> {
-        if let Some(mut entry) = self.nodes.get_mut(node_id) {
-            const CHAIN_LENGTH: usize = 100;
-            let new_seed = ClientConfig::generate_seed();
-            let new_seed_str = ClientConfig::encode_seed(&new_seed);
-            let mut hash = hex::decode(&new_seed_str).unwrap();
-            for _ in 0..CHAIN_LENGTH {
-                hash = blake3::hash(&hash).as_bytes().to_vec();
+    fn update(&self, node_id: &str, change: impl FnOnce(&mut Node)) -> bool {
+        match self.nodes.get_mut(node_id) {
+            Some(mut entry) => {
+                change(&mut entry);
+                self.mark_changed();
+                true
             }
-            let anchor = hex::encode(&hash);
+            None => false,
+        }
+    }
+
+    pub fn get_port_quota(&self, node_id: &str) -> usize {
+        self.nodes
+            .get(node_id)
+            .map(|node| node.port_quota)
+            .unwrap_or(DEFAULT_PORT_QUOTA)
+    }
+
+    /// Move the anchor back to the preimage the node just logged in with, `skipped` preimages
+    /// past the one that was expected.
+    ///
+    /// Returns whether the chain is about to run out, the server then sends `ROTATE` after
+    /// the login so the client proposes its next chain (see `propose_rotation`). It keeps
+    /// asking on every login until the client logs in with the new chain.
+    pub fn set_anchor(&self, node_id: &str, anchor: &str, skipped: usize) -> bool {
+        let Some(mut entry) = self.nodes.get_mut(node_id) else {
+            return false;
+        };
+        entry.anchor = anchor.to_string();
+        entry.current_index = entry.current_index.saturating_sub(skipped + 1);
+        self.mark_changed();
+        entry.current_index <= ROTATE_AHEAD
+    }
 
-            //we update info of the value of entry in node_id key.
-            entry.seed = new_seed_str.clone();
-            entry.anchor = anchor.clone(); //initially it is h_0 = hash(seed). Updated everytime login successfully
-            entry.current_index = CHAIN_LENGTH - 1;
-            entry.created_at = OffsetDateTime::now_utc();
-            Some(new_seed_str.clone());
+    /// Remember the anchor of the chain the client grew from a seed of its own after a
+    /// `ROTATE`. The server never sees that seed. Only a node that was asked to rotate may
+    /// propose, and proposing again replaces the earlier proposal, e.g. when the client lost it.
+    /// Returns false when nothing was asked or `anchor` isn't one.
+    pub fn propose_rotation(&self, node_id: &str, anchor: &str, from: IpAddr) -> bool {
+        if anchor.len() != 64 || hex::decode(anchor).is_err() {
+            return false;
+        }
+        let Some(mut entry) = self.nodes.get_mut(node_id) else {
+            return false;
+        };
+        if entry.current_index > ROTATE_AHEAD {
+            return false;
+        }
+        if entry
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.anchor == anchor)
+        {
+            return true;
         }
-        None
+        entry.pending = Some(PendingRotation {
+            anchor: anchor.to_string(),
+        });
+        self.mark_changed();
+        self.audit.record(
+            "rotation_proposed",
+            Actor::Client(from),
+            Some(node_id),
+            Outcome::Success,
+            &format!("{} logins left on the current chain", entry.current_index),
+        );
+        true
     }
 
-    pub fn set_anchor(&self, node_id: &str, anchor: &str) -> Option<String> {
-        if let Some(mut entry) = self.nodes.get_mut(node_id) {
-            entry.anchor = anchor.to_string();
-            entry.current_index -= 1;
-            if entry.current_index == 0 {
-                return self.rotate_seed(node_id);
+    /// Switch the node over to its proposed chain once the client logged in with it, `anchor`
+    /// being what its preimage hashes to. Until then the old chain stays valid, so a rotation
+    /// that is interrupted halfway never locks the node out. Returns false when `anchor` is not
+    /// the one of the proposed chain.
+    pub fn commit_rotation(&self, node_id: &str, anchor: &str) -> bool {
+        let Some(mut entry) = self.nodes.get_mut(node_id) else {
+            return false;
+        };
+        match entry.pending.take() {
+            Some(pending) if pending.anchor == anchor => {
+                entry.anchor = pending.anchor;
+                entry.current_index = entry.chain_length - 1;
+                self.mark_changed();
+                self.audit.record(
+                    "rotation_committed",
+                    Actor::Server,
+                    Some(node_id),
+                    Outcome::Success,
+                    "node switched to its next hash chain",
+                );
+                true
+            }
+            pending => {
+                entry.pending = pending;
+                false
             }
-            return None;
         }
-        None
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("node_store_{}_{}", name, std::process::id()))
+    }
+
+    fn store(name: &str) -> NodeStore {
+        let audit = AuditLog::open(temp_path(&format!("{}.log", name))).unwrap();
+        NodeStore::load(temp_path(&format!("{}.toml", name)), Arc::new(audit)).unwrap()
+    }
+
+    #[test]
+    fn adding_an_existing_node_keeps_it() {
+        let store = store("add");
+        assert!(store.add_node("node1".into(), MIN_CHAIN_LENGTH).is_ok());
+        store.set_owner("node1", "alice".into());
+        store.set_enabled("node1", false);
+
+        assert!(
+            store
+                .add_node("node1".into(), DEFAULT_CHAIN_LENGTH)
+                .is_err()
+        );
+        assert!(
+            store
+                .add_password_node("node1".into(), "$argon2id$...".into())
+                .is_err()
+        );
+        let node = store.get_node("node1".into()).unwrap();
+        assert_eq!(node.chain_length, MIN_CHAIN_LENGTH);
+        assert_eq!(node.owner, "alice");
+        assert!(!node.enabled && node.password_hash.is_none());
+
+        assert!(store.remove_node("node1"));
+        assert!(
+            store
+                .add_password_node("node1".into(), "$argon2id$...".into())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn rotation_is_proposed_by_the_client_and_committed_on_login() {
+        let nodes = store("rotation");
+        let token = nodes.add_node("node1".into(), MIN_CHAIN_LENGTH).unwrap();
+        let secret = token.rsplit(':').next().unwrap();
+        assert!(nodes.enroll("node1", secret, &"ab".repeat(32)).is_ok());
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let next = "cd".repeat(32);
+
+        //plenty of logins left, nothing to rotate yet
+        assert!(!nodes.set_anchor("node1", &"01".repeat(32), 0));
+        assert!(!nodes.propose_rotation("node1", &next, ip));
+
+        let left = MIN_CHAIN_LENGTH - 2 - ROTATE_AHEAD;
+        assert!(nodes.set_anchor("node1", &"02".repeat(32), left - 1));
+        assert!(!nodes.propose_rotation("node1", "not an anchor", ip));
+        assert!(nodes.propose_rotation("node1", &"ef".repeat(32), ip));
+        //a client that lost its proposal may make another one
+        assert!(nodes.propose_rotation("node1", &next, ip));
+        assert!(nodes.propose_rotation("node1", &next, ip));
+
+        //the old chain stays valid until the new one is used
+        let node = nodes.get_node("node1".into()).unwrap();
+        assert_eq!(node.anchor, "02".repeat(32));
+        assert!(!nodes.commit_rotation("node1", &"ef".repeat(32)));
+        assert!(nodes.commit_rotation("node1", &next));
+        let node = nodes.get_node("node1".into()).unwrap();
+        assert_eq!(node.anchor, next);
+        assert_eq!(node.current_index, MIN_CHAIN_LENGTH - 1);
+        assert!(node.pending.is_none());
+    }
+
+    #[tokio::test]
+    async fn nodes_survive_a_restart() {
+        let _ = fs::remove_file(temp_path("restart.toml"));
+        let nodes = store("restart");
+        let token = nodes.add_node("laptop_1".into(), MIN_CHAIN_LENGTH).unwrap();
+        let secret = token.rsplit(':').next().unwrap();
+        assert!(nodes.enroll("laptop_1", secret, &"ab".repeat(32)).is_ok());
+        nodes.set_label("laptop_1", "team", Some("payments"));
+        nodes.set_expiry("laptop_1", Some(OffsetDateTime::UNIX_EPOCH));
+        nodes.set_enabled("laptop_1", false);
+        nodes.set_port_quota("laptop_1", 3);
+        nodes
+            .add_password_node("laptop_2".into(), "$argon2id$...".into())
+            .unwrap();
+        nodes.set_last_login("laptop_2");
+        nodes.save_if_changed().await.unwrap();
+
+        let loaded = store("restart");
+        let node = loaded.get_node("laptop_1".into()).unwrap();
+        assert_eq!(node.anchor, "ab".repeat(32));
+        assert_eq!(node.current_index, MIN_CHAIN_LENGTH - 1);
+        assert!(node.enrollment.is_none());
+        assert_eq!(
+            node.labels.get("team").map(String::as_str),
+            Some("payments")
+        );
+        assert_eq!(node.expires_at, Some(OffsetDateTime::UNIX_EPOCH));
+        assert!(!node.enabled);
+        assert_eq!(node.port_quota, 3);
+        let node = loaded.get_node("laptop_2".into()).unwrap();
+        assert_eq!(node.password_hash.as_deref(), Some("$argon2id$..."));
+        assert!(node.last_login.is_some());
 
-    pub fn get_seed(&self, node_id: &str) -> Option<String> {
-        self.nodes.get(node_id).map(|node| node.seed.clone())
+        //nothing changed, nothing written
+        fs::remove_file(temp_path("restart.toml")).unwrap();
+        loaded.save_if_changed().await.unwrap();
+        assert!(!temp_path("restart.toml").exists());
+        assert!(loaded.remove_node("laptop_2"));
+        loaded.save_if_changed().await.unwrap();
+        assert_eq!(store("restart").list_nodes().len(), 1);
+        let _ = fs::remove_file(temp_path("restart.toml"));
     }
 }
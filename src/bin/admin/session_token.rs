@@ -0,0 +1,261 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use v_distributed_tunnel_v1::common::admin::client_config::ClientConfig;
+use v_distributed_tunnel_v1::common::helper::config::write_atomic;
+
+/// How long a session token lets a node reconnect without spending a preimage.
+pub const TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+//On disk it looks like this, one [[revoked]] table per node revoked within the last TOKEN_TTL:
+// [[revoked]]
+// node_id = "laptop_1"
+// at = 1760861702000
+#[derive(Serialize, Deserialize, Default)]
+struct RevokedFile {
+    #[serde(default)]
+    revoked: Vec<Revocation>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Revocation {
+    node_id: String,
+    at: u64, //ms since the epoch
+}
+
+/// Why `SessionTokens::verify` turned a token down.
+pub enum TokenError {
+    /// Not a token of this server, or not of this node.
+    Invalid,
+    Expired,
+    /// Issued before the admin revoked the node's tokens.
+    Revoked,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Invalid => write!(f, "invalid session token"),
+            TokenError::Expired => write!(f, "session token expired"),
+            TokenError::Revoked => write!(f, "session token revoked"),
+        }
+    }
+}
+
+/// Short-lived tokens a node gets after a full login and presents with `AUTH-TOKEN` when it
+/// reconnects, so frequent reconnects don't eat through its hash chain.
+///
+/// A token is `<issued ms>.<expires ms>.<mac>`, the MAC being a keyed BLAKE3 hash over the
+/// node id and both times under a server secret. Nothing is stored per token: revoking a
+/// node only remembers when, and every token of that node issued before is refused.
+///
+/// Revocations are written to a TOML file and loaded back at startup, so with a fixed secret a
+/// revoked token doesn't come back to life when the server restarts.
+pub struct SessionTokens {
+    secret: [u8; 32],
+    path: PathBuf,
+    revoked_at: DashMap<String, u64>,
+}
+
+impl SessionTokens {
+    /// Tokens signed with `secret` (hex, e.g. from `SESSION_TOKEN_SECRET`) survive a restart of
+    /// the server, without one a random secret is used and tokens die with the process.
+    /// Revocations are kept in `revoked_file`, a missing file means nothing was revoked.
+    pub fn new(secret: Option<&str>, revoked_file: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let secret = match secret {
+            Some(hex_secret) => hex::decode(hex_secret.trim())?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("SESSION_TOKEN_SECRET must be 32 bytes of hex"))?,
+            None => ClientConfig::generate_seed(),
+        };
+        let path = revoked_file.into();
+        let revoked_at = DashMap::new();
+        if path.exists() {
+            let file: RevokedFile = toml::from_str(&fs::read_to_string(&path)?)?;
+            for revocation in file.revoked {
+                revoked_at.insert(revocation.node_id, revocation.at);
+            }
+        }
+        Ok(Self {
+            secret,
+            path,
+            revoked_at,
+        })
+    }
+
+    pub fn issue(&self, node_id: &str) -> String {
+        let issued = now_millis();
+        let expires = issued + TOKEN_TTL.as_millis() as u64;
+        format!(
+            "{}.{}.{}",
+            issued,
+            expires,
+            self.mac(node_id, issued, expires).to_hex()
+        )
+    }
+
+    pub fn verify(&self, node_id: &str, token: &str) -> Result<(), TokenError> {
+        let [issued, expires, mac] = token.split('.').collect::<Vec<_>>()[..] else {
+            return Err(TokenError::Invalid);
+        };
+        let (Ok(issued), Ok(expires), Ok(mac)) = (
+            issued.parse::<u64>(),
+            expires.parse::<u64>(),
+            blake3::Hash::from_hex(mac),
+        ) else {
+            return Err(TokenError::Invalid);
+        };
+        //blake3::Hash compares in constant time
+        if self.mac(node_id, issued, expires) != mac {
+            return Err(TokenError::Invalid);
+        }
+        if expires <= now_millis() {
+            return Err(TokenError::Expired);
+        }
+        if self
+            .revoked_at
+            .get(node_id)
+            .is_some_and(|revoked_at| issued <= *revoked_at)
+        {
+            return Err(TokenError::Revoked);
+        }
+        Ok(())
+    }
+
+    /// Refuse every token issued to the node so far. The revocation holds in memory even
+    /// when it can't be saved, it just wouldn't survive a restart then.
+    pub fn revoke(&self, node_id: &str) -> std::io::Result<()> {
+        self.revoked_at.insert(node_id.to_string(), now_millis());
+        self.save()
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        //every token issued before a revocation older than TOKEN_TTL has expired by now
+        let cutoff = now_millis().saturating_sub(TOKEN_TTL.as_millis() as u64);
+        self.revoked_at.retain(|_, at| *at > cutoff);
+        let mut revoked: Vec<Revocation> = self
+            .revoked_at
+            .iter()
+            .map(|entry| Revocation {
+                node_id: entry.key().clone(),
+                at: *entry.value(),
+            })
+            .collect();
+        revoked.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        let toml = toml::to_string(&RevokedFile { revoked }).map_err(std::io::Error::other)?;
+        write_atomic(&self.path, toml.as_bytes())
+    }
+
+    fn mac(&self, node_id: &str, issued: u64, expires: u64) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.secret);
+        hasher.update(node_id.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(&issued.to_be_bytes());
+        hasher.update(&expires.to_be_bytes());
+        hasher.finalize()
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    fn revoked_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "session_token_{}_{}.toml",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn tampered_tokens_are_invalid() {
+        let tokens = SessionTokens::new(Some(SECRET), revoked_file("mac")).unwrap();
+        let token = tokens.issue("node1");
+        assert!(tokens.verify("node1", &token).is_ok());
+        //someone else's token, a longer life or a made up MAC
+        assert!(matches!(
+            tokens.verify("node2", &token),
+            Err(TokenError::Invalid)
+        ));
+        let [issued, expires, mac] = token.split('.').collect::<Vec<_>>()[..] else {
+            panic!("token has three parts");
+        };
+        let extended = format!("{}.{}.{}", issued, expires.parse::<u64>().unwrap() + 1, mac);
+        assert!(matches!(
+            tokens.verify("node1", &extended),
+            Err(TokenError::Invalid)
+        ));
+        let forged = format!("{}.{}.{}", issued, expires, "00".repeat(32));
+        assert!(matches!(
+            tokens.verify("node1", &forged),
+            Err(TokenError::Invalid)
+        ));
+        assert!(matches!(
+            tokens.verify("node1", "garbage"),
+            Err(TokenError::Invalid)
+        ));
+        //a server with another secret doesn't take it either
+        let other = SessionTokens::new(None, revoked_file("mac")).unwrap();
+        assert!(matches!(
+            other.verify("node1", &token),
+            Err(TokenError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let tokens = SessionTokens::new(Some(SECRET), revoked_file("expiry")).unwrap();
+        let issued = now_millis() - TOKEN_TTL.as_millis() as u64 - 1000;
+        let expires = now_millis() - 1;
+        let token = format!(
+            "{}.{}.{}",
+            issued,
+            expires,
+            tokens.mac("node1", issued, expires).to_hex()
+        );
+        assert!(matches!(
+            tokens.verify("node1", &token),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn revocation_covers_earlier_tokens_and_survives_a_restart() {
+        let path = revoked_file("revoke");
+        let _ = fs::remove_file(&path);
+        let tokens = SessionTokens::new(Some(SECRET), &path).unwrap();
+        let before = tokens.issue("node1");
+        let other_node = tokens.issue("node2");
+        tokens.revoke("node1").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let after = tokens.issue("node1");
+
+        assert!(matches!(
+            tokens.verify("node1", &before),
+            Err(TokenError::Revoked)
+        ));
+        assert!(tokens.verify("node1", &after).is_ok());
+        assert!(tokens.verify("node2", &other_node).is_ok());
+
+        let restarted = SessionTokens::new(Some(SECRET), &path).unwrap();
+        assert!(matches!(
+            restarted.verify("node1", &before),
+            Err(TokenError::Revoked)
+        ));
+        assert!(restarted.verify("node1", &after).is_ok());
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,25 +1,44 @@
-mod forward;
-use blake3;
-use hex;
-use v_distributed_tunnel_v1::common::helper::config::{load_config, save_config};
+//Each binary only builds the forward handlers it uses, so there is no shared forward/mod.rs
+mod forward {
+    pub mod client_tunnel_handler;
+    pub mod datagram;
+    pub mod health_check;
+}
+use v_distributed_tunnel_v1::common::helper::config::{
+    check_permissions, load_config, lock_config, save_config,
+};
 
-use quinn::{ClientConfig, Endpoint, TransportConfig};
-//use rpassword::read_password;
 use clap::Parser;
+use quinn::{ClientConfig, Endpoint, TransportConfig};
 use rustls::RootCertStore;
 use rustls_pemfile::certs;
-use std::io::{self, Write};
+use std::path::Path;
 use std::time::Duration;
 use std::{env, error::Error, fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+use v_distributed_tunnel_v1::common::admin::client_config::ClientConfig as TunnelConfig;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "QUIC Tunnel Client", long_about = None)]
 struct Args {
-    #[arg(long)]
+    /// Log in as a password node instead of with the hash chain in config.toml
+    #[arg(long, conflicts_with_all = ["enroll", "encrypt"])]
     node_id: Option<String>,
 
-    #[arg(long)]
+    /// Password of the node given with --node-id, prompted for when left out
+    #[arg(long, requires = "node_id")]
     password: Option<String>,
+
+    /// Enrollment token from `tunnel_admin add`, grows a fresh hash chain and registers it
+    #[arg(long)]
+    enroll: Option<String>,
+
+    /// Encrypt the seed in config.toml with a passphrase from now on
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Read the passphrase of an encrypted config from this file instead of prompting for it
+    #[arg(long)]
+    passphrase_file: Option<String>,
 }
 
 //Read a self-signed certificate of server and trust it
@@ -37,6 +56,7 @@ fn load_root_certs(path: &str) -> Result<RootCertStore, Box<dyn Error>> {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv::dotenv().ok();
+    let args = Args::parse();
 
     tracing_subscriber::fmt::init();
     println!("Starting QUIC client on UDP port 5000");
@@ -58,6 +78,29 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut endpoint = Endpoint::client("[::]:0".parse()?)?;
     endpoint.set_default_client_config(client_config);
 
+    let config_path = "config.toml";
+    //A password node has no hash chain, its config.toml (if any) only lists the services
+    let password = match &args.node_id {
+        Some(_) => Some(match &args.password {
+            Some(password) => password.clone(),
+            None => rpassword::prompt_password("Password: ")?,
+        }),
+        None => None,
+    };
+    let (_config_lock, enrollment, mut config) = match &args.node_id {
+        Some(node_id) => {
+            let mut config = TunnelConfig::new(node_id.clone(), String::new(), 0, 0);
+            if Path::new(config_path).exists() {
+                config.services = load_config(config_path)?.services;
+            }
+            (None, None, config)
+        }
+        None => {
+            let (lock, enrollment, config) = load_chain_config(&args, config_path)?;
+            (Some(lock), enrollment, config)
+        }
+    };
+
     //Here we connect to server
     //When we put this to server, we need to change the IP
     let ip = env::var("SERVER_PUBLIC_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
@@ -67,107 +110,225 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let quinn_conn = endpoint.connect(server_addr, "localhost")?.await?; //Connect to server IP and using name localhost and server's self-signed cert.
     println!("Connected to {}", quinn_conn.remote_address());
 
-    //Agfter that we open the bidirectional stream
-    let (mut send_stream, mut recv_stream) = quinn_conn.open_bi().await?;
-
-    //here we prepare the new preimage to send to server for validate
-    let config_path = "config.toml";
-    let mut config = load_config(config_path);
-    let seed_bytes = hex::decode(&config.seed).expect("Invalid hex seed");
-    let mut hash = seed_bytes.to_vec();
-
-    for i in 0..config.current_index {
-        hash = blake3::hash(&hash).as_bytes().to_vec();
-        let computed = blake3::hash(&hash);
-        let computed_hex = computed.to_hex().to_string();
-        println!("New hash {}: {}", i, computed_hex);
+    if let Some(secret) = enrollment {
+        //ENROLL <node id> <token secret> <anchor>, the seed itself never leaves this machine
+        let (mut send, mut recv) = quinn_conn.open_bi().await?;
+        send.write_all(
+            format!("ENROLL {} {} {}\n", config.node_id, secret, config.anchor()).as_bytes(),
+        )
+        .await?;
+        send.finish()?;
+        let reply = recv.read_to_end(1024).await?;
+        let reply = String::from_utf8_lossy(&reply);
+        println!("Response: {}", reply.trim());
+        if reply.trim() != "Enrolled: Success" {
+            println!("Enrollment failed!");
+            return Ok(());
+        }
+        println!("Enrolled as node '{}'", config.node_id);
     }
-    let preimage_hex = hex::encode(&hash);
 
-    //First we send auth message to server in the format AUTH <node_id> <new hex preimage>
+    let mut try_token = password.is_none() && config.has_session_token();
+    let (mut recv_stream, mut linebuf, authenticated, assigned_ports, shared_services) = 'login: loop {
+        //Agfter that we open the bidirectional stream
+        let (mut send_stream, mut recv_stream) = quinn_conn.open_bi().await?;
 
-    let auth_message = format!("AUTH {} {}\n", config.node_id, preimage_hex);
-    println!("[Auth Message]: {}", auth_message);
-    send_stream.write_all(auth_message.as_bytes()).await?; //We can only send bytes in the stream
-    send_stream.finish()?;
+        //First we send auth message to server in the format AUTH <node_id> <new hex preimage>,
+        //or AUTH-PASSWORD <node_id> <password> for a password node, or AUTH-TOKEN <node_id> <token>,
+        //followed by one SERVICE <name> <protocol> line per local service we want to publish
+        let mut auth_message = match &password {
+            Some(password) => format!("AUTH-PASSWORD {} {}\n", config.node_id, password),
+            //a session token from an earlier login spares a preimage
+            None if try_token && let Some(token) = &config.session_token => {
+                format!("AUTH-TOKEN {} {}\n", config.node_id, token)
+            }
+            None => {
+                //here we prepare the new preimage to send to server for validate
+                let preimage_hex = config.preimage();
+                //The preimage is spent before it leaves, so a crash after sending never makes us send it twice.
+                //If the server never saw it we are a step ahead of it, which it tolerates.
+                config.advance();
+                save_config(config_path, &config)?;
+                format!("AUTH {} {}\n", config.node_id, preimage_hex)
+            }
+        };
+        println!("Authenticating as node '{}'", config.node_id);
+        for service in &config.services {
+            auth_message.push_str(&format!("SERVICE {} {}\n", service.name, service.protocol));
+        }
+        send_stream.write_all(auth_message.as_bytes()).await?; //We can only send bytes in the stream
+        send_stream.finish()?;
 
-    let mut buf = vec![0; 1024];
-    let mut linebuf = String::new();
-    let mut authenticated = false;
-    let mut assigned_port: Option<u16> = None;
+        let mut buf = vec![0; 1024];
+        let mut linebuf = String::new();
+        let mut authenticated = false;
+        let mut ready = false;
+        let mut assigned_ports: Vec<(String, u16)> = Vec::new();
+        let mut shared_services: Vec<String> = Vec::new();
 
-    //here, we read lines in a loop until both "Success" and "ASSIGNED" are received
-    loop {
-        let n = match recv_stream.read(&mut buf).await? {
-            Some(n) if n > 0 => n,
-            _ => break,
-        };
-        linebuf.push_str(&String::from_utf8_lossy(&buf[..n]));
-
-        while let Some(idx) = linebuf.find('\n') {
-            let line = linebuf[..idx].trim();
-            println!("Response: {}", line);
-
-            if line.contains("Success") {
-                println!("Authentication successful!");
-                authenticated = true;
-                config.current_index -= 1;
-                save_config(config_path, &config);
-                //We have ti check if current index reach 0 yet or not
-                //if it already 0, we rotate the seed.
-                //We only allow client that is already authenticated to rotate the seed.
-                //So when currnet index == 1 -> In that last time, we force client to rotate the seed
-                if config.current_index == 0 {
-                    if line.starts_with("ROTATE") {
-                        println!("Rotating seed");
-                        const CHAIN_LENGTH: usize = 100;
-                        config.seed = line[6..].trim().to_string();
-                        config.current_index = CHAIN_LENGTH - 1;
-                        save_config(config_path, &config);
-                    }
+        //here, we read lines in a loop until both "Success" and "READY" are received
+        loop {
+            let n = match recv_stream.read(&mut buf).await? {
+                Some(n) if n > 0 => n,
+                _ => break,
+            };
+            linebuf.push_str(&String::from_utf8_lossy(&buf[..n]));
+
+            while let Some(idx) = linebuf.find('\n') {
+                let line = linebuf[..idx].trim();
+                //TOKEN carries our session token, that never goes to the logs
+                if !line.starts_with("TOKEN") {
+                    println!("Response: {}", line);
                 }
-            } else if line.starts_with("ASSIGNED") {
-                assigned_port = line[8..].trim().parse::<u16>().ok();
-                if let Some(port) = assigned_port {
+
+                //only the exact status line counts, a service name or a reason may say "Success" too
+                if line == "Authorized: Success" {
+                    println!("Authentication successful!");
+                    authenticated = true;
+                } else if let Some(token) = line.strip_prefix("TOKEN ") {
+                    //only a chain node keeps it, a password node can log in again at no cost
+                    if password.is_none() {
+                        config.session_token = Some(token.trim().to_string());
+                        save_config(config_path, &config)?;
+                    }
+                } else if line == "ROTATE" {
+                    //Our chain is running out. We grow the next one from a seed of our own, persisted
+                    //before its anchor leaves, and keep using the old chain until the server confirms
+                    //it saved the anchor, so a crash on either side never leaves us without a chain.
+                    config.propose();
+                    save_config(config_path, &config)?;
+                    println!("Server asked for a new hash chain, proposing one");
+                    let anchor = {
+                        let config = config.clone();
+                        tokio::task::spawn_blocking(move || config.pending_anchor()).await?
+                    };
+                    if let Some(anchor) = anchor {
+                        let mut report = quinn_conn.open_uni().await?;
+                        report
+                            .write_all(format!("ROTATED {}", anchor).as_bytes())
+                            .await?;
+                        report.finish()?;
+                    }
+                } else if line == "ROTATED" {
+                    println!("Switched to the new hash chain");
+                    config.commit_rotation();
+                    save_config(config_path, &config)?;
+                } else if let Some(assigned) = line.strip_prefix("ASSIGNED ") {
+                    //ASSIGNED <service> <port>
+                    if let Some((service, port)) = assigned.trim().split_once(' ')
+                        && let Ok(port) = port.trim().parse::<u16>()
+                    {
+                        println!(
+                            "Tunnel ready! Service '{}' is published on port {}. Connect your remote tester to this port.",
+                            service, port
+                        );
+                        assigned_ports.push((service.to_string(), port));
+                    }
+                } else if let Some(service) = line.strip_prefix("SHARED ") {
+                    //SHARED <service>: reachable through the server's shared HTTP listener by Host
                     println!(
-                        "Tunnel ready! Assigned port: {}. Connect your remote tester to this port.",
-                        port
+                        "Tunnel ready! Service '{}' is served through the shared HTTP listener of the server.",
+                        service.trim()
                     );
+                    shared_services.push(service.trim().to_string());
+                } else if let Some(refused) = line.strip_prefix("REFUSED ") {
+                    println!("Service not published: {}", refused);
+                } else if let Some(quota) = line.strip_prefix("QUOTA ") {
+                    print_quota(quota);
+                } else if line == "READY" {
+                    ready = true;
+                } else if line.starts_with("Unauthorized:") {
+                    if try_token {
+                        //expired, revoked or from before a server restart: log in the long way
+                        println!("Session token refused, logging in with the hash chain");
+                        try_token = false;
+                        config.session_token = None;
+                        save_config(config_path, &config)?;
+                        continue 'login;
+                    }
+                    println!("Authentication failed!");
+                    return Ok(());
                 }
-            } else if line.contains("Unauthorized") {
-                println!("Authentication failed!");
-                return Ok(());
-            }
 
-            linebuf = linebuf[idx + 1..].to_string(); //remove processed line from our line buffer
-        }
+                linebuf = linebuf[idx + 1..].to_string(); //remove processed line from our line buffer
+            }
 
-        //If both success and assigned, break to proceed
-        //else, listening?
-        //TODO: Is there a better way to handle reading?
-        if authenticated && assigned_port.is_some() {
-            break;
+            //If both success and ready, break to proceed
+            //else, listening?
+            //TODO: Is there a better way to handle reading?
+            if authenticated && ready {
+                break;
+            }
         }
-    }
+        break (
+            recv_stream,
+            linebuf,
+            authenticated,
+            assigned_ports,
+            shared_services,
+        );
+    };
     if authenticated {
-        if let Some(port) = assigned_port {
+        if !assigned_ports.is_empty() || !shared_services.is_empty() {
+            let ports = assigned_ports
+                .iter()
+                .map(|(service, port)| format!("{}={}", service, port))
+                .chain(
+                    shared_services
+                        .iter()
+                        .map(|service| format!("{}=shared", service)),
+                )
+                .collect::<Vec<_>>()
+                .join(", ");
             println!(
-                "Tunnel ready! Assigned port: {}. Waiting for incoming connections...",
-                port
+                "Tunnel ready! Assigned ports: {}. Waiting for incoming connections...",
+                ports
             );
+            let services = Arc::new(config.services.clone());
+            let node_id = config.node_id.clone();
+            //The server keeps the control stream open to tell us when our traffic quota runs out or renews
+            tokio::spawn(async move {
+                let mut buf = vec![0; 1024];
+                while let Ok(Some(n)) = recv_stream.read(&mut buf).await {
+                    linebuf.push_str(&String::from_utf8_lossy(&buf[..n]));
+                    while let Some(idx) = linebuf.find('\n') {
+                        let line = linebuf[..idx].trim();
+                        if let Some(quota) = line.strip_prefix("QUOTA ") {
+                            print_quota(quota);
+                        }
+                        linebuf = linebuf[idx + 1..].to_string();
+                    }
+                }
+            });
+            //Keep the server told whether our local services are actually up
+            tokio::spawn(forward::health_check::run_health_checks(
+                quinn_conn.clone(),
+                services.clone(),
+            ));
             let mut warned = false;
             //Accept new bi-directional streams from the server (each represents a remote tester connection)
             //We only start forwarding things when there is a remote tester start connecting to server end of the tunnel
             //Then server send new stream, and we can start forwarding
             loop {
-                match quinn_conn.accept_bi().await {
+                let accepted = tokio::select! {
+                    accepted = quinn_conn.accept_bi() => accepted,
+                    _ = tokio::signal::ctrl_c() => {
+                        //Tell the server right away so it frees our ports instead of waiting for the idle timeout
+                        println!("[Tunnel] Shutting down, closing the session.");
+                        quinn_conn.close(0u32.into(), b"client shutting down");
+                        endpoint.wait_idle().await;
+                        break;
+                    }
+                };
+                match accepted {
                     Ok((send_stream, recv_stream)) => {
                         println!("[Tunnel] Accepted new stream from server. Starting relay.");
                         warned = false;
                         //Each new remote tester connection gets its own tunnel handler
-                        tokio::spawn(forward::client_tunnel_handler::handle_tunnel(
+                        tokio::spawn(forward::client_tunnel_handler::handle_stream(
                             send_stream,
                             recv_stream,
+                            services.clone(),
                         ));
                     }
                     Err(e) => {
@@ -188,8 +349,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
             println!(
-                "Tunnel loop for node '{}' on port {} has ended.",
-                config.node_id, port
+                "Tunnel loop for node '{}' on ports {} has ended.",
+                node_id, ports
             );
         } else {
             println!("No assigned port received!");
@@ -198,3 +359,123 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+//Lock, enroll, decrypt and load the hash chain config. Returns the lock, which has to be held
+//while the chain is in use, and the token secret to redeem if we are enrolling.
+fn load_chain_config(
+    args: &Args,
+    config_path: &str,
+) -> Result<(File, Option<String>, TunnelConfig), Box<dyn Error>> {
+    //Only one client at a time may use a chain, a second one would spend the same preimages
+    let config_lock = lock_config(config_path)?;
+    let passphrase = if args.encrypt {
+        Some(read_passphrase(args, true)?)
+    } else {
+        None
+    };
+    let enrollment = match &args.enroll {
+        Some(token) => Some(prepare_enrollment(
+            config_path,
+            token,
+            passphrase.as_deref(),
+        )?),
+        None => None,
+    };
+    check_permissions(config_path)?;
+    let mut config = load_config(config_path)?;
+    if config.is_sealed() {
+        let passphrase = match &passphrase {
+            Some(passphrase) => passphrase.clone(),
+            None => read_passphrase(args, false)?,
+        };
+        config.unseal(&passphrase)?;
+    }
+    if let Some(passphrase) = &passphrase
+        && enrollment.is_none()
+    {
+        config.encrypt_with(passphrase)?;
+        save_config(config_path, &config)?;
+        println!("The seed in {} is encrypted now", config_path);
+    }
+    if config.needs_checkpoints() {
+        println!("Walking the hash chain once to lay down checkpoints...");
+        config.build_checkpoints();
+        save_config(config_path, &config)?;
+    }
+    Ok((config_lock, enrollment, config))
+}
+
+//Grow a fresh chain for the node named in an enrollment token <node id>:<chain length>:<secret>
+//and persist it before the server hears of it. Services already in the config are kept,
+//the config itself is backed up.
+//Returns the token secret to redeem.
+fn prepare_enrollment(
+    config_path: &str,
+    token: &str,
+    passphrase: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let mut parts = token.trim().rsplitn(3, ':');
+    let (Some(secret), Some(chain_length), Some(node_id)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err("Malformed enrollment token".into());
+    };
+    let chain_length: usize = chain_length
+        .parse()
+        .map_err(|_| "Malformed chain length in enrollment token")?;
+    if chain_length < 2 {
+        return Err("Chain length in enrollment token is too short".into());
+    }
+    let seed = TunnelConfig::encode_seed(&TunnelConfig::generate_seed());
+    println!(
+        "Growing a hash chain of {} links for node '{}'...",
+        chain_length, node_id
+    );
+    let mut config = TunnelConfig::new(node_id.to_string(), seed, chain_length - 1, chain_length);
+    if Path::new(config_path).exists() {
+        config.services = load_config(config_path)?.services;
+        //the old chain still works if the token turns out to be bad
+        let backup = format!("{}.bak", config_path);
+        std::fs::copy(config_path, &backup)?;
+        println!("Previous config saved to {}", backup);
+    }
+    if let Some(passphrase) = passphrase {
+        config.encrypt_with(passphrase)?;
+    }
+    save_config(config_path, &config)?;
+    Ok(secret.to_string())
+}
+
+//Passphrase of an encrypted config: --passphrase-file, then TUNNEL_PASSPHRASE, then a prompt.
+//A new passphrase typed at the prompt has to be typed twice.
+fn read_passphrase(args: &Args, confirm: bool) -> Result<String, Box<dyn Error>> {
+    if let Some(path) = &args.passphrase_file {
+        //the keyfile is as secret as the seed it unlocks
+        check_permissions(path)?;
+        return Ok(std::fs::read_to_string(path)?.trim_end().to_string());
+    }
+    if let Ok(passphrase) = env::var("TUNNEL_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Passphrase for config.toml: ")?;
+    if confirm && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+        return Err("Passphrases don't match".into());
+    }
+    if passphrase.is_empty() {
+        return Err("Empty passphrase".into());
+    }
+    Ok(passphrase)
+}
+
+//QUOTA ok | QUOTA exhausted <daily|monthly> <limit in bytes>
+fn print_quota(quota: &str) {
+    let parts: Vec<&str> = quota.split_whitespace().collect();
+    match parts.as_slice() {
+        ["ok"] => println!("[Tunnel] Traffic quota available again, connections are relayed."),
+        ["exhausted", period, limit] => println!(
+            "[Tunnel] The {} traffic quota of {} bytes is used up, the server refuses connections until it renews.",
+            period, limit
+        ),
+        _ => eprintln!("[Tunnel] Unknown quota message: {}", quota),
+    }
+}
@@ -1,22 +1,94 @@
+use super::datagram::{MAX_DATAGRAM, UDP_IDLE_TIMEOUT_SECS, read_datagram, write_datagram};
 use quinn::{RecvStream, SendStream};
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UdpSocket};
+use v_distributed_tunnel_v1::common::admin::client_config::{ServiceConfig, ServiceProtocol};
+
+//How long we wait for the local service to accept before telling the server it's unavailable
+const LOCAL_CONNECT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+//Stream reset code for "the local service did not accept the connection"
+const BACKEND_UNAVAILABLE: u32 = 1;
+
+/// Read the `STREAM <service>` line the server writes at the start of every stream it opens.
+///
+/// We read byte by byte so that nothing after the newline (the actual payload) is consumed.
+async fn read_stream_header(recv_stream: &mut RecvStream) -> anyhow::Result<String> {
+    let mut header = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        recv_stream.read_exact(&mut byte).await?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        header.push(byte[0]);
+        if header.len() > 256 {
+            anyhow::bail!("stream header too long");
+        }
+    }
+    let header = String::from_utf8(header)?;
+    match header.trim().strip_prefix("STREAM ") {
+        Some(service) => Ok(service.trim().to_string()),
+        None => anyhow::bail!("invalid stream header '{}'", header.trim()),
+    }
+}
+
+//The server names the target either by service name or, for routes like "laptop_1:8080", by local port.
+//We only ever connect to ports of services listed in our own config.
+fn resolve_service<'a>(services: &'a [ServiceConfig], target: &str) -> Option<&'a ServiceConfig> {
+    services.iter().find(|s| s.name == target).or_else(|| {
+        let port = target.parse::<u16>().ok()?;
+        services.iter().find(|s| s.local_port == port)
+    })
+}
+
+//Entry point for every stream the server opens: figure out which local service it is for, then relay.
+pub async fn handle_stream(
+    send_stream: SendStream,
+    mut recv_stream: RecvStream,
+    services: Arc<Vec<ServiceConfig>>,
+) -> anyhow::Result<()> {
+    let target = read_stream_header(&mut recv_stream).await?;
+    let service = match resolve_service(&services, &target) {
+        Some(service) => service,
+        None => {
+            eprintln!("[Tunnel] Server asked for unknown service '{}'", target);
+            return Ok(());
+        }
+    };
+    match service.protocol {
+        ServiceProtocol::Tcp | ServiceProtocol::Http => {
+            handle_tunnel(send_stream, recv_stream, service.local_port).await
+        }
+        ServiceProtocol::Udp => {
+            handle_udp_tunnel(send_stream, recv_stream, service.local_port).await
+        }
+    }
+}
 
 //This function is for client to act as a relay to connect QUIC stream to the local service through TCP stream
 pub async fn handle_tunnel(
     mut send_stream: SendStream,
-    mut recv_stream: RecvStream,
+    recv_stream: RecvStream,
+    local_service_port: u16,
 ) -> anyhow::Result<()> {
-    let local_service_port = 8080;
     //Connect to the local service on the assigned port. For example 8080
-    // If the local service isn't up, retry a few times before failing
-    let mut tcp_stream = loop {
-        match TcpStream::connect(("127.0.0.1", local_service_port)).await {
-            Ok(s) => break s,
-            Err(e) => {
-                eprintln!("[Tunnel] Failed to connect to local service: {e}. Retrying in 1s...");
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            }
+    //If the local service isn't up, give up right away instead of leaving the remote side hanging
+    let connect = TcpStream::connect(("127.0.0.1", local_service_port));
+    let mut tcp_stream = match tokio::time::timeout(LOCAL_CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            eprintln!(
+                "[Tunnel] Failed to connect to local service on port {local_service_port}: {e}"
+            );
+            let _ = send_stream.reset(BACKEND_UNAVAILABLE.into());
+            return Ok(());
+        }
+        Err(_) => {
+            eprintln!("[Tunnel] Local service on port {local_service_port} did not accept in time");
+            let _ = send_stream.reset(BACKEND_UNAVAILABLE.into());
+            return Ok(());
         }
     };
 
@@ -72,4 +144,47 @@ pub async fn handle_tunnel(
 
     println!("[Tunnel] Bidirectional relay fully completed.");
     Ok(())
-}
\ No newline at end of file
+}
+
+//Same relay for UDP services: one QUIC stream carries the datagrams of one remote peer.
+pub async fn handle_udp_tunnel(
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+    local_service_port: u16,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).await?;
+    socket.connect(("127.0.0.1", local_service_port)).await?;
+
+    //Forwarding: QUIC -> UDP
+    let quic_to_udp = async {
+        while let Some(datagram) = read_datagram(&mut recv_stream).await? {
+            socket.send(&datagram).await?;
+        }
+        println!("[Tunnel] QUIC -> UDP: QUIC closed connection");
+        anyhow::Ok(())
+    };
+
+    //Forwarding: UDP -> QUIC, until the local service goes quiet for too long
+    let udp_to_quic = async {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let idle = tokio::time::Duration::from_secs(UDP_IDLE_TIMEOUT_SECS);
+        while let Ok(n) = tokio::time::timeout(idle, socket.recv(&mut buf)).await {
+            write_datagram(&mut send_stream, &buf[..n?]).await?;
+        }
+        println!("[Tunnel] UDP -> QUIC: local service idle, closing");
+        anyhow::Ok(())
+    };
+
+    //Unlike TCP there is no half close, whichever side ends first ends the relay
+    let result = tokio::select! {
+        res = quic_to_udp => res,
+        res = udp_to_quic => res,
+    };
+    let _ = send_stream.finish();
+    if let Err(e) = result {
+        eprintln!("[Tunnel] UDP relay error: {e}");
+    }
+
+    println!("[Tunnel] UDP relay completed.");
+    Ok(())
+}
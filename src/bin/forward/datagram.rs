@@ -0,0 +1,29 @@
+use quinn::{ReadExactError, RecvStream, SendStream};
+
+//UDP has no stream, so each datagram rides the QUIC stream as a frame:
+// <payload length as u16 big endian><payload>
+pub const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+//A UDP "connection" is torn down after this long without traffic from the peer
+pub const UDP_IDLE_TIMEOUT_SECS: u64 = 60;
+
+pub async fn write_datagram(send_stream: &mut SendStream, payload: &[u8]) -> anyhow::Result<()> {
+    send_stream
+        .write_all(&(payload.len() as u16).to_be_bytes())
+        .await?;
+    send_stream.write_all(payload).await?;
+    Ok(())
+}
+
+/// Read the next datagram frame. Returns `None` once the other side finished the stream.
+pub async fn read_datagram(recv_stream: &mut RecvStream) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match recv_stream.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut payload = vec![0u8; u16::from_be_bytes(len) as usize];
+    recv_stream.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
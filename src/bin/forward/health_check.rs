@@ -0,0 +1,117 @@
+use quinn::Connection;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use v_distributed_tunnel_v1::common::admin::client_config::{HealthCheckConfig, ServiceConfig};
+
+/// Run the configured health checks of every service until the connection closes.
+///
+/// Each state change is reported to the server on a fresh unidirectional stream:
+/// `HEALTH <service> <local port> <up|down>`. The first result is reported right away,
+/// after that a service needs `unhealthy_threshold` failures (or `healthy_threshold`
+/// successes) in a row to flip.
+pub async fn run_health_checks(conn: Connection, services: Arc<Vec<ServiceConfig>>) {
+    let mut checks = tokio::task::JoinSet::new();
+    for service in services.iter() {
+        if let Some(check) = service.health_check.clone() {
+            checks.spawn(check_service(conn.clone(), service.clone(), check));
+        }
+    }
+    while checks.join_next().await.is_some() {}
+}
+
+async fn check_service(conn: Connection, service: ServiceConfig, check: HealthCheckConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(check.interval_secs.max(1)));
+    let mut healthy: Option<bool> = None; //nothing reported yet
+    let mut streak = 0u32; //consecutive results that disagree with `healthy`
+
+    loop {
+        tokio::select! {
+            _ = conn.closed() => return,
+            _ = interval.tick() => {}
+        }
+
+        let up = probe(&service, &check).await;
+        let flip = match healthy {
+            None => true,
+            Some(current) if current == up => {
+                streak = 0;
+                false
+            }
+            Some(_) => {
+                streak += 1;
+                let threshold = if up {
+                    check.healthy_threshold
+                } else {
+                    check.unhealthy_threshold
+                };
+                streak >= threshold.max(1)
+            }
+        };
+        if !flip {
+            continue;
+        }
+
+        healthy = Some(up);
+        streak = 0;
+        let state = if up { "up" } else { "down" };
+        println!("[Health] Service '{}' is {}", service.name, state);
+        if let Err(e) = report(&conn, &service, state).await {
+            eprintln!(
+                "[Health] Failed to report service '{}': {}",
+                service.name, e
+            );
+            //try again on the next tick
+            healthy = None;
+        }
+    }
+}
+
+async fn report(conn: &Connection, service: &ServiceConfig, state: &str) -> anyhow::Result<()> {
+    let mut send_stream = conn.open_uni().await?;
+    send_stream
+        .write_all(format!("HEALTH {} {} {}\n", service.name, service.local_port, state).as_bytes())
+        .await?;
+    send_stream.finish()?;
+    Ok(())
+}
+
+async fn probe(service: &ServiceConfig, check: &HealthCheckConfig) -> bool {
+    let timeout = Duration::from_secs(check.timeout_secs.max(1));
+    matches!(
+        tokio::time::timeout(
+            timeout,
+            probe_once(service.local_port, check.http_path.as_deref())
+        )
+        .await,
+        Ok(Ok(true))
+    )
+}
+
+//TCP connect, and with a path, one GET that has to answer 2xx or 3xx
+async fn probe_once(local_port: u16, http_path: Option<&str>) -> anyhow::Result<bool> {
+    let mut tcp_stream = TcpStream::connect(("127.0.0.1", local_port)).await?;
+    let Some(path) = http_path else {
+        return Ok(true);
+    };
+
+    tcp_stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\n\r\n",
+                path, local_port
+            )
+            .as_bytes(),
+        )
+        .await?;
+    //"HTTP/1.1 200 OK" -> we only need the status code
+    let mut buf = [0u8; 64];
+    let n = tcp_stream.read(&mut buf).await?;
+    let status_line = String::from_utf8_lossy(&buf[..n]);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+    Ok(matches!(status, Some(200..=399)))
+}
@@ -1,2 +0,0 @@
-pub mod client_tunnel_handler;
-pub mod server_tunnel_handler;
@@ -1,47 +1,120 @@
+use super::datagram::{UDP_IDLE_TIMEOUT_SECS, read_datagram, write_datagram};
+use crate::pool::traffic::{Direction, TrafficMeter};
 use quinn::{RecvStream, SendStream};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+/// Tell the client which of its local services a freshly opened stream is for.
+///
+/// This is always the first line on the stream: `STREAM <service>\n`. The service is
+/// either a service name or, for routes written as `node:port`, the client's local port.
+pub async fn write_stream_header(
+    send_stream: &mut SendStream,
+    service: &str,
+) -> anyhow::Result<()> {
+    send_stream
+        .write_all(format!("STREAM {}\n", service).as_bytes())
+        .await?;
+    Ok(())
+}
+
+pub type ForwardFn = Arc<
+    dyn Fn(TcpStream, SendStream, RecvStream, TrafficMeter) -> tokio::task::JoinHandle<()>
+        + Send
+        + Sync,
+>;
 
 //Returns Arc for use in TCP listener code (Checkout server)
-pub fn make_forward_fn()
--> Arc<dyn Fn(TcpStream, SendStream, RecvStream) -> tokio::task::JoinHandle<()> + Send + Sync> {
-    Arc::new(|mut tcp_stream, mut send_stream, mut recv_stream| {
-        tokio::spawn(async move {
-            let (mut tcp_reader, mut tcp_writer) = tcp_stream.split();
-            let (mut quic_writer, mut quic_reader) = (send_stream, recv_stream);
-
-            let tcp_to_quic = async {
-                let mut buf = [0u8; 4096];
-                loop {
-                    let n = tcp_reader.read(&mut buf).await?;
-                    if n == 0 {
-                        break;
-                    }
-                    quic_writer.write_all(&buf[..n]).await?;
-                }
-                quic_writer.finish()?;
-                Ok::<(), std::io::Error>(())
-            };
-
-            let quic_to_tcp = async {
-                let mut buf = [0u8; 4096];
-                loop {
-                    let n = quic_reader.read(&mut buf).await?;
-                    if let Some(n) = n {
-                        if n == 0 {
-                            break;
-                        }
-                        tcp_writer.write_all(&buf[..n]).await?;
-                    } else {
-                        break;
-                    }
+pub fn make_forward_fn() -> ForwardFn {
+    Arc::new(|tcp_stream, send_stream, recv_stream, meter| {
+        tokio::spawn(relay_stream(tcp_stream, send_stream, recv_stream, meter))
+    })
+}
+
+//Bidirectional copy between a public stream (plain TCP or TLS) and a QUIC stream to the node.
+//Every chunk goes through the meter first, which shapes it and counts it against the node's quota.
+pub async fn relay_stream<S>(
+    stream: S,
+    send_stream: SendStream,
+    recv_stream: RecvStream,
+    meter: TrafficMeter,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut tcp_reader, mut tcp_writer) = tokio::io::split(stream);
+    let (mut quic_writer, mut quic_reader) = (send_stream, recv_stream);
+
+    let tcp_to_quic = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = tcp_reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            meter.pass(Direction::Ingress, n).await?;
+            quic_writer.write_all(&buf[..n]).await?;
+        }
+        quic_writer.finish()?;
+        Ok::<(), std::io::Error>(())
+    };
+
+    let quic_to_tcp = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = quic_reader.read(&mut buf).await?;
+            if let Some(n) = n {
+                if n == 0 {
+                    break;
                 }
-                tcp_writer.shutdown().await?;
-                Ok::<(), std::io::Error>(())
-            };
+                meter.pass(Direction::Egress, n).await?;
+                tcp_writer.write_all(&buf[..n]).await?;
+            } else {
+                break;
+            }
+        }
+        tcp_writer.shutdown().await?;
+        Ok::<(), std::io::Error>(())
+    };
 
-            let _ = tokio::try_join!(tcp_to_quic, quic_to_tcp);
-        })
-    })
+    let _ = tokio::try_join!(tcp_to_quic, quic_to_tcp);
+}
+
+//Relay the datagrams of one remote UDP peer over its own QUIC stream.
+//The listener feeds us the peer's datagrams through `from_peer`, replies go straight out of the shared socket.
+pub async fn relay_udp_peer(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+    mut from_peer: mpsc::Receiver<Vec<u8>>,
+    meter: TrafficMeter,
+) {
+    let peer_to_quic = async {
+        let idle = tokio::time::Duration::from_secs(UDP_IDLE_TIMEOUT_SECS);
+        while let Ok(Some(datagram)) = tokio::time::timeout(idle, from_peer.recv()).await {
+            meter.pass(Direction::Ingress, datagram.len()).await?;
+            write_datagram(&mut send_stream, &datagram).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let quic_to_peer = async {
+        while let Some(datagram) = read_datagram(&mut recv_stream).await? {
+            meter.pass(Direction::Egress, datagram.len()).await?;
+            socket.send_to(&datagram, peer).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let result = tokio::select! {
+        res = peer_to_quic => res,
+        res = quic_to_peer => res,
+    };
+    let _ = send_stream.finish();
+    if let Err(e) = result {
+        eprintln!("UDP relay for {} failed: {:?}", peer, e);
+    }
 }
@@ -0,0 +1,494 @@
+use dashmap::DashMap;
+use ipnet::{IpNet, Ipv6Net};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+//New connections per minute a single source IP may open, and how many it may keep open
+pub const DEFAULT_RATE_PER_MINUTE: u32 = 600;
+pub const DEFAULT_MAX_PER_IP: u32 = 100;
+/// IPv6 sources are limited per network of this size: one host usually gets a whole /64, so
+/// per address limits would be no limit at all.
+pub const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+//How often buckets that refilled completely are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why a public connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The source address is on a deny list, or missing from an allow list.
+    Denied,
+    /// The source opened connections faster than the rate limit.
+    RateLimited,
+    /// The source already holds as many connections as it may.
+    TooManyConnections,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Denied => write!(f, "denied by the ip rules"),
+            Rejection::RateLimited => write!(f, "connection rate limit reached"),
+            Rejection::TooManyConnections => write!(f, "too many connections"),
+        }
+    }
+}
+
+/// CIDR allow/deny lists. Deny always wins; an empty allow list allows everyone else.
+#[derive(Clone, Default)]
+pub struct IpRules {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl IpRules {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Per source limits, 0 turns a limit off. A source is an IPv4 address or an IPv6 network of
+/// `ipv6_prefix` bits.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub rate_per_minute: u32,
+    pub max_per_ip: u32,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            rate_per_minute: DEFAULT_RATE_PER_MINUTE,
+            max_per_ip: DEFAULT_MAX_PER_IP,
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+        }
+    }
+}
+
+impl Limits {
+    //The network the limits of `ip` are counted against
+    fn source(&self, ip: IpAddr) -> IpNet {
+        match ip {
+            IpAddr::V4(_) => IpNet::from(ip),
+            IpAddr::V6(v6) => Ipv6Net::new(v6, self.ipv6_prefix.min(128))
+                .map(|net| IpNet::V6(net.trunc()))
+                .unwrap_or_else(|_| IpNet::from(ip)),
+        }
+    }
+}
+
+/// What the guard has let through and turned away since the server started.
+#[derive(Default)]
+pub struct Counters {
+    pub accepted: AtomicU64,
+    pub denied: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub too_many: AtomicU64,
+}
+
+impl Counters {
+    fn count(&self, rejection: Rejection) {
+        let counter = match rejection {
+            Rejection::Denied => &self.denied,
+            Rejection::RateLimited => &self.rate_limited,
+            Rejection::TooManyConnections => &self.too_many,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+//Token bucket of one source: holds up to `rate_per_minute` tokens, refilled continuously
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate_per_minute: u32) {
+        let capacity = rate_per_minute as f64;
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.last = now;
+    }
+}
+
+/// Gatekeeper of every public listener, checked when a connection is accepted and before
+/// anything is opened towards a node:
+///
+/// 1. `admit` on accept: the global allow/deny lists, then the connection rate and the
+///    concurrent connection cap of the source (its IPv4 address, or its IPv6 network, see
+///    `Limits`). The returned `Admitted` holds the connection's slot.
+/// 2. `check_node` once the node is known (right away on a node's own port, after routing on
+///    the shared HTTP listener): the allow/deny lists of that node.
+///
+/// Lists and limits can be changed at runtime from the admin port, which also shows the counters.
+pub struct ConnectionGuard {
+    global: RwLock<IpRules>,
+    nodes: DashMap<String, IpRules>,
+    limits: RwLock<Limits>,
+    buckets: DashMap<IpNet, Bucket>,
+    active: Arc<DashMap<IpNet, u32>>,
+    pub counters: Counters,
+}
+
+/// A connection let in by `ConnectionGuard::admit`, frees its slot of the source on drop.
+pub struct Admitted {
+    source: IpNet,
+    active: Arc<DashMap<IpNet, u32>>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.active.remove_if_mut(&self.source, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+impl ConnectionGuard {
+    pub fn new(global: IpRules, limits: Limits) -> Self {
+        Self {
+            global: RwLock::new(global),
+            nodes: DashMap::new(),
+            limits: RwLock::new(limits),
+            buckets: DashMap::new(),
+            active: Arc::new(DashMap::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn admit(&self, ip: IpAddr) -> Result<Admitted, Rejection> {
+        //an IPv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        let result = self.try_admit(ip, Instant::now());
+        match &result {
+            Ok(_) => {
+                self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(rejection) => self.counters.count(*rejection),
+        }
+        result
+    }
+
+    fn try_admit(&self, ip: IpAddr, now: Instant) -> Result<Admitted, Rejection> {
+        if !self.global.read().unwrap().permits(ip) {
+            return Err(Rejection::Denied);
+        }
+        let limits = *self.limits.read().unwrap();
+        let source = limits.source(ip);
+
+        if limits.rate_per_minute > 0 {
+            let mut bucket = self.buckets.entry(source).or_insert_with(|| Bucket {
+                tokens: limits.rate_per_minute as f64,
+                last: now,
+            });
+            bucket.refill(now, limits.rate_per_minute);
+            if bucket.tokens < 1.0 {
+                return Err(Rejection::RateLimited);
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        let mut active = self.active.entry(source).or_insert(0);
+        if limits.max_per_ip > 0 && *active >= limits.max_per_ip {
+            return Err(Rejection::TooManyConnections);
+        }
+        *active += 1;
+        Ok(Admitted {
+            source,
+            active: self.active.clone(),
+        })
+    }
+
+    /// Forget the buckets that refilled completely every `PRUNE_INTERVAL`, a source that comes
+    /// back just starts with a full one again. Keeps `admit` from ever sweeping the whole map.
+    pub async fn prune_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.prune(Instant::now());
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        let rate_per_minute = self.limits.read().unwrap().rate_per_minute;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now, rate_per_minute);
+            bucket.tokens < rate_per_minute as f64
+        });
+    }
+
+    pub fn check_node(&self, node_id: &str, ip: IpAddr) -> Result<(), Rejection> {
+        let permitted = self
+            .nodes
+            .get(node_id)
+            .is_none_or(|rules| rules.permits(ip.to_canonical()));
+        if permitted {
+            Ok(())
+        } else {
+            self.counters.count(Rejection::Denied);
+            Err(Rejection::Denied)
+        }
+    }
+
+    /// Add a network to the allow (`allow == true`) or deny list, of a node or of everyone.
+    pub fn add_rule(&self, node_id: Option<&str>, allow: bool, network: IpNet) {
+        self.update_rules(node_id, |rules| {
+            let list = if allow {
+                &mut rules.allow
+            } else {
+                &mut rules.deny
+            };
+            if !list.contains(&network) {
+                list.push(network);
+            }
+        });
+    }
+
+    /// Returns whether the network was on that list.
+    pub fn remove_rule(&self, node_id: Option<&str>, allow: bool, network: IpNet) -> bool {
+        let mut removed = false;
+        self.update_rules(node_id, |rules| {
+            let list = if allow {
+                &mut rules.allow
+            } else {
+                &mut rules.deny
+            };
+            let before = list.len();
+            list.retain(|existing| *existing != network);
+            removed = list.len() != before;
+        });
+        removed
+    }
+
+    fn update_rules(&self, node_id: Option<&str>, update: impl FnOnce(&mut IpRules)) {
+        match node_id {
+            None => update(&mut self.global.write().unwrap()),
+            Some(node_id) => {
+                let mut rules = self.nodes.entry(node_id.to_string()).or_default();
+                update(&mut rules);
+                if rules.is_empty() {
+                    drop(rules);
+                    self.nodes.remove(node_id);
+                }
+            }
+        }
+    }
+
+    pub fn global_rules(&self) -> IpRules {
+        self.global.read().unwrap().clone()
+    }
+
+    pub fn node_rules(&self) -> Vec<(String, IpRules)> {
+        let mut rules: Vec<(String, IpRules)> = self
+            .nodes
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        rules.sort_by(|a, b| a.0.cmp(&b.0));
+        rules
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Sources holding the most connections right now, busiest first.
+    pub fn busiest(&self, max: usize) -> Vec<(IpNet, u32)> {
+        let mut active: Vec<(IpNet, u32)> = self
+            .active
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        active.sort_by_key(|(_, open)| std::cmp::Reverse(*open));
+        active.truncate(max);
+        active
+    }
+}
+
+/// Parse a comma separated list of networks; a bare address counts as a single host.
+pub fn parse_networks(list: &str) -> anyhow::Result<Vec<IpNet>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(parse_network)
+        .collect()
+}
+
+pub fn parse_network(item: &str) -> anyhow::Result<IpNet> {
+    match item.parse::<IpNet>() {
+        Ok(network) => Ok(network),
+        Err(_) => item
+            .parse::<IpAddr>()
+            .map(IpNet::from)
+            .map_err(|_| anyhow::anyhow!("'{}' is not a network or an address", item)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn rules(allow: &str, deny: &str) -> IpRules {
+        IpRules {
+            allow: parse_networks(allow).unwrap(),
+            deny: parse_networks(deny).unwrap(),
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let open = rules("", "");
+        assert!(open.permits(ip("203.0.113.7")));
+
+        let rules = rules("10.0.0.0/8, 2001:db8::/32", "10.1.0.0/16, 10.2.3.4");
+        assert!(rules.permits(ip("10.9.9.9")));
+        assert!(rules.permits(ip("2001:db8::1")));
+        assert!(!rules.permits(ip("10.1.2.3")));
+        assert!(!rules.permits(ip("10.2.3.4")));
+        assert!(rules.permits(ip("10.2.3.5")));
+        //an allow list shuts out everyone it doesn't name
+        assert!(!rules.permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn node_rules_only_apply_to_that_node() {
+        let guard = ConnectionGuard::new(rules("", "192.0.2.0/24"), Limits::default());
+        guard.add_rule(
+            Some("node1"),
+            true,
+            parse_network("198.51.100.0/24").unwrap(),
+        );
+        assert!(guard.check_node("node1", ip("198.51.100.5")).is_ok());
+        assert_eq!(
+            guard.check_node("node1", ip("203.0.113.7")),
+            Err(Rejection::Denied)
+        );
+        assert!(guard.check_node("node2", ip("203.0.113.7")).is_ok());
+        //the global deny list is checked on admit, whatever the node allows
+        assert_eq!(guard.admit(ip("192.0.2.1")).err(), Some(Rejection::Denied));
+
+        assert!(guard.remove_rule(
+            Some("node1"),
+            true,
+            parse_network("198.51.100.0/24").unwrap()
+        ));
+        assert!(guard.node_rules().is_empty());
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let guard = ConnectionGuard::new(
+            IpRules::default(),
+            Limits {
+                rate_per_minute: 2,
+                max_per_ip: 0,
+                ..Limits::default()
+            },
+        );
+        let start = Instant::now();
+        let source = ip("203.0.113.7");
+        assert!(guard.try_admit(source, start).is_ok());
+        assert!(guard.try_admit(source, start).is_ok());
+        assert_eq!(
+            guard.try_admit(source, start).err(),
+            Some(Rejection::RateLimited)
+        );
+        //2 per minute is one token every 30s
+        assert!(
+            guard
+                .try_admit(source, start + Duration::from_secs(20))
+                .is_err()
+        );
+        assert!(
+            guard
+                .try_admit(source, start + Duration::from_secs(30))
+                .is_ok()
+        );
+        //a full bucket never holds more than a minute's worth
+        let later = start + Duration::from_secs(3600);
+        assert!(guard.try_admit(source, later).is_ok());
+        assert!(guard.try_admit(source, later).is_ok());
+        assert!(guard.try_admit(source, later).is_err());
+        //other sources have buckets of their own
+        assert!(guard.try_admit(ip("203.0.113.8"), start).is_ok());
+    }
+
+    #[test]
+    fn ipv6_sources_share_limits_per_network() {
+        let guard = ConnectionGuard::new(
+            IpRules::default(),
+            Limits {
+                rate_per_minute: 0,
+                max_per_ip: 2,
+                ..Limits::default()
+            },
+        );
+        let first = guard.admit(ip("2001:db8:1:2::1")).unwrap();
+        let _second = guard.admit(ip("2001:db8:1:2:ffff::9")).unwrap();
+        assert_eq!(
+            guard.admit(ip("2001:db8:1:2::3")).err(),
+            Some(Rejection::TooManyConnections)
+        );
+        assert!(guard.admit(ip("2001:db8:1:3::1")).is_ok());
+        assert_eq!(
+            guard.busiest(1),
+            vec![(parse_network("2001:db8:1:2::/64").unwrap(), 2)]
+        );
+        drop(first);
+        assert!(guard.admit(ip("2001:db8:1:2::3")).is_ok());
+
+        //with /128 every address is on its own again
+        guard.set_limits(Limits {
+            rate_per_minute: 0,
+            max_per_ip: 1,
+            ipv6_prefix: 128,
+        });
+        assert!(guard.admit(ip("2001:db8:9::1")).is_ok());
+        assert!(guard.admit(ip("2001:db8:9::2")).is_ok());
+        //IPv4 is always per address
+        assert!(guard.admit(ip("192.0.2.1")).is_ok());
+        assert!(guard.admit(ip("::ffff:192.0.2.2")).is_ok());
+    }
+
+    #[test]
+    fn prune_forgets_refilled_buckets() {
+        let guard = ConnectionGuard::new(
+            IpRules::default(),
+            Limits {
+                rate_per_minute: 60,
+                max_per_ip: 0,
+                ..Limits::default()
+            },
+        );
+        let start = Instant::now();
+        for n in 0..5u8 {
+            let _ = guard.try_admit(IpAddr::from([198, 51, 100, n]), start);
+        }
+        let _ = guard.try_admit(ip("198.51.100.9"), start + Duration::from_secs(10));
+        assert_eq!(guard.buckets.len(), 6);
+        //a token comes back every second, so only the last bucket is still short of one
+        guard.prune(start + Duration::from_millis(10_500));
+        assert_eq!(guard.buckets.len(), 1);
+        guard.prune(start + Duration::from_secs(12));
+        assert!(guard.buckets.is_empty());
+    }
+}
@@ -1,2 +1,5 @@
+pub mod connection_guard;
+pub mod port_lease;
 pub mod port_pool;
 pub mod port_registry;
+pub mod traffic;
@@ -0,0 +1,217 @@
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use v_distributed_tunnel_v1::common::helper::config::write_atomic;
+
+//On disk it looks like this, one [[lease]] table per pinned port:
+// [[lease]]
+// node_id = "laptop_1"
+// port = 5123
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PortLease {
+    pub node_id: String,
+    pub port: u16,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LeaseFile {
+    #[serde(default)]
+    lease: Vec<PortLease>,
+}
+
+#[derive(Debug)]
+pub enum LeaseError {
+    OutOfRange(u16, u16),
+    AssignedToOther(String),
+    ReservedByOther(String),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeaseError::OutOfRange(start, end) => {
+                write!(f, "port is outside of the pool range {}-{}", start, end)
+            }
+            LeaseError::AssignedToOther(node_id) => {
+                write!(f, "port is currently assigned to node '{}'", node_id)
+            }
+            LeaseError::ReservedByOther(node_id) => {
+                write!(f, "port is already reserved for node '{}'", node_id)
+            }
+            LeaseError::Io(e) => write!(f, "failed to persist leases: {}", e),
+        }
+    }
+}
+
+/// Ports that the admin pinned to a node with `reserve <node> <port>`.
+///
+/// Leases are written to a TOML file on every change and loaded back at startup, so a node
+/// keeps its public port across server restarts and seed rotations.
+pub struct LeaseStore {
+    path: PathBuf,
+    leases: DashMap<u16, String>, //port -> node id
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl LeaseStore {
+    /// Load leases from `path`. A missing file just means nothing has been reserved yet.
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let leases = DashMap::new();
+        if path.exists() {
+            let data = fs::read_to_string(&path)?;
+            let file: LeaseFile = toml::from_str(&data)?;
+            for lease in file.lease {
+                leases.insert(lease.port, lease.node_id);
+            }
+        }
+        Ok(Self {
+            path,
+            leases,
+            save_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Write the leases out. Saves are serialized and each one writes whatever is in memory
+    /// when it gets its turn, so the last one to finish always has the latest leases. The
+    /// write happens off the runtime.
+    async fn save(&self) -> std::io::Result<()> {
+        let _saving = self.save_lock.lock().await;
+        let mut lease: Vec<PortLease> = self.list();
+        lease.sort_by_key(|l| l.port);
+        let toml = toml::to_string(&LeaseFile { lease }).map_err(std::io::Error::other)?;
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || write_atomic(&path, toml.as_bytes()))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+    }
+
+    /// Pin `port` to `node_id` in memory only, returns false when the node already holds it.
+    /// A new lease must be followed by `persist_claim`, which saves it or undoes it.
+    ///
+    /// Every change is undone in memory when it can't be saved, so memory and disk never
+    /// disagree for long about who holds a port.
+    pub fn claim(&self, node_id: &str, port: u16) -> Result<bool, LeaseError> {
+        match self.leases.entry(port) {
+            Entry::Occupied(owner) if owner.get() == node_id => Ok(false),
+            Entry::Occupied(owner) => Err(LeaseError::ReservedByOther(owner.get().clone())),
+            Entry::Vacant(slot) => {
+                slot.insert(node_id.to_string());
+                Ok(true)
+            }
+        }
+    }
+
+    /// Save a lease taken with `claim`, dropping it again if that fails.
+    pub async fn persist_claim(&self, node_id: &str, port: u16) -> Result<(), LeaseError> {
+        self.save().await.map_err(|e| {
+            self.leases.remove_if(&port, |_, owner| owner == node_id);
+            LeaseError::Io(e)
+        })
+    }
+
+    /// Drop the lease on `port`, returning the node it was pinned to.
+    pub async fn unreserve(&self, port: u16) -> Result<Option<String>, LeaseError> {
+        let Some((_, node_id)) = self.leases.remove(&port) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.save().await {
+            self.leases.entry(port).or_insert_with(|| node_id.clone());
+            return Err(LeaseError::Io(e));
+        }
+        Ok(Some(node_id))
+    }
+
+    /// Drop every lease of a node, e.g. when the node itself is deleted.
+    pub async fn unreserve_node(&self, node_id: &str) -> Result<(), LeaseError> {
+        let ports = self.ports_of(node_id);
+        if ports.is_empty() {
+            return Ok(());
+        }
+        for port in &ports {
+            self.leases.remove_if(port, |_, owner| owner == node_id);
+        }
+        if let Err(e) = self.save().await {
+            for port in ports {
+                self.leases
+                    .entry(port)
+                    .or_insert_with(|| node_id.to_string());
+            }
+            return Err(LeaseError::Io(e));
+        }
+        Ok(())
+    }
+
+    pub fn owner(&self, port: u16) -> Option<String> {
+        self.leases.get(&port).map(|entry| entry.value().clone())
+    }
+
+    pub fn ports_of(&self, node_id: &str) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .leases
+            .iter()
+            .filter(|entry| entry.value() == node_id)
+            .map(|entry| *entry.key())
+            .collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    pub fn list(&self) -> Vec<PortLease> {
+        self.leases
+            .iter()
+            .map(|entry| PortLease {
+                node_id: entry.value().clone(),
+                port: *entry.key(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lease_is_dropped_when_it_cannot_be_saved() {
+        let path = std::env::temp_dir()
+            .join(format!("port_lease_test_{}", std::process::id()))
+            .join("missing_dir")
+            .join("leases.toml");
+        let leases = LeaseStore::load(&path).unwrap();
+
+        assert!(leases.claim("node1", 5000).unwrap());
+        assert!(matches!(
+            leases.persist_claim("node1", 5000).await,
+            Err(LeaseError::Io(_))
+        ));
+        assert_eq!(leases.owner(5000), None);
+    }
+
+    #[tokio::test]
+    async fn leases_survive_a_reload() {
+        let path =
+            std::env::temp_dir().join(format!("port_lease_test_{}.toml", std::process::id()));
+        let leases = LeaseStore::load(&path).unwrap();
+        assert!(leases.claim("node1", 5000).unwrap());
+        leases.persist_claim("node1", 5000).await.unwrap();
+        assert!(!leases.claim("node1", 5000).unwrap());
+        assert!(matches!(
+            leases.claim("node2", 5000),
+            Err(LeaseError::ReservedByOther(_))
+        ));
+
+        let reloaded = LeaseStore::load(&path).unwrap();
+        assert_eq!(reloaded.ports_of("node1"), vec![5000]);
+        assert_eq!(
+            reloaded.unreserve(5000).await.unwrap().as_deref(),
+            Some("node1")
+        );
+        assert_eq!(LeaseStore::load(&path).unwrap().owner(5000), None);
+        let _ = fs::remove_file(path);
+    }
+}
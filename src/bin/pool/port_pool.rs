@@ -1,9 +1,13 @@
+use super::port_lease::{LeaseError, LeaseStore};
 use dashmap::DashMap;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use sqlx::types::time::OffsetDateTime;
 use std::sync::Arc;
 
+//How many slots of the node's own probe sequence we try before giving up and picking a random free port.
+const MAX_PROBES: u64 = 16;
+
 #[derive(Clone)]
 pub struct Port {
     port: u16,
@@ -14,18 +18,20 @@ pub struct Port {
 
 pub enum StaticPortAssignResult {
     Success(u16),
-    SeedMissing,
-    SeedHexInvalid,
-    PortInUse(u16),
+    PoolExhausted,
 }
 
 #[derive(Clone)]
 pub struct PortPool {
     pool: Arc<DashMap<u16, Port>>, //To sum, it's a pool of ports
+    start: u16,
+    end: u16,
+    previous: Arc<DashMap<String, u16>>, //Last port each node service held, so a reconnecting node gets the same one back
+    leases: Arc<LeaseStore>,
 }
 
 impl PortPool {
-    pub fn new(start: u16, end: u16) -> Self {
+    pub fn new(start: u16, end: u16, leases: Arc<LeaseStore>) -> Self {
         let pool = Arc::new(DashMap::new());
         //At init, all ports are unassigned so assigned = falsem assign_to = None, assign_at = None
         for port in start..=end {
@@ -39,77 +45,154 @@ impl PortPool {
                 },
             );
         }
-        return Self { pool };
+        Self {
+            pool,
+            start,
+            end,
+            previous: Arc::new(DashMap::new()),
+            leases,
+        }
+    }
+
+    pub fn leases(&self) -> &LeaseStore {
+        &self.leases
+    }
+
+    //A port is off limits for a node when the admin pinned it to somebody else
+    fn reserved_for_other(&self, port: u16, node_id: &str) -> bool {
+        self.leases
+            .owner(port)
+            .is_some_and(|owner| owner != node_id)
+    }
+
+    /// Pin `port` to `node_id` so that no other node is ever handed that port.
+    ///
+    /// The port's entry stays locked while the lease is taken in memory, so a session can't
+    /// claim the port in between. Locks are always taken pool first, leases second. The
+    /// lease is saved after the entry is unlocked and dropped again if the save fails.
+    pub async fn reserve_port(&self, node_id: &str, port: u16) -> Result<(), LeaseError> {
+        {
+            let Some(port_data) = self.pool.get(&port) else {
+                return Err(LeaseError::OutOfRange(self.start, self.end));
+            };
+            if let Some(owner) = &port_data.assign_to
+                && owner != node_id
+            {
+                return Err(LeaseError::AssignedToOther(owner.clone()));
+            }
+            if !self.leases.claim(node_id, port)? {
+                return Ok(());
+            }
+        }
+        self.leases.persist_claim(node_id, port).await
+    }
+
+    /// Claim `port` for `node_id` if it is part of the pool, still free and not pinned to
+    /// another node.
+    ///
+    /// The checks and the update happen under the port's `DashMap` shard lock, so two
+    /// sessions racing for the same port cannot both win, and neither can a `reserve_port`.
+    fn try_claim(&self, port: u16, node_id: &str) -> bool {
+        match self.pool.get_mut(&port) {
+            Some(mut port_data)
+                if !port_data.assigned && !self.reserved_for_other(port, node_id) =>
+            {
+                port_data.assigned = true;
+                port_data.assign_to = Some(node_id.to_string());
+                port_data.assign_at = Some(OffsetDateTime::now_utc());
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn assign_random_port(&self, node_id: &str) -> Option<u16> {
         let mut rng = thread_rng();
-        let available_ports: Vec<u16> = self
+        let mut available_ports: Vec<u16> = self
             .pool
             .iter()
             .filter_map(|entry| {
                 if !entry.value().assigned {
-                    Some(*entry.key())
+                    Some(entry.value().port)
                 } else {
                     None
                 }
             })
             .collect(); //basically just check assigned and put all unassigned into a vector
 
-        if available_ports.len() == 0 {
-            return None;
-        }
+        //Someone may grab a port between the scan and the claim, so just move on to the next candidate.
+        available_ports.shuffle(&mut rng);
+        available_ports
+            .into_iter()
+            .find(|&port| self.try_claim(port, node_id))
+    }
 
-        let &random_port = available_ports.choose(&mut rng).unwrap();
-        if 5001 <= random_port && random_port <= 5999 {
-            let mut port_data = self.pool.get_mut(&random_port).unwrap();
-            port_data.assigned = true;
-            port_data.assign_to = Some(node_id.to_string());
-            port_data.assign_at = Some(OffsetDateTime::now_utc());
-            Some(random_port)
-        } else {
-            None
+    /// Deterministic probe sequence for a node service over the whole pool range.
+    ///
+    /// This is double hashing: the first 8 bytes of `blake3(slot)` pick the starting
+    /// slot and the next 8 bytes pick the step. The step is forced to be coprime with the
+    /// range size, so the sequence visits every port of the pool before it repeats.
+    fn probe_sequence(&self, slot: &str) -> impl Iterator<Item = u16> {
+        let hash = blake3::hash(slot.as_bytes());
+        let bytes = hash.as_bytes();
+        let range = (self.end - self.start) as u64 + 1;
+        let offset = u64::from_le_bytes(bytes[0..8].try_into().unwrap()) % range;
+        let mut step = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) % range;
+        if step == 0 {
+            step = 1;
+        }
+        while gcd(step, range) != 1 {
+            step += 1;
         }
-    }
 
-    fn static_port_from_seed(seed: &[u8]) -> u16 {
-        let hash = blake3::hash(seed);
-        5000 + (hash.as_bytes()[0] as u16 % 1000)
+        let start = self.start;
+        (0..range).map(move |i| start + ((offset + i * step) % range) as u16)
     }
 
-    pub fn assign_static_port(
-        &self,
-        node_id: &str,
-        seed_hex_opt: Option<&str>,
-    ) -> StaticPortAssignResult {
+    /// Pick a port for one service of a node that just logged in.
+    ///
+    /// In order of preference:
+    /// 1. a free port the admin reserved for this node,
+    /// 2. the port this service held last time, so clients pointed at it keep working,
+    /// 3. the first free slot among the first `MAX_PROBES` of the service's probe sequence,
+    /// 4. any free port of the pool, chosen at random.
+    ///
+    /// Ports reserved for other nodes are skipped at every step.
+    pub fn assign_static_port(&self, node_id: &str, service: &str) -> StaticPortAssignResult {
         use StaticPortAssignResult::*;
-        //check if seed present
-        let seed_hex = match seed_hex_opt {
-            Some(s) => s,
-            None => return SeedMissing,
-        };
+        //Each service of a node gets its own slot, so they don't all chase the same port
+        let slot = format!("{}/{}", node_id, service);
 
-        //we decode hex
-        let seed_bytes = match hex::decode(seed_hex) {
-            Ok(b) => b,
-            Err(_) => return SeedHexInvalid,
-        };
-
-        let port = Self::static_port_from_seed(&seed_bytes);
+        let port = self
+            .leases
+            .ports_of(node_id)
+            .into_iter()
+            .find(|&port| self.try_claim(port, node_id))
+            .or_else(|| {
+                self.previous
+                    .get(&slot)
+                    .map(|p| *p)
+                    .filter(|&port| self.try_claim(port, node_id))
+            })
+            .or_else(|| {
+                self.probe_sequence(&slot)
+                    .take(MAX_PROBES as usize)
+                    .find(|&port| self.try_claim(port, node_id))
+            })
+            .or_else(|| {
+                println!(
+                    "First {} probe slots of '{}' are taken, falling back to a random port",
+                    MAX_PROBES, slot
+                );
+                self.assign_random_port(node_id)
+            });
 
-        //only assign if that port is available
-        if let Some(mut port_data) = self.pool.get_mut(&port) {
-            if !port_data.assigned {
-                port_data.assigned = true;
-                port_data.assign_to = Some(node_id.to_string());
-                port_data.assign_at = Some(OffsetDateTime::now_utc());
+        match port {
+            Some(port) => {
+                self.previous.insert(slot, port);
                 Success(port)
-            } else {
-                PortInUse(port)
             }
-        } else {
-            // Port not in pool, treat as in use or invalid
-            PortInUse(port)
+            None => PoolExhausted,
         }
     }
 
@@ -123,16 +206,100 @@ impl PortPool {
     }
 }
 
-//Simple guard struct to ensure the port is always released, even on panic or early return.
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+//Simple guard struct to ensure the ports of a session are always released together, even on panic or early return.
 pub struct PortGuard {
     pub port_pool: Arc<PortPool>,
-    pub port: u16,
+    pub ports: Vec<u16>,
     pub node_id: String,
 }
 
 impl Drop for PortGuard {
     fn drop(&mut self) {
-        self.port_pool.release_port(self.port);
-        println!("Released port {} from node '{}'", self.port, self.node_id);
+        for port in &self.ports {
+            self.port_pool.release_port(*port);
+            println!("Released port {} from node '{}'", port, self.node_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn pool(start: u16, end: u16) -> PortPool {
+        let path = std::env::temp_dir().join(format!(
+            "port_pool_test_{}_{}_{}.toml",
+            std::process::id(),
+            start,
+            end
+        ));
+        PortPool::new(start, end, Arc::new(LeaseStore::load(path).unwrap()))
+    }
+
+    #[test]
+    fn gcd_works() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(7, 100), 1);
+        assert_eq!(gcd(5, 0), 5);
+        assert_eq!(gcd(1, 1), 1);
+    }
+
+    #[test]
+    fn probe_sequence_visits_every_port_once() {
+        //a range with lots of divisors, where a careless step would cycle early
+        for (start, end) in [(5000, 5119), (6000, 6000), (7000, 7001), (8000, 8096)] {
+            let pool = pool(start, end);
+            for slot in ["node1/web", "node2/ssh", "a/b", ""] {
+                let ports: Vec<u16> = pool.probe_sequence(slot).collect();
+                let unique: HashSet<u16> = ports.iter().copied().collect();
+                assert_eq!(ports.len(), (end - start) as usize + 1);
+                assert_eq!(unique.len(), ports.len(), "{} repeats a port", slot);
+                assert!(ports.iter().all(|port| (start..=end).contains(port)));
+            }
+        }
+    }
+
+    #[test]
+    fn probe_sequence_is_deterministic_per_slot() {
+        let pool = pool(5000, 5999);
+        let first: Vec<u16> = pool.probe_sequence("node1/web").take(16).collect();
+        let again: Vec<u16> = pool.probe_sequence("node1/web").take(16).collect();
+        let other: Vec<u16> = pool.probe_sequence("node1/ssh").take(16).collect();
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn node_gets_its_port_back_and_skips_taken_ones() {
+        let pool = pool(5000, 5009);
+        let StaticPortAssignResult::Success(port) = pool.assign_static_port("node1", "web") else {
+            panic!("pool is empty");
+        };
+        pool.release_port(port);
+        assert!(matches!(
+            pool.assign_static_port("node1", "web"),
+            StaticPortAssignResult::Success(again) if again == port
+        ));
+
+        //the other nine ports still go out, each one once
+        let mut taken = HashSet::from([port]);
+        for n in 0..9 {
+            match pool.assign_static_port(&format!("node{}", n + 2), "web") {
+                StaticPortAssignResult::Success(port) => assert!(taken.insert(port)),
+                StaticPortAssignResult::PoolExhausted => panic!("ran out after {}", n),
+            }
+        }
+        assert!(matches!(
+            pool.assign_static_port("node99", "web"),
+            StaticPortAssignResult::PoolExhausted
+        ));
     }
 }
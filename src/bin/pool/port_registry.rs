@@ -1,51 +1,56 @@
-use dashmap::DashMap;
+use crate::session::service_health::ServiceHealth;
 use quinn::Connection;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use v_distributed_tunnel_v1::common::admin::client_config::ServiceProtocol;
 
 #[derive(Clone)]
 pub struct NodeInfo {
     pub conn: Connection,
     pub node_id: String,
+    pub service: String, //Name of the client's local service published on this port
+    pub protocol: ServiceProtocol,
+    pub session_id: u64, //The session that registered this port, see `TunnelSession`
+    pub health: ServiceHealth,
 }
 
+//A plain RwLock instead of a DashMap: a session's ports must disappear from the registry
+//all at once, readers should never see half of a session that is going away.
 #[derive(Clone)]
 pub struct PortRegistry {
-    registry: Arc<DashMap<u16, NodeInfo>>,
+    registry: Arc<RwLock<HashMap<u16, NodeInfo>>>,
 }
 
 impl PortRegistry {
     pub fn new() -> Self {
         Self {
-            registry: Arc::new(DashMap::new()),
+            registry: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
     pub fn insert(&self, port: u16, node_info: NodeInfo) {
-        self.registry.insert(port, node_info);
+        self.registry.write().unwrap().insert(port, node_info);
     }
 
     pub fn get(&self, port: &u16) -> Option<NodeInfo> {
-        self.registry.get(port).map(|entry| entry.clone())
+        self.registry.read().unwrap().get(port).cloned()
     }
 
     pub fn remove(&self, port: &u16) {
-        self.registry.remove(port);
+        self.registry.write().unwrap().remove(port);
     }
 
-    /// Retrieves the `NodeInfo` associated with a given node ID.
-    ///
-    /// ### Arguments
-    ///
-    /// * `node_id` - A string slice representing the node ID.
-    ///
-    /// ### Returns
-    ///
-    /// An `Option<NodeInfo>` which is `Some` if a `NodeInfo` with the specified node ID exists
-    /// in the registry, and `None` otherwise.
-    pub fn get_by_node_id(&self, node_id: &str) -> Option<NodeInfo> {
-        self.registry
+    /// Removes every port registered by a session in one step and returns those ports.
+    pub fn remove_session(&self, session_id: u64) -> Vec<u16> {
+        let mut registry = self.registry.write().unwrap();
+        let ports: Vec<u16> = registry
             .iter()
-            .find(|kv| kv.value().node_id == node_id)
-            .map(|kv| kv.value().clone())
+            .filter(|(_, info)| info.session_id == session_id)
+            .map(|(port, _)| *port)
+            .collect();
+        for port in &ports {
+            registry.remove(port);
+        }
+        ports
     }
 }
@@ -0,0 +1,576 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::watch;
+use v_distributed_tunnel_v1::common::helper::config::write_atomic;
+
+//How often usage is written to disk and quota periods are rolled over
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+//On disk it looks like this, one [[node]] table per node that has limits or traffic:
+// [[node]]
+// node_id = "laptop_1"
+// daily_quota = 1073741824
+// monthly_quota = 0
+// day = "2026-10-19"
+// day_bytes = 52428800
+// month = "2026-10"
+// month_bytes = 734003200
+// [node.bandwidth]
+// ingress_bps = 1048576
+// egress_bps = 1048576
+// [node.services.web]
+// ingress_bps = 0
+// egress_bps = 262144
+
+/// Which way relayed bytes go: ingress from the public side to the node, egress from the
+/// node back to the public side.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+/// Bandwidth limits in bytes per second, 0 means unlimited.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bandwidth {
+    #[serde(default)]
+    pub ingress_bps: u64,
+    #[serde(default)]
+    pub egress_bps: u64,
+}
+
+impl Bandwidth {
+    pub fn is_unlimited(&self) -> bool {
+        self.ingress_bps == 0 && self.egress_bps == 0
+    }
+}
+
+/// Limits and usage of one node. Quotas count both directions, 0 means no quota.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TrafficRecord {
+    pub node_id: String,
+    #[serde(default, skip_serializing_if = "Bandwidth::is_unlimited")]
+    pub bandwidth: Bandwidth,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub services: BTreeMap<String, Bandwidth>,
+    #[serde(default)]
+    pub daily_quota: u64,
+    #[serde(default)]
+    pub monthly_quota: u64,
+    #[serde(default)]
+    pub day: String, //the day `day_bytes` is counted for, e.g. "2026-10-19"
+    #[serde(default)]
+    pub day_bytes: u64,
+    #[serde(default)]
+    pub month: String, //e.g. "2026-10"
+    #[serde(default)]
+    pub month_bytes: u64,
+}
+
+impl TrafficRecord {
+    //Start counting from zero when a new day or month began since the last bytes
+    fn roll_over(&mut self, now: OffsetDateTime) {
+        let day = now.date().to_string();
+        let month = format!("{}-{:02}", now.year(), now.month() as u8);
+        if self.day != day {
+            self.day = day;
+            self.day_bytes = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+
+    fn quota_state(&self) -> QuotaState {
+        if self.daily_quota > 0 && self.day_bytes >= self.daily_quota {
+            QuotaState::Exhausted {
+                period: "daily",
+                limit: self.daily_quota,
+            }
+        } else if self.monthly_quota > 0 && self.month_bytes >= self.monthly_quota {
+            QuotaState::Exhausted {
+                period: "monthly",
+                limit: self.monthly_quota,
+            }
+        } else {
+            QuotaState::Available
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct TrafficFile {
+    #[serde(default)]
+    node: Vec<TrafficRecord>,
+}
+
+/// Whether a node may still move bytes. Sent to the client as is on the control stream:
+/// `QUOTA ok` or `QUOTA exhausted <daily|monthly> <limit in bytes>`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum QuotaState {
+    Available,
+    Exhausted { period: &'static str, limit: u64 },
+}
+
+impl fmt::Display for QuotaState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaState::Available => write!(f, "QUOTA ok"),
+            QuotaState::Exhausted { period, limit } => {
+                write!(f, "QUOTA exhausted {} {}", period, limit)
+            }
+        }
+    }
+}
+
+//Token bucket holding up to one second of traffic. Going into debt is allowed, the caller
+//then sleeps it off, so a big chunk is never stuck waiting for a bucket it doesn't fit in.
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>, //tokens, last refill
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Option<Self> {
+        (bytes_per_sec > 0).then(|| Self {
+            rate: bytes_per_sec as f64,
+            state: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        })
+    }
+
+    //Take n bytes worth of tokens, returns how long to wait before sending them
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+//The buckets of one node or one service, shared by all of its connections
+#[derive(Default)]
+struct Shaper {
+    ingress: Option<TokenBucket>,
+    egress: Option<TokenBucket>,
+}
+
+impl Shaper {
+    fn new(bandwidth: Bandwidth) -> Arc<Self> {
+        Arc::new(Self {
+            ingress: TokenBucket::new(bandwidth.ingress_bps),
+            egress: TokenBucket::new(bandwidth.egress_bps),
+        })
+    }
+
+    fn take(&self, direction: Direction, n: usize) -> Duration {
+        let bucket = match direction {
+            Direction::Ingress => &self.ingress,
+            Direction::Egress => &self.egress,
+        };
+        bucket
+            .as_ref()
+            .map(|bucket| bucket.take(n))
+            .unwrap_or_default()
+    }
+}
+
+struct NodeTraffic {
+    record: TrafficRecord,
+    shaper: Arc<Shaper>,
+    services: HashMap<String, Arc<Shaper>>,
+    state: watch::Sender<QuotaState>,
+}
+
+impl NodeTraffic {
+    fn new(record: TrafficRecord) -> Self {
+        let state = record.quota_state();
+        let mut node = Self {
+            record,
+            shaper: Arc::default(),
+            services: HashMap::new(),
+            state: watch::Sender::new(state),
+        };
+        node.rebuild_shapers();
+        node
+    }
+
+    fn rebuild_shapers(&mut self) {
+        self.shaper = Shaper::new(self.record.bandwidth);
+        self.services = self
+            .record
+            .services
+            .iter()
+            .map(|(service, bandwidth)| (service.clone(), Shaper::new(*bandwidth)))
+            .collect();
+    }
+
+    fn refresh_state(&mut self, now: OffsetDateTime) {
+        self.record.roll_over(now);
+        let state = self.record.quota_state();
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+}
+
+/// Bandwidth limits, traffic quotas and usage of every node.
+///
+/// Like the nodes themselves (see `NodeStore`), their traffic record is written to a TOML file
+/// (`NODE_TRAFFIC_FILE`) so a server restart doesn't hand a node a fresh monthly quota. Limits
+/// are saved right away, usage every `FLUSH_INTERVAL` by `flush_periodically`.
+pub struct TrafficStore {
+    path: PathBuf,
+    nodes: DashMap<String, NodeTraffic>,
+    dirty: AtomicBool,
+}
+
+impl TrafficStore {
+    /// Load records from `path`. A missing file just means no node has moved a byte yet.
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let nodes = DashMap::new();
+        if path.exists() {
+            let data = fs::read_to_string(&path)?;
+            let file: TrafficFile = toml::from_str(&data)?;
+            for record in file.node {
+                nodes.insert(record.node_id.clone(), NodeTraffic::new(record));
+            }
+        }
+        Ok(Self {
+            path,
+            nodes,
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        self.dirty.store(false, Ordering::Relaxed);
+        let mut node: Vec<TrafficRecord> = self
+            .nodes
+            .iter()
+            .map(|entry| entry.record.clone())
+            .collect();
+        node.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        let toml = toml::to_string(&TrafficFile { node }).map_err(std::io::Error::other)?;
+        write_atomic(&self.path, toml.as_bytes()).inspect_err(|_| {
+            //try again on the next flush
+            self.dirty.store(true, Ordering::Relaxed);
+        })
+    }
+
+    /// Save usage and roll quota periods over every `FLUSH_INTERVAL`, so a node whose daily
+    /// quota ran out hears it is back at midnight without having to try first.
+    pub async fn flush_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = OffsetDateTime::now_utc();
+            for mut entry in self.nodes.iter_mut() {
+                entry.refresh_state(now);
+            }
+            if self.dirty.load(Ordering::Relaxed)
+                && let Err(e) = self.save()
+            {
+                eprintln!("Failed to save traffic usage: {}", e);
+            }
+        }
+    }
+
+    fn with_node<T>(&self, node_id: &str, f: impl FnOnce(&mut NodeTraffic) -> T) -> T {
+        let mut entry = self.nodes.entry(node_id.to_string()).or_insert_with(|| {
+            NodeTraffic::new(TrafficRecord {
+                node_id: node_id.to_string(),
+                ..Default::default()
+            })
+        });
+        f(&mut entry)
+    }
+
+    /// Meter for one relayed connection of `service` on `node_id`.
+    pub fn meter(self: &Arc<Self>, node_id: &str, service: &str) -> TrafficMeter {
+        let (node, service) = self.with_node(node_id, |node| {
+            (node.shaper.clone(), node.services.get(service).cloned())
+        });
+        TrafficMeter {
+            store: self.clone(),
+            node_id: node_id.to_string(),
+            node,
+            service,
+        }
+    }
+
+    /// Current quota state of a node, checked before a stream to it is opened.
+    pub fn quota_state(&self, node_id: &str) -> QuotaState {
+        match self.nodes.get_mut(node_id) {
+            Some(mut node) => {
+                node.refresh_state(OffsetDateTime::now_utc());
+                *node.state.borrow()
+            }
+            None => QuotaState::Available,
+        }
+    }
+
+    /// Follow the quota state of a node, the session forwards every change to the client.
+    pub fn subscribe(&self, node_id: &str) -> watch::Receiver<QuotaState> {
+        self.with_node(node_id, |node| node.state.subscribe())
+    }
+
+    //Count n relayed bytes, false once the node is out of quota
+    fn consume(&self, node_id: &str, n: usize) -> bool {
+        let now = OffsetDateTime::now_utc();
+        self.with_node(node_id, |node| {
+            node.refresh_state(now);
+            if *node.state.borrow() != QuotaState::Available {
+                return false;
+            }
+            node.record.day_bytes += n as u64;
+            node.record.month_bytes += n as u64;
+            node.refresh_state(now);
+            self.dirty.store(true, Ordering::Relaxed);
+            true
+        })
+    }
+
+    /// Set the bandwidth of a node, or of one of its services. Connections that are already
+    /// open keep the limits they started with.
+    pub fn set_bandwidth(
+        &self,
+        node_id: &str,
+        service: Option<&str>,
+        bandwidth: Bandwidth,
+    ) -> std::io::Result<()> {
+        self.with_node(node_id, |node| {
+            match service {
+                Some(service) if bandwidth.is_unlimited() => {
+                    node.record.services.remove(service);
+                }
+                Some(service) => {
+                    node.record.services.insert(service.to_string(), bandwidth);
+                }
+                None => node.record.bandwidth = bandwidth,
+            }
+            node.rebuild_shapers();
+        });
+        self.save()
+    }
+
+    pub fn set_quota(&self, node_id: &str, daily: u64, monthly: u64) -> std::io::Result<()> {
+        self.with_node(node_id, |node| {
+            node.record.daily_quota = daily;
+            node.record.monthly_quota = monthly;
+            node.refresh_state(OffsetDateTime::now_utc());
+        });
+        self.save()
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<TrafficRecord> {
+        self.nodes.get_mut(node_id).map(|mut node| {
+            node.record.roll_over(OffsetDateTime::now_utc());
+            node.record.clone()
+        })
+    }
+
+    /// Forget everything about a node, e.g. when the node itself is deleted.
+    pub fn remove_node(&self, node_id: &str) -> std::io::Result<()> {
+        if self.nodes.remove(node_id).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// Shapes and counts the bytes of one relayed connection against its node's and its
+/// service's limits.
+#[derive(Clone)]
+pub struct TrafficMeter {
+    store: Arc<TrafficStore>,
+    node_id: String,
+    node: Arc<Shaper>,
+    service: Option<Arc<Shaper>>,
+}
+
+impl TrafficMeter {
+    /// Call before relaying n bytes: waits as long as the bandwidth limits ask for, and fails
+    /// once the node's quota is used up, which ends the relay.
+    pub async fn pass(&self, direction: Direction, n: usize) -> std::io::Result<()> {
+        if !self.store.consume(&self.node_id, n) {
+            return Err(std::io::Error::other("traffic quota exhausted"));
+        }
+        let mut wait = self.node.take(direction, n);
+        if let Some(service) = &self.service {
+            wait = wait.max(service.take(direction, n));
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a byte size like `1048576`, `512k`, `10M` or `2G` (powers of 1024).
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_lowercase().trim_end_matches(['b', 'i']) {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Human readable byte size, e.g. `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Bandwidth for display, e.g. `512.0 KiB/s`, or `unlimited` for 0.
+pub fn format_rate(bytes_per_sec: u64) -> String {
+    if bytes_per_sec == 0 {
+        "unlimited".to_string()
+    } else {
+        format!("{}/s", format_size(bytes_per_sec))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    fn at(year: i32, month: Month, day: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day)
+            .unwrap()
+            .midnight()
+            .assume_utc()
+    }
+
+    fn fresh_record(daily_quota: u64, monthly_quota: u64) -> TrafficRecord {
+        let mut record = TrafficRecord {
+            node_id: "node1".to_string(),
+            daily_quota,
+            monthly_quota,
+            ..Default::default()
+        };
+        record.roll_over(at(2026, Month::October, 19));
+        record
+    }
+
+    #[test]
+    fn usage_restarts_with_each_day_and_month() {
+        let mut record = fresh_record(0, 0);
+        assert_eq!(record.day, "2026-10-19");
+        assert_eq!(record.month, "2026-10");
+        record.day_bytes = 100;
+        record.month_bytes = 1000;
+
+        //later that same day nothing changes
+        record.roll_over(at(2026, Month::October, 19) + Duration::from_secs(23 * 3600));
+        assert_eq!((record.day_bytes, record.month_bytes), (100, 1000));
+
+        //next day only the daily count starts over
+        record.roll_over(at(2026, Month::October, 20));
+        assert_eq!(record.day, "2026-10-20");
+        assert_eq!((record.day_bytes, record.month_bytes), (0, 1000));
+
+        //new month, both do
+        record.day_bytes = 100;
+        record.roll_over(at(2026, Month::November, 1));
+        assert_eq!(record.month, "2026-11");
+        assert_eq!((record.day_bytes, record.month_bytes), (0, 0));
+
+        //same month a year later is still a new month
+        record.month_bytes = 1000;
+        record.roll_over(at(2027, Month::November, 1));
+        assert_eq!(record.month, "2027-11");
+        assert_eq!(record.month_bytes, 0);
+    }
+
+    #[test]
+    fn quota_runs_out_at_the_limit() {
+        let mut record = fresh_record(100, 1000);
+        assert!(record.quota_state() == QuotaState::Available);
+
+        record.day_bytes = 99;
+        record.month_bytes = 99;
+        assert!(record.quota_state() == QuotaState::Available);
+
+        record.day_bytes = 100;
+        record.month_bytes = 100;
+        assert_eq!(
+            record.quota_state().to_string(),
+            "QUOTA exhausted daily 100"
+        );
+
+        //a new day lifts the daily quota but not a used up monthly one
+        record.month_bytes = 1000;
+        record.roll_over(at(2026, Month::October, 20));
+        assert_eq!(
+            record.quota_state().to_string(),
+            "QUOTA exhausted monthly 1000"
+        );
+        record.roll_over(at(2026, Month::November, 1));
+        assert_eq!(record.quota_state().to_string(), "QUOTA ok");
+
+        //0 is no quota at all
+        let mut unlimited = fresh_record(0, 0);
+        unlimited.day_bytes = u64::MAX;
+        unlimited.month_bytes = u64::MAX;
+        assert!(unlimited.quota_state() == QuotaState::Available);
+    }
+
+    #[test]
+    fn sizes_parse_with_binary_units() {
+        assert_eq!(parse_size("1048576"), Some(1048576));
+        assert_eq!(parse_size(" 512k "), Some(512 * 1024));
+        assert_eq!(parse_size("10M"), Some(10 << 20));
+        assert_eq!(parse_size("10MB"), Some(10 << 20));
+        assert_eq!(parse_size("10MiB"), Some(10 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size("0"), Some(0));
+
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("-1"), None);
+        //too big for a u64
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+}
@@ -0,0 +1,260 @@
+use super::helper::RequestHead;
+use crate::admin::auth_guard::FailureCounter;
+use crate::admin::password_hash::{hash_password, verify_password};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use dashmap::DashSet;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+//Unknown users are checked against this, so they take as long as a wrong password and
+//the timing doesn't tell which user names exist
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("not a real password").unwrap_or_default());
+
+/// Why a request was turned away, and how the edge should answer it.
+pub enum AccessDenied {
+    /// 403, the client's address is not on the allowlist.
+    Forbidden,
+    /// 401 with a `WWW-Authenticate` challenge for this realm.
+    Unauthorized(String),
+    /// 429, the client sent too many wrong passwords, retry after this long.
+    TooManyAttempts(Duration),
+}
+
+/// Who may use a route, checked at the edge before a stream to the node is opened.
+///
+/// - `allow_ip`: when any is set, the client address must be in one of these networks.
+/// - `allow_basic` / `allow_bearer`: when any credential is set, the request must carry one
+///   of them in its `Authorization` header. Basic passwords are stored as Argon2id hashes
+///   (`hash <password>` on the admin port makes one), bearer tokens as blake3 hex digests.
+///
+/// Both checks apply when both are configured. The `Authorization` header is removed before
+/// the request is forwarded, so the local service never sees the edge credentials.
+///
+/// Wrong Basic passwords are counted per client address like failed node logins are (see
+/// `FailureCounter`), a locked out address gets a 429 without any Argon2 run. Not per user:
+/// that would let anyone lock a user out.
+pub struct AccessPolicy {
+    pub realm: String,
+    pub allow_ips: Vec<IpNet>,
+    pub basic: Vec<(String, String)>, //user -> Argon2id PHC string
+    pub bearer: Vec<blake3::Hash>,
+    verified: DashSet<blake3::Hash>, //"user:password" that already passed Argon2, so we only pay for it once
+    failures: FailureCounter<IpAddr>,
+}
+
+impl AccessPolicy {
+    pub fn new(realm: &str) -> Self {
+        Self {
+            realm: realm.to_string(),
+            allow_ips: Vec::new(),
+            basic: Vec::new(),
+            bearer: Vec::new(),
+            verified: DashSet::new(),
+            failures: FailureCounter::new(),
+        }
+    }
+
+    pub fn allow_ip(mut self, network: IpNet) -> Self {
+        self.allow_ips.push(network);
+        self
+    }
+
+    pub fn allow_basic(mut self, user: &str, password_hash: &str) -> Self {
+        self.basic
+            .push((user.to_string(), password_hash.to_string()));
+        self
+    }
+
+    /// `token_hash` is the blake3 hex digest of the token, e.g. `b3sum <<< -n "$TOKEN"`.
+    pub fn allow_bearer(mut self, token_hash: &str) -> anyhow::Result<Self> {
+        self.bearer.push(blake3::Hash::from_hex(token_hash)?);
+        Ok(self)
+    }
+
+    pub fn has_credentials(&self) -> bool {
+        !self.basic.is_empty() || !self.bearer.is_empty()
+    }
+
+    pub async fn check(
+        &self,
+        client_ip: IpAddr,
+        request: &RequestHead,
+    ) -> Result<(), AccessDenied> {
+        //an IPv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+        let client_ip = client_ip.to_canonical();
+        if !self.allow_ips.is_empty() && !self.allow_ips.iter().any(|n| n.contains(&client_ip)) {
+            return Err(AccessDenied::Forbidden);
+        }
+        if !self.has_credentials() {
+            return Ok(());
+        }
+
+        let authorized = match request
+            .header("authorization")
+            .and_then(|value| value.split_once(' '))
+        {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                //blake3::Hash compares in constant time
+                let presented = blake3::hash(token.trim().as_bytes());
+                self.bearer.contains(&presented)
+            }
+            Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => {
+                if let Some(remaining) = self.failures.lockout(&client_ip) {
+                    return Err(AccessDenied::TooManyAttempts(remaining));
+                }
+                let ok = self.check_basic(encoded.trim()).await;
+                if !ok {
+                    self.failures.failed(client_ip);
+                }
+                ok
+            }
+            _ => false,
+        };
+        if authorized {
+            Ok(())
+        } else {
+            Err(AccessDenied::Unauthorized(self.realm.clone()))
+        }
+    }
+
+    async fn check_basic(&self, encoded: &str) -> bool {
+        let Ok(decoded) = STANDARD.decode(encoded) else {
+            return false;
+        };
+        let key = blake3::hash(&decoded);
+        if self.verified.contains(&key) {
+            return true;
+        }
+        let Some((user, password)) = std::str::from_utf8(&decoded)
+            .ok()
+            .and_then(|credentials| credentials.split_once(':'))
+        else {
+            return false;
+        };
+        let hashes: Vec<String> = self
+            .basic
+            .iter()
+            .filter(|(name, _)| name == user)
+            .map(|(_, hash)| hash.clone())
+            .collect();
+        //Argon2 takes a good while on purpose, keep it off the runtime threads
+        let password = password.to_string();
+        let ok = tokio::task::spawn_blocking(move || {
+            if hashes.is_empty() {
+                verify_password(&password, &DUMMY_HASH);
+                return false;
+            }
+            hashes.iter().any(|hash| verify_password(&password, hash))
+        })
+        .await
+        .unwrap_or(false);
+        if ok {
+            self.verified.insert(key);
+        }
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<String>) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: authorization
+                .map(|value| vec![("authorization".to_string(), value)])
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    fn basic(credentials: &str) -> Option<String> {
+        Some(format!("Basic {}", STANDARD.encode(credentials)))
+    }
+
+    #[tokio::test]
+    async fn allowlist_is_checked_first() {
+        let policy = AccessPolicy::new("dev").allow_ip("10.0.0.0/8".parse().unwrap());
+        let inside: IpAddr = "10.1.2.3".parse().unwrap();
+        let mapped: IpAddr = "::ffff:10.1.2.3".parse().unwrap();
+        let outside: IpAddr = "192.168.1.1".parse().unwrap();
+
+        assert!(policy.check(inside, &request(None)).await.is_ok());
+        assert!(policy.check(mapped, &request(None)).await.is_ok());
+        assert!(matches!(
+            policy.check(outside, &request(None)).await,
+            Err(AccessDenied::Forbidden)
+        ));
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_are_compared_by_digest() {
+        let digest = blake3::hash(b"s3cret").to_hex();
+        let policy = AccessPolicy::new("dev").allow_bearer(&digest).unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert!(
+            policy
+                .check(ip, &request(Some("Bearer s3cret".into())))
+                .await
+                .is_ok()
+        );
+        assert!(
+            policy
+                .check(ip, &request(Some("bearer  s3cret ".into())))
+                .await
+                .is_ok()
+        );
+        for authorization in [None, Some("Bearer wrong".into()), basic("s3cret:s3cret")] {
+            assert!(matches!(
+                policy.check(ip, &request(authorization)).await,
+                Err(AccessDenied::Unauthorized(realm)) if realm == "dev"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_basic_passwords_lock_the_address_out() {
+        let policy =
+            AccessPolicy::new("dev").allow_basic("alice", &hash_password("letmein").unwrap());
+        let attacker: IpAddr = "203.0.113.7".parse().unwrap();
+        let alice: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert!(
+            policy
+                .check(alice, &request(basic("alice:letmein")))
+                .await
+                .is_ok()
+        );
+        //garbage, an unknown user and a wrong password all count
+        for authorization in [
+            Some("Basic !!!".to_string()),
+            basic("mallory:letmein"),
+            basic("alice:guess"),
+            basic("alice:guess2"),
+        ] {
+            assert!(matches!(
+                policy.check(attacker, &request(authorization)).await,
+                Err(AccessDenied::Unauthorized(_))
+            ));
+        }
+        //locked out now, even with the right password
+        assert!(matches!(
+            policy.check(attacker, &request(basic("alice:letmein"))).await,
+            Err(AccessDenied::TooManyAttempts(remaining)) if remaining > Duration::ZERO
+        ));
+        //alice herself is not
+        assert!(
+            policy
+                .check(alice, &request(basic("alice:letmein")))
+                .await
+                .is_ok()
+        );
+    }
+}
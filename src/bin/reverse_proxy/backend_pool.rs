@@ -0,0 +1,287 @@
+use super::helper::RequestHead;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How a pool picks one of its live backends for a new connection.
+#[derive(Clone)]
+pub enum LbStrategy {
+    /// Weighted round-robin.
+    RoundRobin,
+    /// The backend with the fewest open connections relative to its weight.
+    LeastConnections,
+    /// The same client IP keeps landing on the same backend while it stays live.
+    HashClientIp,
+    /// Like `HashClientIp`, keyed on a header value (e.g. a tenant or session header).
+    /// Requests without the header are hashed on the client IP.
+    HashHeader(String),
+}
+
+/// One member of a pool: a `node:service` backend and its weight.
+pub struct Backend {
+    pub addr: String,
+    pub weight: u32,
+    active: AtomicUsize, //connections currently relayed to this backend
+}
+
+impl Backend {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Count a connection against this backend until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> ActiveConnection {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    //part of the backend that is a node id, "laptop_1:web" -> "laptop_1"
+    pub fn node_id(&self) -> &str {
+        self.addr
+            .split_once(':')
+            .map_or(self.addr.as_str(), |(node, _)| node)
+    }
+
+    //and the service part, "laptop_1:web" -> "web"
+    pub fn service(&self) -> &str {
+        self.addr
+            .split_once(':')
+            .map_or("default", |(_, service)| service)
+    }
+}
+
+/// Keeps a connection counted on its backend (for `LeastConnections`) while it lives.
+pub struct ActiveConnection(Arc<Backend>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Several backends serving the same thing, e.g. redundant client instances of one service.
+///
+/// A route targets a pool with the backend `@<pool name>`. Only backends whose node has a
+/// live session are considered, so a node that goes away simply stops getting traffic.
+pub struct BackendPool {
+    backends: Vec<Arc<Backend>>,
+    strategy: LbStrategy,
+    next: AtomicUsize, //round-robin position
+}
+
+impl BackendPool {
+    pub fn new(strategy: LbStrategy) -> Self {
+        Self {
+            backends: Vec::new(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a `node:service` backend. A weight of 0 is treated as 1.
+    pub fn with_backend(mut self, addr: &str, weight: u32) -> Self {
+        self.backends.push(Arc::new(Backend {
+            addr: addr.to_string(),
+            weight: weight.max(1),
+            active: AtomicUsize::new(0),
+        }));
+        self
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Pick a backend among those `is_live` accepts, or `None` if none of them is.
+    pub fn pick(
+        &self,
+        client_ip: IpAddr,
+        request: &RequestHead,
+        is_live: impl Fn(&Backend) -> bool,
+    ) -> Option<Arc<Backend>> {
+        let live: Vec<&Arc<Backend>> = self.backends.iter().filter(|b| is_live(b)).collect();
+        if live.is_empty() {
+            return None;
+        }
+
+        let picked = match &self.strategy {
+            LbStrategy::RoundRobin => {
+                let total: usize = live.iter().map(|b| b.weight as usize).sum();
+                let mut slot = self.next.fetch_add(1, Ordering::Relaxed) % total;
+                live.iter()
+                    .find(|b| {
+                        if slot < b.weight as usize {
+                            return true;
+                        }
+                        slot -= b.weight as usize;
+                        false
+                    })
+                    .copied()
+            }
+            //active/weight compared without division: a.active * b.weight vs b.active * a.weight
+            LbStrategy::LeastConnections => live.iter().copied().min_by(|a, b| {
+                (a.active() as u64 * b.weight as u64).cmp(&(b.active() as u64 * a.weight as u64))
+            }),
+            LbStrategy::HashClientIp => rendezvous(&live, client_ip.to_string().as_bytes()),
+            LbStrategy::HashHeader(name) => match request.header(&name.to_ascii_lowercase()) {
+                Some(value) => rendezvous(&live, value.as_bytes()),
+                None => rendezvous(&live, client_ip.to_string().as_bytes()),
+            },
+        };
+        picked.cloned()
+    }
+}
+
+//Weighted rendezvous hashing: each backend scores the key, the best score wins.
+//When a backend goes away only the keys that were on it move, everybody else stays put.
+fn rendezvous<'a>(live: &[&'a Arc<Backend>], key: &[u8]) -> Option<&'a Arc<Backend>> {
+    live.iter()
+        .map(|backend| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(key);
+            hasher.update(b"/");
+            hasher.update(backend.addr.as_bytes());
+            let hash = hasher.finalize();
+            let bytes: [u8; 8] = hash.as_bytes()[..8].try_into().unwrap();
+            //uniform in (0, 1), then score = -weight / ln(u)
+            let unit = ((u64::from_be_bytes(bytes) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            (backend, -(backend.weight as f64) / unit.ln())
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(backend, _)| *backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    //how many of `picks` went to each backend
+    fn tally(picks: impl Iterator<Item = Arc<Backend>>) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for backend in picks {
+            *counts.entry(backend.addr.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn round_robin_follows_the_weights() {
+        let pool = BackendPool::new(LbStrategy::RoundRobin)
+            .with_backend("a:web", 3)
+            .with_backend("b:web", 1)
+            .with_backend("c:web", 0);
+        let head = request(&[]);
+        let counts = tally((0..50).filter_map(|_| pool.pick(ip(1), &head, |_| true)));
+        assert_eq!(counts["a:web"], 30);
+        assert_eq!(counts["b:web"], 10);
+        assert_eq!(counts["c:web"], 10); //weight 0 counts as 1
+
+        //a backend that is not live is skipped, the others split its share
+        let counts = tally((0..40).filter_map(|_| pool.pick(ip(1), &head, |b| b.addr != "a:web")));
+        assert_eq!(counts.get("a:web"), None);
+        assert_eq!(counts["b:web"], 20);
+        assert_eq!(counts["c:web"], 20);
+
+        assert!(pool.pick(ip(1), &head, |_| false).is_none());
+    }
+
+    #[test]
+    fn least_connections_weighs_open_connections() {
+        let pool = BackendPool::new(LbStrategy::LeastConnections)
+            .with_backend("a:web", 2)
+            .with_backend("b:web", 1);
+        let head = request(&[]);
+        //keep every connection open, a should end up with twice as many as b
+        let open: Vec<ActiveConnection> = (0..9)
+            .map(|_| pool.pick(ip(1), &head, |_| true).unwrap().track())
+            .collect();
+        assert_eq!(pool.backends()[0].active(), 6);
+        assert_eq!(pool.backends()[1].active(), 3);
+
+        //once they close, a busy a (1 of weight 2) still loses to an idle b
+        drop(open);
+        assert_eq!(pool.backends()[0].active(), 0);
+        let _held = pool.backends()[0].track();
+        assert_eq!(pool.pick(ip(1), &head, |_| true).unwrap().addr, "b:web");
+    }
+
+    #[test]
+    fn hashing_is_sticky_and_only_moves_keys_of_a_gone_backend() {
+        let pool = BackendPool::new(LbStrategy::HashClientIp)
+            .with_backend("a:web", 1)
+            .with_backend("b:web", 1)
+            .with_backend("c:web", 2);
+        let head = request(&[]);
+        let all: Vec<Arc<Backend>> = (0..4000)
+            .map(|n| pool.pick(ip(n), &head, |_| true).unwrap())
+            .collect();
+
+        //same client, same backend
+        for n in 0..100 {
+            assert_eq!(
+                pool.pick(ip(n), &head, |_| true).unwrap().addr,
+                all[n as usize].addr
+            );
+        }
+
+        //roughly 1:1:2, with plenty of room for the hash
+        let counts = tally(all.iter().cloned());
+        assert!((800..1200).contains(&counts["a:web"]), "{counts:?}");
+        assert!((800..1200).contains(&counts["b:web"]), "{counts:?}");
+        assert!((1700..2300).contains(&counts["c:web"]), "{counts:?}");
+
+        //taking b away moves b's clients and nobody else
+        for (n, before) in all.iter().enumerate() {
+            let after = pool
+                .pick(ip(n as u32), &head, |b| b.addr != "b:web")
+                .unwrap();
+            if before.addr != "b:web" {
+                assert_eq!(after.addr, before.addr);
+            }
+        }
+    }
+
+    #[test]
+    fn header_hashing_falls_back_to_the_client_ip() {
+        let pool = BackendPool::new(LbStrategy::HashHeader("X-Tenant".to_string()))
+            .with_backend("a:web", 1)
+            .with_backend("b:web", 1);
+        let tenant = request(&[("x-tenant", "acme")]);
+        let first = pool.pick(ip(1), &tenant, |_| true).unwrap();
+        //the tenant decides, not where the request comes from
+        for n in 2..50 {
+            assert_eq!(
+                pool.pick(ip(n), &tenant, |_| true).unwrap().addr,
+                first.addr
+            );
+        }
+
+        let plain = request(&[]);
+        let by_ip = BackendPool::new(LbStrategy::HashClientIp)
+            .with_backend("a:web", 1)
+            .with_backend("b:web", 1);
+        for n in 0..50 {
+            assert_eq!(
+                pool.pick(ip(n), &plain, |_| true).unwrap().addr,
+                by_ip.pick(ip(n), &plain, |_| true).unwrap().addr
+            );
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use super::helper::{RequestHead, normalize_host};
+use dashmap::DashMap;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_HTML: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>{{message}}</p>
+</body>
+</html>
+";
+
+const DEFAULT_JSON: &str =
+    "{\"status\": {{status}}, \"error\": \"{{reason}}\", \"message\": \"{{message}}\"}\n";
+
+/// Pages the edge answers with when it can't relay a request: no route, node offline, ...
+///
+/// Templates are loaded from a directory: `<host>.html` and `<host>.json` for one host,
+/// `default.html` and `default.json` for every other one. Missing files fall back to the
+/// built-in pages. The client gets JSON when its `Accept` header asks for it and HTML
+/// otherwise.
+///
+/// Templates can use `{{status}}`, `{{reason}}`, `{{message}}` and `{{host}}`. Values are
+/// escaped for the page's format, since the host comes straight from the request.
+pub struct ErrorPages {
+    html: DashMap<String, String>, //host (or "default") -> template
+    json: DashMap<String, String>,
+}
+
+impl ErrorPages {
+    /// Only the built-in pages.
+    pub fn new() -> Self {
+        Self {
+            html: DashMap::new(),
+            json: DashMap::new(),
+        }
+    }
+
+    /// Load every `*.html` / `*.json` template of `dir`.
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let pages = Self::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let (Some(stem), Some(extension)) = (
+                path.file_stem().and_then(|s| s.to_str()),
+                path.extension().and_then(|s| s.to_str()),
+            ) else {
+                continue;
+            };
+            let host = if stem == "default" {
+                stem.to_string()
+            } else {
+                normalize_host(stem)
+            };
+            match extension {
+                "html" => pages.html.insert(host, fs::read_to_string(&path)?),
+                "json" => pages.json.insert(host, fs::read_to_string(&path)?),
+                _ => continue,
+            };
+        }
+        Ok(pages)
+    }
+
+    /// Build a complete `Connection: close` response for `status`.
+    pub fn render(&self, request: Option<&RequestHead>, status: u16, message: &str) -> Vec<u8> {
+        self.render_with_headers(request, status, message, &[])
+    }
+
+    /// Same as `render`, with extra response headers such as a `WWW-Authenticate` challenge.
+    pub fn render_with_headers(
+        &self,
+        request: Option<&RequestHead>,
+        status: u16,
+        message: &str,
+        headers: &[(&str, String)],
+    ) -> Vec<u8> {
+        let reason = reason_phrase(status);
+        let host = request
+            .and_then(|r| r.host())
+            .map(normalize_host)
+            .unwrap_or_default();
+        let wants_json = request
+            .and_then(|r| r.header("accept"))
+            .is_some_and(|accept| accept.contains("json") && !accept.contains("html"));
+
+        let (templates, default, content_type, escape): (_, _, _, fn(&str) -> String) =
+            if wants_json {
+                (&self.json, DEFAULT_JSON, "application/json", escape_json)
+            } else {
+                (
+                    &self.html,
+                    DEFAULT_HTML,
+                    "text/html; charset=utf-8",
+                    escape_html,
+                )
+            };
+        let template = templates
+            .get(&host)
+            .or_else(|| templates.get("default"))
+            .map(|t| t.value().clone())
+            .unwrap_or_else(|| default.to_string());
+
+        let body = template
+            .replace("{{status}}", &status.to_string())
+            .replace("{{reason}}", &escape(reason))
+            .replace("{{message}}", &escape(message))
+            .replace("{{host}}", &escape(&host));
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+            status,
+            reason,
+            content_type,
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut response = head.into_bytes();
+        response.extend_from_slice(body.as_bytes());
+        response
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
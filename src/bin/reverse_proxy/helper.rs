@@ -1,39 +1,219 @@
+/// Normalises a Host header value so it can be compared against routing rules.
+///
+/// The port is stripped (`Api.Example.com:5123` -> `api.example.com`), as is a trailing
+/// dot, and the name is converted to its lowercase ASCII (IDNA/punycode) form, so
+/// `Bücher.Example` and `xn--bcher-kva.example` match the same rule. IPv6 literals keep
+/// their brackets: `[::1]:8080` -> `[::1]`.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        }
+    };
+    let host = host.trim_end_matches('.');
+    //Names that are not valid IDNA (e.g. IP literals) are still compared case-insensitively
+    idna::domain_to_ascii(host).unwrap_or_else(|_| host.to_ascii_lowercase())
+}
+
+/// Puts a request path in the one form routing rules and access policies are checked against,
+/// so `/%61dmin`, `//admin` and `/x/../admin` can't slip past a rule for `/admin` while the
+/// backend still resolves them to `/admin`.
+///
+/// Percent-encoded unreserved characters are decoded (other escapes only get uppercase hex),
+/// repeated slashes are collapsed and `.`/`..` segments removed, like RFC 3986 does.
+/// Returns `None` for targets that aren't a path (absolute URLs, `*` is kept as is).
+pub fn normalize_path(path: &str) -> Option<String> {
+    if path == "*" {
+        return Some(path.to_string());
+    }
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = match bytes.get(index..index + 3) {
+            Some([b'%', high, low]) => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte as char);
+                index += 3;
+            }
+            Some(byte) => {
+                decoded.push_str(&format!("%{:02X}", byte));
+                index += 3;
+            }
+            None => {
+                //the path came from a &str, so a char starts here
+                let c = path[index..].chars().next()?;
+                decoded.push(c);
+                index += c.len_utf8();
+            }
+        }
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in decoded.split('/').skip(1) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// The parts of an HTTP request head that routing rules can look at.
+///
+/// Header names are lowercased. `path` has the query string cut off and is normalized (see
+/// `normalize_path`), the query itself and the cookies are split into raw (not
+/// percent-decoded) name/value pairs.
+#[derive(Default)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub cookies: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// First value of a header, `name` must be lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_pair(&self.headers, name)
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        find_pair(&self.query, name)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        find_pair(&self.cookies, name)
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.header("host")
+    }
+}
+
+fn find_pair<'a>(pairs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    pairs
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
 //This is the example of http request data
 // GET /path/to/resource HTTP/1.1
 // Host: example.com
 // Accept: */*
 
-/// Extracts the hostname from the HTTP request data.
-///
-/// This function takes a string slice containing HTTP request data and attempts
-/// to extract the hostname from the request. It does this by iterating over the
-/// lines of the HTTP data and checking if each line starts with "Host: ". If
-/// it does, it extracts the hostname from the line and returns it as a `String`.
-/// If no hostname is found, it returns `None`.
-pub fn extract_host(http_data: &str) -> Option<String> {
-    for line in http_data.lines() {
-        //The line we looking for is in form of "Host: <hostname>"
-        if line.to_ascii_lowercase().starts_with("host: ") {
-            return Some(line[6..].to_string());
+/// Parses the request line and headers. Returns `None` if the request line is malformed.
+pub fn parse_request_head(http_data: &str) -> Option<RequestHead> {
+    let mut lines = http_data.lines();
+    //METHOD REQUEST-URI HTTP-VERSION
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_ascii_uppercase();
+    let target = request_line.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut head = RequestHead {
+        method,
+        path: normalize_path(path)?,
+        query: split_pairs(query, '&'),
+        ..Default::default()
+    };
+    for line in lines {
+        if line.is_empty() {
+            break; //end of the head, the body starts here
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim().to_ascii_lowercase();
+            let value = value.trim();
+            if name == "cookie" {
+                head.cookies.extend(split_pairs(value, ';'));
+            }
+            head.headers.push((name, value.to_string()));
         }
     }
-    None
+    Some(head)
 }
 
-/// Extracts the path from the HTTP request data.
-///
-/// This function takes a string slice containing HTTP request data and attempts
-/// to extract the path from the request line. The request line is assumed to be
-/// the first line of the HTTP data, and the path is the second whitespace-separated
-/// token in this line. If successful, it returns the path as a `String`. If the
-/// path cannot be found, it returns `None`.
-pub fn extract_path(http_data: &str) -> Option<String> {
-    let mut lines = http_data.lines();
-    if let Some(first_line) = lines.next() {
-        //HTTP req usually be in form of METHOD REQUEST-URI HTTP-VERSION
-        if let Some(path) = first_line.split_whitespace().nth(1) {
-            return Some(path.to_string());
+//"a=1&b=2" -> [("a", "1"), ("b", "2")], a name without "=" gets an empty value
+fn split_pairs(data: &str, separator: char) -> Vec<(String, String)> {
+    data.split(separator)
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (name.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_normalized() {
+        for (path, normalized) in [
+            ("/", "/"),
+            ("/admin", "/admin"),
+            ("/%61dmin", "/admin"),
+            ("/%7euser/%41", "/~user/A"),
+            ("//admin//users", "/admin/users"),
+            ("/x/../admin", "/admin"),
+            ("/./admin/.", "/admin/"),
+            ("/a/b/..", "/a/"),
+            ("/../../admin", "/admin"),
+            ("/%2e%2E/admin", "/admin"),
+            ("/files/my%20doc", "/files/my%20doc"),
+            ("/a%2fb", "/a%2Fb"),
+            ("/100%", "/100%"),
+            ("/caf%C3%A9/é", "/caf%C3%A9/é"),
+            ("/admin/", "/admin/"),
+        ] {
+            assert_eq!(normalize_path(path).as_deref(), Some(normalized), "{path}");
         }
+        assert_eq!(normalize_path("*").as_deref(), Some("*"));
+        assert_eq!(normalize_path("http://example.com/admin"), None);
+        assert_eq!(normalize_path("admin"), None);
+    }
+
+    #[test]
+    fn request_head_is_parsed() {
+        let head = parse_request_head(
+            "get /x/../api?a=1&b HTTP/1.1\r\nHost: Example.com\r\nCookie: s=2; t=3\r\n\r\nbody",
+        )
+        .unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path, "/api");
+        assert_eq!(head.query_param("a"), Some("1"));
+        assert_eq!(head.query_param("b"), Some(""));
+        assert_eq!(head.host(), Some("Example.com"));
+        assert_eq!(head.cookie("t"), Some("3"));
+        assert!(parse_request_head("GET").is_none());
     }
-    None
 }
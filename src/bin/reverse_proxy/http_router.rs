@@ -0,0 +1,438 @@
+use super::access_policy::AccessDenied;
+use super::error_pages::ErrorPages;
+use super::helper::{RequestHead, parse_request_head};
+use super::rewrite::{Rewrite, split_head};
+use super::routing_table::RoutingTable;
+use crate::forward::server_tunnel_handler::write_stream_header;
+use crate::pool::connection_guard::ConnectionGuard;
+use crate::pool::traffic::{Direction, QuotaState, TrafficMeter, TrafficStore};
+use crate::session::session_registry::SessionRegistry;
+use quinn::{RecvStream, SendStream};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//We never look further than this for the end of the request head
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+//A client trickling in its request head (slowloris) is cut off after this
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+//A node that can't even give us a stream this fast is overloaded (its stream limit is reached)
+const OPEN_STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+//How long the local service may take before the first byte of its response
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Routes HTTP connections by Host/path through the `RoutingTable` to the node that owns the route.
+///
+/// Used by the shared virtual-host listener and by per-node ports published as `http`.
+/// When there is nobody to relay to, the client gets an error page instead of a reset:
+/// 404 no route, 502 node offline, 503 unhealthy or overloaded, 504 backend timeout.
+pub struct HttpRouter {
+    routing_table: Arc<RoutingTable>,
+    sessions: Arc<SessionRegistry>,
+    error_pages: Arc<ErrorPages>,
+    guard: Arc<ConnectionGuard>,
+    traffic: Arc<TrafficStore>,
+}
+
+impl HttpRouter {
+    pub fn new(
+        routing_table: Arc<RoutingTable>,
+        sessions: Arc<SessionRegistry>,
+        error_pages: Arc<ErrorPages>,
+        guard: Arc<ConnectionGuard>,
+        traffic: Arc<TrafficStore>,
+    ) -> Self {
+        Self {
+            routing_table,
+            sessions,
+            error_pages,
+            guard,
+            traffic,
+        }
+    }
+
+    /// Read the request head, pick the backend and relay the whole connection to it.
+    ///
+    /// The stream can be plain TCP or TLS, so instead of peeking we read the head and replay
+    /// it to the node before relaying the rest. Only one request is served per connection:
+    /// the backend is asked for `Connection: close` and the relay ends with its response, so
+    /// a keep-alive client sends its next request on a new connection that is routed and
+    /// checked again.
+    pub async fn proxy<S>(&self, mut stream: S, remote_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let head = match tokio::time::timeout(HEAD_TIMEOUT, read_request_head(&mut stream)).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(e)) => {
+                eprintln!("Failed to read request from {}: {}", remote_addr, e);
+                return;
+            }
+            Err(_) => {
+                eprintln!("{} took too long to send its request", remote_addr);
+                self.respond_error(&mut stream, None, 408, "The request took too long.")
+                    .await;
+                return;
+            }
+        };
+
+        //A head cut off by MAX_HEAD_SIZE or EOF, or one that isn't UTF-8, can't be rewritten,
+        //so it isn't forwarded either
+        let Some((http_data, _)) = split_head(&head) else {
+            eprintln!("Incomplete or malformed request head from {}", remote_addr);
+            self.respond_error(&mut stream, None, 400, "The request could not be parsed.")
+                .await;
+            return;
+        };
+        let request = match parse_request_head(http_data) {
+            Some(request) if request.host().is_some() => request,
+            _ => {
+                eprintln!("Not an HTTP request from {}", remote_addr);
+                self.respond_error(&mut stream, None, 400, "The request could not be parsed.")
+                    .await;
+                return;
+            }
+        };
+
+        let host = request.host().unwrap_or_default();
+        println!(
+            "Method: {}, Host: {:?}, Path: {:?}",
+            request.method, host, request.path
+        );
+
+        //here, we use our routing table as a dictionary to look/map to our wanted backend
+        let route = match self.routing_table.route(&request) {
+            Some(route) => route,
+            None => {
+                eprintln!(
+                    "No backend found for host {:?} and path {:?}",
+                    host, request.path
+                );
+                self.respond_error(
+                    &mut stream,
+                    Some(&request),
+                    404,
+                    "No route for this address.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        //Access policies are enforced here, before anything is opened towards the node
+        let mut rewrite = route.rewrite.clone();
+        if let Some(access) = &route.access {
+            match access.check(remote_addr.ip(), &request).await {
+                Ok(()) => {}
+                Err(AccessDenied::Forbidden) => {
+                    eprintln!("{} is not allowed on {:?}", remote_addr, host);
+                    self.respond_error(&mut stream, Some(&request), 403, "Access denied.")
+                        .await;
+                    return;
+                }
+                Err(AccessDenied::TooManyAttempts(remaining)) => {
+                    eprintln!(
+                        "{} sent too many wrong passwords for {:?}",
+                        remote_addr, host
+                    );
+                    let response = self.error_pages.render_with_headers(
+                        Some(&request),
+                        429,
+                        "Too many failed attempts, try again later.",
+                        &[("Retry-After", remaining.as_secs().max(1).to_string())],
+                    );
+                    let _ = stream.write_all(&response).await;
+                    let _ = stream.shutdown().await;
+                    return;
+                }
+                Err(AccessDenied::Unauthorized(realm)) => {
+                    eprintln!("Unauthorized request from {} to {:?}", remote_addr, host);
+                    let challenge = format!(
+                        "Basic realm=\"{}\", charset=\"UTF-8\"",
+                        realm.replace('"', "'")
+                    );
+                    let response = self.error_pages.render_with_headers(
+                        Some(&request),
+                        401,
+                        "Authentication required.",
+                        &[("WWW-Authenticate", challenge)],
+                    );
+                    let _ = stream.write_all(&response).await;
+                    let _ = stream.shutdown().await;
+                    return;
+                }
+            }
+            //Keep the edge credentials away from the node
+            if access.has_credentials() {
+                rewrite = rewrite.remove_request_header("Authorization");
+            }
+        }
+
+        //"@<pool>" targets a backend pool, pick one of its members that is connected right now
+        let (backend_id, _active) = match route.backend_addr.strip_prefix('@') {
+            Some(pool_name) => {
+                let picked = self.routing_table.pool(pool_name).and_then(|pool| {
+                    pool.pick(remote_addr.ip(), &request, |backend| {
+                        self.sessions
+                            .get(backend.node_id())
+                            .is_some_and(|session| session.health.is_healthy(backend.service()))
+                    })
+                });
+                match picked {
+                    Some(backend) => (backend.addr.clone(), Some(backend.track())),
+                    None => {
+                        eprintln!("No live backend in pool {}", pool_name);
+                        self.respond_error(
+                            &mut stream,
+                            Some(&request),
+                            503,
+                            "No backend is available right now.",
+                        )
+                        .await;
+                        return;
+                    }
+                }
+            }
+            None => (route.backend_addr.clone(), None),
+        };
+
+        //Backends look like "node:service", where service is a service name or local port
+        let (node_id, service) = backend_id
+            .split_once(':')
+            .unwrap_or((backend_id.as_str(), "default"));
+        //The connection passed the global ip rules on accept, the node may have its own
+        if let Err(rejection) = self.guard.check_node(node_id, remote_addr.ip()) {
+            eprintln!(
+                "{} refused for node {}: {}",
+                remote_addr, node_id, rejection
+            );
+            self.respond_error(&mut stream, Some(&request), 403, "Access denied.")
+                .await;
+            return;
+        }
+        let session = match self.sessions.get(node_id) {
+            Some(session) => session,
+            None => {
+                eprintln!("Node of backend {} is not connected", backend_id);
+                self.respond_error(&mut stream, Some(&request), 502, "The backend is offline.")
+                    .await;
+                return;
+            }
+        };
+        if !session.health.is_healthy(service) {
+            eprintln!("Backend {} is unhealthy", backend_id);
+            self.respond_error(
+                &mut stream,
+                Some(&request),
+                503,
+                "The backend is unavailable right now.",
+            )
+            .await;
+            return;
+        }
+
+        //Every request goes through the rewrite, which asks for `Connection: close`: each one
+        //has to come on a connection of its own, so none gets past the routing and access checks
+        let Some(head) = rewrite.rewrite_request(&head) else {
+            self.respond_error(
+                &mut stream,
+                Some(&request),
+                400,
+                "The request could not be parsed.",
+            )
+            .await;
+            return;
+        };
+
+        if let QuotaState::Exhausted { period, .. } = self.traffic.quota_state(node_id) {
+            eprintln!("Node {} used up its {} traffic quota", node_id, period);
+            self.respond_error(
+                &mut stream,
+                Some(&request),
+                503,
+                "The backend used up its traffic quota.",
+            )
+            .await;
+            return;
+        }
+
+        let (mut send_stream, recv_stream) =
+            match tokio::time::timeout(OPEN_STREAM_TIMEOUT, session.conn.open_bi()).await {
+                Ok(Ok(x)) => x,
+                Ok(Err(e)) => {
+                    eprintln!("Failed to open QUIC stream to node {}: {:?}", node_id, e);
+                    self.respond_error(&mut stream, Some(&request), 502, "The backend is offline.")
+                        .await;
+                    return;
+                }
+                Err(_) => {
+                    eprintln!("Node {} has no free stream, it is overloaded", node_id);
+                    self.respond_error(
+                        &mut stream,
+                        Some(&request),
+                        503,
+                        "The backend is overloaded, try again later.",
+                    )
+                    .await;
+                    return;
+                }
+            };
+
+        //Tell the node which service, then replay what we already consumed from the client
+        let meter = self.traffic.meter(node_id, service);
+        let forwarded = async {
+            write_stream_header(&mut send_stream, service).await?;
+            meter.pass(Direction::Ingress, head.len()).await?;
+            send_stream.write_all(&head).await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = forwarded.await {
+            eprintln!("Failed to forward request to node {}: {:?}", node_id, e);
+            self.respond_error(&mut stream, Some(&request), 502, "The backend is offline.")
+                .await;
+            return;
+        }
+
+        //The node's session waits for this relay before it lets go of its connection
+        session
+            .tracker
+            .track_future(self.relay(stream, &request, &rewrite, send_stream, recv_stream, meter))
+            .await;
+        println!("Closed HTTP tunnel from {} to {}", remote_addr, backend_id);
+    }
+
+    //Like `relay_stream`, but the first byte of the response has to come within
+    //BACKEND_TIMEOUT, a backend that gives up before answering becomes a 502, and the
+    //response head gets the route's extra headers and `Connection: close`.
+    async fn relay<S>(
+        &self,
+        stream: S,
+        request: &RequestHead,
+        rewrite: &Rewrite,
+        send_stream: SendStream,
+        recv_stream: RecvStream,
+        meter: TrafficMeter,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut tcp_reader, mut tcp_writer) = tokio::io::split(stream);
+        let (mut quic_writer, mut quic_reader) = (send_stream, recv_stream);
+
+        let tcp_to_quic = async {
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = tcp_reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                meter.pass(Direction::Ingress, n).await?;
+                quic_writer.write_all(&buf[..n]).await?;
+            }
+            quic_writer.finish()?;
+            Ok::<(), std::io::Error>(())
+        };
+
+        let quic_to_tcp = async {
+            let mut buf = [0u8; 4096];
+            let first = tokio::time::timeout(BACKEND_TIMEOUT, quic_reader.read(&mut buf)).await;
+            let failure = match first {
+                Ok(Ok(Some(n))) if n > 0 => {
+                    let mut response = buf[..n].to_vec();
+                    //collect the whole response head before rewriting it
+                    loop {
+                        if let Some(rewritten) = rewrite.rewrite_response(&response) {
+                            response = rewritten;
+                            break;
+                        }
+                        if response.len() >= MAX_HEAD_SIZE {
+                            break;
+                        }
+                        match quic_reader.read(&mut buf).await? {
+                            Some(n) if n > 0 => response.extend_from_slice(&buf[..n]),
+                            _ => break,
+                        }
+                    }
+                    meter.pass(Direction::Egress, response.len()).await?;
+                    tcp_writer.write_all(&response).await?;
+                    None
+                }
+                Ok(_) => Some((502, "The backend closed the connection without answering.")),
+                Err(_) => Some((504, "The backend did not answer in time.")),
+            };
+            if let Some((status, message)) = failure {
+                tcp_writer
+                    .write_all(&self.error_pages.render(Some(request), status, message))
+                    .await?;
+                tcp_writer.shutdown().await?;
+                //stop the upload too, nobody is going to read it
+                return Err(std::io::Error::other("backend failed"));
+            }
+
+            loop {
+                let n = quic_reader.read(&mut buf).await?;
+                if let Some(n) = n {
+                    if n == 0 {
+                        break;
+                    }
+                    meter.pass(Direction::Egress, n).await?;
+                    tcp_writer.write_all(&buf[..n]).await?;
+                } else {
+                    break;
+                }
+            }
+            tcp_writer.shutdown().await?;
+            Ok::<(), std::io::Error>(())
+        };
+
+        //The response ends the connection (see `proxy`), whatever else the client sends is
+        //never forwarded. A client that is done sending still gets the whole response.
+        tokio::pin!(tcp_to_quic, quic_to_tcp);
+        tokio::select! {
+            _ = &mut quic_to_tcp => {}
+            sent = &mut tcp_to_quic => {
+                if sent.is_ok() {
+                    let _ = quic_to_tcp.await;
+                }
+            }
+        }
+    }
+
+    //Answer the client ourselves when there is nobody to relay to
+    async fn respond_error<S>(
+        &self,
+        stream: &mut S,
+        request: Option<&RequestHead>,
+        status: u16,
+        message: &str,
+    ) where
+        S: AsyncWrite + Unpin,
+    {
+        let response = self.error_pages.render(request, status, message);
+        let _ = stream.write_all(&response).await;
+        let _ = stream.shutdown().await;
+    }
+}
+
+//Read until the blank line ending the request head, or until MAX_HEAD_SIZE / EOF.
+async fn read_request_head<S>(stream: &mut S) -> std::io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while head.len() < MAX_HEAD_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+    Ok(head)
+}
@@ -1,2 +1,10 @@
+pub mod access_policy;
+pub mod backend_pool;
+pub mod error_pages;
 pub mod helper;
+pub mod http_router;
+pub mod rewrite;
+pub mod route_config;
+pub mod route_rule;
 pub mod routing_table;
+pub mod shared_listener;
@@ -0,0 +1,298 @@
+use super::helper::normalize_path;
+
+/// Changes a route makes to the traffic it relays, e.g.
+///
+/// ```ignore
+/// Rewrite::new()
+///     .strip_prefix("/v1")                       // GET /v1/users -> GET /users
+///     .host("localhost:3000")                    // what the local dev server expects
+///     .set_request_header("X-Forwarded-Proto", "https")
+///     .remove_request_header("Cookie")
+///     .add_response_header("Strict-Transport-Security", "max-age=31536000")
+/// ```
+///
+/// Only the head of the first request and response on a connection can be rewritten, so
+/// `rewrite_request` always asks the backend for `Connection: close`: every request then comes
+/// on its own connection and goes through the rewrite (and the route's access checks).
+#[derive(Clone, Default)]
+pub struct Rewrite {
+    pub strip_prefix: Option<String>,
+    pub host: Option<String>,
+    pub set_request_headers: Vec<(String, String)>,
+    pub remove_request_headers: Vec<String>,
+    pub add_response_headers: Vec<(String, String)>,
+}
+
+impl Rewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn strip_prefix(mut self, prefix: &str) -> Self {
+        self.strip_prefix = Some(prefix.to_string());
+        self
+    }
+
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn set_request_header(mut self, name: &str, value: &str) -> Self {
+        self.set_request_headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn remove_request_header(mut self, name: &str) -> Self {
+        self.remove_request_headers.push(name.to_string());
+        self
+    }
+
+    pub fn add_response_header(mut self, name: &str, value: &str) -> Self {
+        self.add_response_headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strip_prefix.is_none()
+            && self.host.is_none()
+            && self.set_request_headers.is_empty()
+            && self.remove_request_headers.is_empty()
+            && self.add_response_headers.is_empty()
+    }
+
+    /// Apply the request side to a raw request head. The path is normalized like the routing
+    /// saw it (see `normalize_path`) and whatever follows the head (the start of the body) is
+    /// kept as is. Returns `None` for a head that isn't complete, isn't UTF-8 or has no path:
+    /// it can't be rewritten, so it must not be forwarded either.
+    pub fn rewrite_request(&self, head: &[u8]) -> Option<Vec<u8>> {
+        let (head_text, body) = split_head(head)?;
+        let mut lines = head_text.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+
+        //METHOD REQUEST-URI HTTP-VERSION
+        let mut parts: Vec<String> = request_line.split(' ').map(str::to_string).collect();
+        //The backend gets the normalized path the request was routed and checked on
+        let target = parts.get_mut(1)?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, format!("?{}", query)),
+            None => (target.as_str(), String::new()),
+        };
+        let mut path = normalize_path(path)?;
+        if let Some(prefix) = &self.strip_prefix
+            && let Some(rest) = path.strip_prefix(prefix.as_str())
+        {
+            path = if rest.starts_with('/') {
+                rest.to_string()
+            } else {
+                format!("/{}", rest)
+            };
+        }
+        *target = path + &query;
+
+        let mut headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .filter(|(name, _)| {
+                !self
+                    .remove_request_headers
+                    .iter()
+                    .any(|removed| removed.eq_ignore_ascii_case(name))
+            })
+            .collect();
+        if let Some(host) = &self.host {
+            set_header(&mut headers, "Host", host);
+        }
+        for (name, value) in &self.set_request_headers {
+            set_header(&mut headers, name, value);
+        }
+        //One request per connection, see the type docs
+        set_header(&mut headers, "Connection", "close");
+
+        let mut rewritten = parts.join(" ");
+        rewritten.push_str("\r\n");
+        for (name, value) in &headers {
+            rewritten.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        rewritten.push_str("\r\n");
+        let mut rewritten = rewritten.into_bytes();
+        rewritten.extend_from_slice(body);
+        Some(rewritten)
+    }
+
+    /// Add the response headers to a raw response head, right before the blank line, and
+    /// tell the client the connection ends with this response (see the type docs).
+    /// Returns `None` while the head isn't complete yet.
+    pub fn rewrite_response(&self, head: &[u8]) -> Option<Vec<u8>> {
+        let end = find_head_end(head)?;
+        let mut rewritten = Vec::with_capacity(head.len() + 64);
+        for (index, line) in head[..end].split(|&b| b == b'\n').enumerate() {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            //the status line first, then every header but the backend's own connection ones
+            let name = line
+                .split(|&b| b == b':')
+                .next()
+                .unwrap_or_default()
+                .trim_ascii();
+            if index > 0
+                && (name.eq_ignore_ascii_case(b"connection")
+                    || name.eq_ignore_ascii_case(b"keep-alive"))
+            {
+                continue;
+            }
+            rewritten.extend_from_slice(line);
+            rewritten.extend_from_slice(b"\r\n");
+        }
+        for (name, value) in &self.add_response_headers {
+            rewritten.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        rewritten.extend_from_slice(b"Connection: close\r\n\r\n");
+        rewritten.extend_from_slice(&head[end + 4..]);
+        Some(rewritten)
+    }
+}
+
+//Replace every header with this name by one header, or add it
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+    headers.push((name.to_string(), value.to_string()));
+}
+
+//Index of the "\r\n\r\n" ending a head
+pub fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// The request head as text (without the blank line) and whatever comes after it.
+/// `None` unless the head is complete, valid UTF-8 and only uses CRLF line endings: anything
+/// else could be read differently by the backend than by our routing and rewrite.
+pub fn split_head(data: &[u8]) -> Option<(&str, &[u8])> {
+    let end = find_head_end(data)?;
+    let head = std::str::from_utf8(&data[..end]).ok()?;
+    let bare_newline = head
+        .match_indices('\n')
+        .any(|(index, _)| index == 0 || head.as_bytes()[index - 1] != b'\r');
+    if bare_newline {
+        return None;
+    }
+    Some((head, &data[end + 4..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewritten(rewrite: &Rewrite, head: &[u8]) -> String {
+        String::from_utf8(rewrite.rewrite_request(head).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn every_request_asks_for_connection_close() {
+        let head =
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\nconnection: upgrade\r\n\r\n";
+        assert_eq!(
+            rewritten(&Rewrite::new(), head),
+            "GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn request_headers_are_rewritten() {
+        let rewrite = Rewrite::new()
+            .host("localhost:3000")
+            .set_request_header("X-Forwarded-Proto", "https")
+            .remove_request_header("authorization");
+        let head = b"GET / HTTP/1.1\r\nhost: api.example.com\r\nAuthorization: Basic eDp5\r\n\
+            x-forwarded-proto: http\r\nAccept: */*\r\n\r\n";
+        assert_eq!(
+            rewritten(&rewrite, head),
+            "GET / HTTP/1.1\r\nAccept: */*\r\nHost: localhost:3000\r\n\
+            X-Forwarded-Proto: https\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn prefix_is_stripped_and_body_kept() {
+        let rewrite = Rewrite::new().strip_prefix("/v2");
+        assert_eq!(
+            rewritten(
+                &rewrite,
+                b"POST /v2/users?x=1 HTTP/1.1\r\nHost: a\r\n\r\n{\"a\":1}"
+            ),
+            "POST /users?x=1 HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n{\"a\":1}"
+        );
+        assert!(rewritten(&rewrite, b"GET /v2 HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("GET / "));
+        //only a leading prefix is stripped
+        assert!(
+            rewritten(&rewrite, b"GET /x/v2 HTTP/1.1\r\nHost: a\r\n\r\n").starts_with("GET /x/v2 ")
+        );
+    }
+
+    #[test]
+    fn backend_gets_the_normalized_path() {
+        let rewrite = Rewrite::new().strip_prefix("/v2");
+        assert!(
+            rewritten(
+                &rewrite,
+                b"GET //v2/x/../%61dmin?a=%2e HTTP/1.1\r\nHost: a\r\n\r\n"
+            )
+            .starts_with("GET /admin?a=%2e HTTP/1.1\r\n")
+        );
+        assert!(
+            Rewrite::new()
+                .rewrite_request(b"GET http://a/admin HTTP/1.1\r\nHost: a\r\n\r\n")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn heads_that_cannot_be_rewritten_are_refused() {
+        let rewrite = Rewrite::new();
+        //not UTF-8
+        assert!(
+            rewrite
+                .rewrite_request(b"GET / HTTP/1.1\r\nHost: a\r\nX-Bad: \xff\r\n\r\n")
+                .is_none()
+        );
+        //incomplete
+        assert!(
+            rewrite
+                .rewrite_request(b"GET / HTTP/1.1\r\nHost: a\r\n")
+                .is_none()
+        );
+        //bare LF hiding a header from the CRLF split
+        assert!(
+            rewrite
+                .rewrite_request(b"GET / HTTP/1.1\r\nHost: a\nAuthorization: x\r\n\r\n")
+                .is_none()
+        );
+        //a binary body is fine, only the head has to be text
+        assert!(
+            rewrite
+                .rewrite_request(b"POST / HTTP/1.1\r\nHost: a\r\n\r\n\xff\xfe")
+                .is_some_and(|head| head.ends_with(b"\r\n\r\n\xff\xfe"))
+        );
+    }
+
+    #[test]
+    fn response_gets_extra_headers_and_connection_close() {
+        let rewrite = Rewrite::new().add_response_header("X-Frame-Options", "DENY");
+        assert_eq!(
+            rewrite.rewrite_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2"),
+            None
+        );
+        let response = rewrite
+            .rewrite_response(
+                b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\
+                Content-Length: 2\r\n\r\nok",
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Frame-Options: DENY\r\n\
+            Connection: close\r\n\r\nok"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use super::access_policy::AccessPolicy;
+use super::backend_pool::{BackendPool, LbStrategy};
+use super::rewrite::Rewrite;
+use super::route_rule::{PathMatch, Predicate, RouteRule, ValueMatch};
+use super::routing_table::RoutingTable;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+//The routes file (ROUTES_FILE) looks like this, see routes.example.toml for every option:
+// default_backend = "laptop_1:web"
+//
+// [[route]]
+// host = "api.example.com"   # "*.dev.example.com" for a wildcard, "~<regex>" for a pattern
+// path = "/v1/"              # or exact = "/healthz", or path_regex = "^/items/[0-9]+$"
+// backend = "laptop_1:8080"  # or "@<pool>"
+// header = { X-Env = "staging" }
+//
+// [[pool]]
+// name = "web"
+// strategy = "round_robin"
+// backends = [{ addr = "laptop_1:web", weight = 3 }, { addr = "laptop_2:web" }]
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RoutesFile {
+    default_backend: Option<String>,
+    #[serde(default)]
+    route: Vec<RouteEntry>,
+    #[serde(default)]
+    pool: Vec<PoolEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteEntry {
+    host: String,
+    path: Option<String>,
+    exact: Option<String>,
+    path_regex: Option<String>,
+    backend: String,
+    method: Option<String>,
+    //name -> value: "*" only needs it to be there, "~<regex>" matches a pattern
+    #[serde(default)]
+    header: BTreeMap<String, String>,
+    #[serde(default)]
+    query: BTreeMap<String, String>,
+    #[serde(default)]
+    cookie: BTreeMap<String, String>,
+    rewrite: Option<RewriteEntry>,
+    access: Option<AccessEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RewriteEntry {
+    strip_prefix: Option<String>,
+    host: Option<String>,
+    #[serde(default)]
+    set_request_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    remove_request_headers: Vec<String>,
+    #[serde(default)]
+    add_response_headers: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessEntry {
+    realm: String,
+    #[serde(default)]
+    allow_ips: Vec<String>,
+    #[serde(default)]
+    basic: BTreeMap<String, String>, //user -> Argon2id PHC string
+    #[serde(default)]
+    bearer: Vec<String>, //blake3 hex digests
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolEntry {
+    name: String,
+    //round_robin, least_connections, hash_client_ip or header:<name>
+    #[serde(default = "default_strategy")]
+    strategy: String,
+    backends: Vec<PoolBackend>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolBackend {
+    addr: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_strategy() -> String {
+    "round_robin".to_string()
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Build the routing table from a routes file, see `parse_routes`.
+pub fn load_routes(path: impl AsRef<Path>) -> anyhow::Result<RoutingTable> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read routes file {}: {}", path.display(), e))?;
+    parse_routes(&data)
+        .map_err(|e| anyhow::anyhow!("Invalid routes file {}: {}", path.display(), e))
+}
+
+/// Build a routing table from the TOML of a routes file. A bad route fails the whole file,
+/// so a typo never goes live as a half-applied table.
+pub fn parse_routes(data: &str) -> anyhow::Result<RoutingTable> {
+    let file: RoutesFile = toml::from_str(data)?;
+    let mut routing_table = RoutingTable::new();
+    routing_table
+        .default_backend_addr
+        .extend(file.default_backend);
+    for pool in file.pool {
+        routing_table.insert_pool(&pool.name, build_pool(&pool)?);
+    }
+    for route in file.route {
+        let host = route.host.clone();
+        let rule = build_rule(route).map_err(|e| anyhow::anyhow!("route of {}: {}", host, e))?;
+        match host.strip_prefix('~') {
+            Some(pattern) => routing_table.insert_regex_route(pattern, rule)?,
+            None => routing_table.insert_route(host, rule),
+        }
+    }
+    Ok(routing_table)
+}
+
+fn build_pool(pool: &PoolEntry) -> anyhow::Result<BackendPool> {
+    let strategy = match pool.strategy.as_str() {
+        "round_robin" => LbStrategy::RoundRobin,
+        "least_connections" => LbStrategy::LeastConnections,
+        "hash_client_ip" => LbStrategy::HashClientIp,
+        other => match other.strip_prefix("header:") {
+            Some(name) => LbStrategy::HashHeader(name.to_string()),
+            None => anyhow::bail!("pool {}: unknown strategy {}", pool.name, other),
+        },
+    };
+    Ok(pool
+        .backends
+        .iter()
+        .fold(BackendPool::new(strategy), |built, backend| {
+            built.with_backend(&backend.addr, backend.weight)
+        }))
+}
+
+fn build_rule(route: RouteEntry) -> anyhow::Result<RouteRule> {
+    let path = match (route.path, route.exact, route.path_regex) {
+        (Some(prefix), None, None) => PathMatch::Prefix(prefix),
+        (None, Some(exact), None) => PathMatch::Exact(exact),
+        (None, None, Some(pattern)) => PathMatch::Regex(Regex::new(&pattern)?),
+        _ => anyhow::bail!("set exactly one of path, exact or path_regex"),
+    };
+    let mut rule = RouteRule::new(path, route.backend);
+    if let Some(method) = route.method {
+        rule = rule.with_predicate(Predicate::Method(method));
+    }
+    for (name, value) in route.header {
+        rule = rule.with_predicate(Predicate::Header(name, value_match(&value)?));
+    }
+    for (name, value) in route.query {
+        rule = rule.with_predicate(Predicate::Query(name, value_match(&value)?));
+    }
+    for (name, value) in route.cookie {
+        rule = rule.with_predicate(Predicate::Cookie(name, value_match(&value)?));
+    }
+    if let Some(rewrite) = route.rewrite {
+        rule = rule.with_rewrite(build_rewrite(rewrite));
+    }
+    if let Some(access) = route.access {
+        rule = rule.with_access(build_access(access)?);
+    }
+    Ok(rule)
+}
+
+fn value_match(value: &str) -> anyhow::Result<ValueMatch> {
+    if value == "*" {
+        return Ok(ValueMatch::Present);
+    }
+    Ok(match value.strip_prefix('~') {
+        Some(pattern) => ValueMatch::Regex(Regex::new(pattern)?),
+        None => ValueMatch::Equals(value.to_string()),
+    })
+}
+
+fn build_rewrite(entry: RewriteEntry) -> Rewrite {
+    let mut rewrite = Rewrite::new();
+    if let Some(prefix) = &entry.strip_prefix {
+        rewrite = rewrite.strip_prefix(prefix);
+    }
+    if let Some(host) = &entry.host {
+        rewrite = rewrite.host(host);
+    }
+    for (name, value) in &entry.set_request_headers {
+        rewrite = rewrite.set_request_header(name, value);
+    }
+    for name in &entry.remove_request_headers {
+        rewrite = rewrite.remove_request_header(name);
+    }
+    for (name, value) in &entry.add_response_headers {
+        rewrite = rewrite.add_response_header(name, value);
+    }
+    rewrite
+}
+
+fn build_access(entry: AccessEntry) -> anyhow::Result<AccessPolicy> {
+    let mut access = AccessPolicy::new(&entry.realm);
+    for network in &entry.allow_ips {
+        access = access.allow_ip(
+            network
+                .parse()
+                .map_err(|e| anyhow::anyhow!("network {}: {}", network, e))?,
+        );
+    }
+    for (user, password_hash) in &entry.basic {
+        access = access.allow_basic(user, password_hash);
+    }
+    for token_hash in &entry.bearer {
+        access = access.allow_bearer(token_hash)?;
+    }
+    Ok(access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_routes_file_loads() {
+        let table = parse_routes(include_str!("../../../routes.example.toml")).unwrap();
+        let lookup = |host: &str, path: &str| table.lookup_with_path(host.into(), path.into());
+
+        assert_eq!(
+            lookup("api.example.com", "/v1/users").as_deref(),
+            Some("laptop_1:8080")
+        );
+        assert_eq!(
+            lookup("api.example.com", "/healthz").as_deref(),
+            Some("laptop_1:8002")
+        );
+        assert_eq!(
+            lookup("box.dev.example.com", "/").as_deref(),
+            Some("laptop_1:8002")
+        );
+        assert_eq!(lookup("lb.example.com", "/").as_deref(), Some("@web"));
+        assert_eq!(
+            lookup("laptop_3.nodes.example.com", "/").as_deref(),
+            Some("laptop_3:web")
+        );
+        assert_eq!(lookup("unknown.example.org", "/"), None);
+        assert!(table.pool("web").is_some());
+
+        let share = table.lookup("share.example.com".into()).unwrap();
+        assert!(
+            share[0]
+                .access
+                .as_ref()
+                .is_some_and(|access| access.has_credentials())
+        );
+    }
+
+    #[test]
+    fn empty_file_is_an_empty_table() {
+        let table = parse_routes("").unwrap();
+        assert!(table.list_rules().is_empty());
+        assert!(table.default_backend_addr.is_empty());
+    }
+
+    #[test]
+    fn bad_routes_are_refused() {
+        //two path matches
+        assert!(
+            parse_routes(
+                "[[route]]\nhost = \"a\"\npath = \"/\"\nexact = \"/\"\nbackend = \"n:web\""
+            )
+            .is_err()
+        );
+        //typo in a key
+        assert!(
+            parse_routes("[[route]]\nhost = \"a\"\npaht = \"/\"\nbackend = \"n:web\"").is_err()
+        );
+        //bad host pattern
+        assert!(
+            parse_routes("[[route]]\nhost = \"~(\"\npath = \"/\"\nbackend = \"n:web\"").is_err()
+        );
+        assert!(
+            parse_routes("[[pool]]\nname = \"web\"\nstrategy = \"random\"\nbackends = []").is_err()
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use super::access_policy::AccessPolicy;
+use super::helper::RequestHead;
+use super::rewrite::Rewrite;
+use regex::Regex;
+use std::sync::Arc;
+
+/// How a rule matches the request path (query string excluded).
+#[derive(Clone)]
+pub enum PathMatch {
+    Prefix(String),
+    Exact(String),
+    Regex(Regex),
+}
+
+impl PathMatch {
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathMatch::Exact(exact) => path == exact,
+            PathMatch::Regex(regex) => regex.is_match(path),
+        }
+    }
+
+    /// The path as the admin wrote it, used to find a rule again for removal/update.
+    pub fn as_str(&self) -> &str {
+        match self {
+            PathMatch::Prefix(path) | PathMatch::Exact(path) => path,
+            PathMatch::Regex(regex) => regex.as_str(),
+        }
+    }
+}
+
+/// How a header, query parameter or cookie value is compared.
+#[derive(Clone)]
+pub enum ValueMatch {
+    Present,
+    Equals(String),
+    Regex(Regex),
+}
+
+impl ValueMatch {
+    fn matches(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (_, None) => false,
+            (ValueMatch::Present, Some(_)) => true,
+            (ValueMatch::Equals(expected), Some(value)) => value == expected,
+            (ValueMatch::Regex(regex), Some(value)) => regex.is_match(value),
+        }
+    }
+}
+
+/// Extra condition a request has to meet, on top of its host and path.
+#[derive(Clone)]
+pub enum Predicate {
+    Method(String),
+    Header(String, ValueMatch), //header names are compared case-insensitively
+    Query(String, ValueMatch),
+    Cookie(String, ValueMatch),
+}
+
+impl Predicate {
+    fn matches(&self, request: &RequestHead) -> bool {
+        match self {
+            Predicate::Method(method) => request.method.eq_ignore_ascii_case(method),
+            Predicate::Header(name, value) => {
+                value.matches(request.header(&name.to_ascii_lowercase()))
+            }
+            Predicate::Query(name, value) => value.matches(request.query_param(name)),
+            Predicate::Cookie(name, value) => value.matches(request.cookie(name)),
+        }
+    }
+}
+
+/// One route of a host: a path match, optional predicates, the backend (`node:service`) and
+/// how to rewrite the traffic on the way (see `Rewrite`).
+///
+/// ```ignore
+/// //canary: staging traffic of /api goes to the second laptop
+/// RouteRule::new(PathMatch::Prefix("/api".into()), "laptop_2:web".into())
+///     .with_predicate(Predicate::Header("X-Env".into(), ValueMatch::Equals("staging".into())))
+/// ```
+#[derive(Clone)]
+pub struct RouteRule {
+    pub path: PathMatch,
+    pub predicates: Vec<Predicate>,
+    pub backend_addr: String,
+    pub rewrite: Rewrite,
+    pub access: Option<Arc<AccessPolicy>>,
+}
+
+impl RouteRule {
+    pub fn new(path: PathMatch, backend_addr: String) -> Self {
+        Self {
+            path,
+            predicates: Vec::new(),
+            backend_addr,
+            rewrite: Rewrite::new(),
+            access: None,
+        }
+    }
+
+    pub fn with_predicate(mut self, predicate: Predicate) -> Self {
+        self.predicates.push(predicate);
+        self
+    }
+
+    pub fn with_rewrite(mut self, rewrite: Rewrite) -> Self {
+        self.rewrite = rewrite;
+        self
+    }
+
+    pub fn with_access(mut self, access: AccessPolicy) -> Self {
+        self.access = Some(Arc::new(access));
+        self
+    }
+
+    pub fn matches(&self, request: &RequestHead) -> bool {
+        self.path.matches(&request.path) && self.predicates.iter().all(|p| p.matches(request))
+    }
+
+    //Higher is more specific: exact paths, then regex paths, then the longest prefix.
+    //Between rules with the same path, the one with more predicates is more specific.
+    fn specificity(&self) -> (u8, usize, usize) {
+        match &self.path {
+            PathMatch::Exact(_) => (2, 0, self.predicates.len()),
+            PathMatch::Regex(_) => (1, 0, self.predicates.len()),
+            PathMatch::Prefix(prefix) => (0, prefix.len(), self.predicates.len()),
+        }
+    }
+}
+
+/// Pick the most specific rule matching the request. Equally specific rules are tried in
+/// the order they were inserted.
+pub fn best_match<'a>(rules: &'a [RouteRule], request: &RequestHead) -> Option<&'a RouteRule> {
+    rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.matches(request))
+        .max_by_key(|(index, rule)| (rule.specificity(), std::cmp::Reverse(*index)))
+        .map(|(_, rule)| rule)
+}
@@ -1,4 +1,11 @@
+use super::access_policy::AccessPolicy;
+use super::backend_pool::BackendPool;
+use super::helper::{RequestHead, normalize_host};
+use super::rewrite::Rewrite;
+use super::route_rule::{PathMatch, RouteRule, best_match};
 use dashmap::DashMap;
+use regex::Regex;
+use std::sync::{Arc, RwLock};
 
 //For example, host will be api.example.com
 //path will be 127.0.0.1:8080
@@ -13,24 +20,96 @@ use dashmap::DashMap;
 //       ("/", "127.0.0.1:9000".to_string())
 //     ]
 //   },
-//   default_backend: Some("127.0.0.1:8080".to_string())
+//   wildcards: {
+//     "dev.example.com": vec![("/", "dev_box:web".to_string())] // from "*.dev.example.com"
+//   },
+//   regex_rules: [ ^(?P<node>[a-z0-9_]+)\.nodes\.example\.com$ "/" -> "$node:web" ],
+//   (each tuple above is really a RouteRule, which can also require a method, header, query
+//    parameter or cookie, and match the path exactly or by regex instead of by prefix)
+//   default_backend: None (unmatched requests get a 404)
 // }
 
-#[derive(Clone)]
+/// What a lookup resolved to: the backend, rewrite and access policy of the rule that matched.
+pub struct Route {
+    pub backend_addr: String,
+    pub rewrite: Rewrite,
+    pub access: Option<Arc<AccessPolicy>>,
+}
+
+impl Route {
+    fn from_rule(rule: &RouteRule) -> Self {
+        Self {
+            backend_addr: rule.backend_addr.clone(),
+            rewrite: rule.rewrite.clone(),
+            access: rule.access.clone(),
+        }
+    }
+}
+
+/// One rule as `RoutingTable::list_rules` reports it.
+pub struct RuleSummary {
+    pub host: String,
+    pub path: String,
+    pub backend_addr: String,
+}
+
+//host -> rules of that host
+type HostRules = DashMap<String, Vec<RouteRule>>;
+
+/// A host rule given as a regular expression, e.g. `^(?P<node>[a-z0-9_]+)\.nodes\.example\.com$`.
+///
+/// Captures can be used in the backend: `$node:web` routes `laptop_1.nodes.example.com`
+/// to `laptop_1:web`.
+pub struct RegexRule {
+    pub host: Regex,
+    pub rule: RouteRule,
+}
+
+/// Host/path based routing rules of the reverse proxy.
+///
+/// Hosts are normalised before they are stored or looked up (see `normalize_host`), so
+/// case, a `:port` suffix, a trailing dot and IDNA spelling don't matter.
+///
+/// A lookup tries the rules in this order and the first one with a matching path wins:
+/// 1. exact host rules (`api.example.com`),
+/// 2. wildcard rules, most specific first: `a.b.example.com` tries `*.b.example.com` and
+///    then `*.example.com`. A wildcard covers subdomains of any depth, but not the bare
+///    domain itself,
+/// 3. regex rules, in the order they were inserted,
+/// 4. the default backend, if one is set. Without it the lookup returns `None`.
+///
+/// Within one host, the most specific matching rule wins (see `best_match`): an exact path
+/// beats a regex path, which beats the longest prefix, and more predicates beat fewer. A host
+/// whose rules don't match the request falls through to the next step instead of failing
+/// the lookup.
 pub struct RoutingTable {
-    pub table: DashMap<String, Vec<(String, String)>>,
+    pub table: HostRules,
+    pub wildcards: HostRules, //keyed by the suffix after "*."
+    pub regex_rules: RwLock<Vec<RegexRule>>,
+    pub pools: DashMap<String, Arc<BackendPool>>, //targeted by routes as "@<pool name>"
     pub default_backend_addr: Vec<String>,
 }
 
 impl RoutingTable {
     /// Create a new, empty routing table.
     ///
-    /// The default backend address is set to `"127.0.0.1:8080"` as known as localhost.
-    /// Nothing better than home
+    /// There is no default backend, a request no rule matches is answered with a 404.
+    /// Push a `node:service` to `default_backend_addr` to catch those instead.
     pub fn new() -> Self {
         Self {
             table: DashMap::new(),
-            default_backend_addr: ["127.0.0.1:8080".to_string()].to_vec(),
+            wildcards: DashMap::new(),
+            regex_rules: RwLock::new(Vec::new()),
+            pools: DashMap::new(),
+            default_backend_addr: Vec::new(),
+        }
+    }
+
+    //Exact hosts and wildcards ("*.dev.example.com") live in different maps
+    fn rules_for(&self, host: &str) -> (&HostRules, String) {
+        match host.trim().strip_prefix("*.") {
+            Some(suffix) => (&self.wildcards, normalize_host(suffix)),
+            None => (&self.table, normalize_host(host)),
         }
     }
 
@@ -40,55 +119,175 @@ impl RoutingTable {
     /// insert the path and backend address into the host's list of
     /// rules. If the host does not exist, it will be created. If the
     /// host does exist, the rule will be added to the existing list of
-    /// rules.
+    /// rules. A host starting with `*.` is a wildcard rule.
     pub fn insert_rule(&self, host: String, path: String, backend_addr: String) {
-        let rule = (path, backend_addr);
-        self.table.entry(host).or_insert(Vec::new()).push(rule); //insert if there no host key, push if there is 
+        self.insert_route(host, RouteRule::new(PathMatch::Prefix(path), backend_addr));
+    }
+
+    /// Insert a rule with its own path match and predicates, see `insert_rule`.
+    pub fn insert_route(&self, host: String, rule: RouteRule) {
+        let (map, host) = self.rules_for(&host);
+        map.entry(host).or_default().push(rule); //insert if there no host key, push if there is
+    }
+
+    /// Insert a regex host rule. Regex rules are tried after exact and wildcard rules, in
+    /// insertion order. The pattern is matched against the normalised (lowercase) host and
+    /// `$name`/`${1}` in the backend are replaced with its captures.
+    pub fn insert_regex_rule(
+        &self,
+        pattern: &str,
+        path: String,
+        backend_addr: String,
+    ) -> Result<(), regex::Error> {
+        self.insert_regex_route(
+            pattern,
+            RouteRule::new(PathMatch::Prefix(path), backend_addr),
+        )
+    }
+
+    /// Insert a regex host rule with its own path match and predicates, see `insert_regex_rule`.
+    pub fn insert_regex_route(&self, pattern: &str, rule: RouteRule) -> Result<(), regex::Error> {
+        let rule = RegexRule {
+            host: Regex::new(pattern)?,
+            rule,
+        };
+        self.regex_rules.write().unwrap().push(rule);
+        Ok(())
+    }
+
+    /// Register (or replace) a backend pool. Routes use it with the backend `@<name>`.
+    pub fn insert_pool(&self, name: &str, pool: BackendPool) {
+        self.pools.insert(name.to_string(), Arc::new(pool));
+    }
+
+    pub fn remove_pool(&self, name: &str) {
+        self.pools.remove(name);
+    }
+
+    pub fn pool(&self, name: &str) -> Option<Arc<BackendPool>> {
+        self.pools.get(name).map(|pool| pool.value().clone())
     }
 
     /// Remove a rule from the routing table.
     ///
-    /// Given a host, and a path, this function will remove the path from the host's
-    /// list of rules. If the host does not exist, this function does nothing.
-    pub fn remove_rule(&self, host: String, path: String) {
-        if let Some(mut rules) = self.table.get_mut(&host) {
-            rules.retain(|(prefix, _)| prefix != &path); //only keep rules that do not match the path
+    /// Given a host, and a path, this function will remove every rule with that path from
+    /// the host's list of rules. If the host does not exist, this function does nothing.
+    /// Returns whether a rule was removed.
+    pub fn remove_rule(&self, host: String, path: String) -> bool {
+        let (map, host) = self.rules_for(&host);
+        let Some(mut rules) = map.get_mut(&host) else {
+            return false;
+        };
+        let before = rules.len();
+        rules.retain(|rule| rule.path.as_str() != path); //only keep rules that do not match the path
+        rules.len() != before
+    }
+
+    /// Remove every regex rule with this exact pattern and path.
+    pub fn remove_regex_rule(&self, pattern: &str, path: &str) {
+        self.regex_rules.write().unwrap().retain(|regex_rule| {
+            regex_rule.host.as_str() != pattern || regex_rule.rule.path.as_str() != path
+        });
+    }
+
+    /// Every rule of the table in lookup order, for the admin: wildcard hosts are listed as
+    /// `*.<suffix>` and regex hosts as `~<pattern>`.
+    pub fn list_rules(&self) -> Vec<RuleSummary> {
+        let mut summaries = Vec::new();
+        let mut push = |host: String, rules: &[RouteRule]| {
+            for rule in rules {
+                summaries.push(RuleSummary {
+                    host: host.clone(),
+                    path: rule.path.as_str().to_string(),
+                    backend_addr: rule.backend_addr.clone(),
+                });
+            }
+        };
+        for entry in self.table.iter() {
+            push(entry.key().clone(), entry.value());
+        }
+        for entry in self.wildcards.iter() {
+            push(format!("*.{}", entry.key()), entry.value());
         }
+        for regex_rule in self.regex_rules.read().unwrap().iter() {
+            push(
+                format!("~{}", regex_rule.host.as_str()),
+                std::slice::from_ref(&regex_rule.rule),
+            );
+        }
+        summaries
     }
 
-    /// Given a host, return a vector of (path_prefix, backend_addr) that are associated with that host.
+    /// Given a host, return the rules that are associated with that host.
     /// If no host is found, return None.
-    pub fn lookup(&self, host: String) -> Option<Vec<(String, String)>> {
-        self.table.get(&host).map(|ref_val| ref_val.value().clone())
+    pub fn lookup(&self, host: String) -> Option<Vec<RouteRule>> {
+        let (map, host) = self.rules_for(&host);
+        map.get(&host).map(|ref_val| ref_val.value().clone())
     }
 
-    //find the host in the map.
-    //iterate over the path rules (sorted by length descending).
-    //find the first path_prefix where path.starts_with(path_prefix).
-    //return the corresponding backend address.
-    //fallback: If no rule matches, use default_backend.
+    /// Route by host and path only, as if it was a plain GET without headers.
     pub fn lookup_with_path(&self, host: String, path: String) -> Option<String> {
-        if let Some(rules) = self.table.get(&host) {
-            let mut sorted_rules = rules.clone();
-            sorted_rules.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
-
-            for (prefix, backend) in sorted_rules {
-                //if there is prefix, return backend
-                if path.starts_with(prefix.as_str()) {
-                    return Some(backend);
-                }
+        let request = RequestHead {
+            method: "GET".to_string(),
+            path,
+            headers: vec![("host".to_string(), host)],
+            ..Default::default()
+        };
+        self.route(&request).map(|route| route.backend_addr)
+    }
+
+    //normalise the host, then walk the precedence order documented on `RoutingTable`.
+    //fallback: If no rule matches, use default_backend.
+    pub fn route(&self, request: &RequestHead) -> Option<Route> {
+        let host = normalize_host(request.host().unwrap_or_default());
+
+        if let Some(route) = self
+            .table
+            .get(&host)
+            .and_then(|rules| best_match(&rules, request).map(Route::from_rule))
+        {
+            return Some(route);
+        }
+
+        //a.b.example.com -> try "b.example.com", then "example.com", then "com"
+        let mut rest = host.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if let Some(route) = self
+                .wildcards
+                .get(parent)
+                .and_then(|rules| best_match(&rules, request).map(Route::from_rule))
+            {
+                return Some(route);
+            }
+            rest = parent;
+        }
+
+        for regex_rule in self.regex_rules.read().unwrap().iter() {
+            if !regex_rule.rule.matches(request) {
+                continue;
+            }
+            if let Some(captures) = regex_rule.host.captures(&host) {
+                let mut route = Route::from_rule(&regex_rule.rule);
+                route.backend_addr.clear();
+                captures.expand(&regex_rule.rule.backend_addr, &mut route.backend_addr);
+                return Some(route);
             }
         }
 
         //if no rule matches, use default backend
-        Some(self.default_backend_addr[0].clone())
+        self.default_backend_addr.first().map(|backend_addr| Route {
+            backend_addr: backend_addr.clone(),
+            rewrite: Rewrite::new(),
+            access: None,
+        })
     }
 
     pub fn update_backend_addr(&mut self, host: String, path: String, new_backend_addr: String) {
-        if let Some(mut rules) = self.table.get_mut(&host) {
-            for (prefix, backend) in rules.iter_mut() {
-                if *prefix == path {
-                    *backend = new_backend_addr;
+        let (map, host) = self.rules_for(&host);
+        if let Some(mut rules) = map.get_mut(&host) {
+            for rule in rules.iter_mut() {
+                if rule.path.as_str() == path {
+                    rule.backend_addr = new_backend_addr;
                     break;
                 }
             }
@@ -96,27 +295,230 @@ impl RoutingTable {
     }
 }
 
-pub fn setup_routing_table() -> RoutingTable {
-    let routing_table = RoutingTable::new();
-    routing_table.insert_rule(
-        "api.example.com".to_string(),
-        "/v1/".to_string(),
-        "laptop_1:8080".to_string(),
-    );
-    routing_table.insert_rule(
-        "api.example.com".to_string(),
-        "/admin".to_string(),
-        "laptop_1:8001".to_string(),
-    );
-    routing_table.insert_rule(
-        "api.example.com".to_string(),
-        "/".to_string(),
-        "laptop_1:8002".to_string(),
-    );
-    routing_table.insert_rule(
-        "admin.example.com".to_string(),
-        "/".to_string(),
-        "laptop_1:9000".to_string(),
-    );
-    routing_table
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(table: &RoutingTable, host: &str, path: &str) -> Option<String> {
+        table.lookup_with_path(host.to_string(), path.to_string())
+    }
+
+    #[test]
+    fn host_is_normalised_on_insert_and_lookup() {
+        let table = RoutingTable::new();
+        table.insert_rule("API.Example.com".into(), "/".into(), "api:web".into());
+        table.insert_rule("bücher.example".into(), "/".into(), "books:web".into());
+        table.insert_rule("[::1]:8080".into(), "/".into(), "local:web".into());
+
+        for host in [
+            "api.example.com",
+            "API.EXAMPLE.COM",
+            "api.example.com.",
+            "api.example.com:8443",
+            "Api.Example.Com.:80",
+        ] {
+            assert_eq!(
+                lookup(&table, host, "/").as_deref(),
+                Some("api:web"),
+                "{host}"
+            );
+        }
+        for host in ["bücher.example", "BÜCHER.example", "xn--bcher-kva.example"] {
+            assert_eq!(
+                lookup(&table, host, "/").as_deref(),
+                Some("books:web"),
+                "{host}"
+            );
+        }
+        assert_eq!(lookup(&table, "[::1]", "/").as_deref(), Some("local:web"));
+        assert_eq!(
+            lookup(&table, "[::1]:9000", "/").as_deref(),
+            Some("local:web")
+        );
+        assert_eq!(lookup(&table, "other.example.com", "/"), None);
+    }
+
+    #[test]
+    fn wildcards_are_normalised_too() {
+        let table = RoutingTable::new();
+        table.insert_rule("*.Dev.Example.com.".into(), "/".into(), "dev:web".into());
+        assert_eq!(
+            lookup(&table, "box.DEV.example.com:8080", "/").as_deref(),
+            Some("dev:web")
+        );
+        assert!(table.remove_rule("*.dev.example.com".into(), "/".into()));
+        assert_eq!(lookup(&table, "box.dev.example.com", "/"), None);
+    }
+
+    #[test]
+    fn exact_beats_wildcard_beats_regex_beats_default() {
+        let mut table = RoutingTable::new();
+        table.insert_rule("api.example.com".into(), "/".into(), "exact:web".into());
+        table.insert_rule("*.example.com".into(), "/".into(), "wildcard:web".into());
+        table
+            .insert_regex_rule(
+                r"^(?P<node>[a-z0-9_]+)\.nodes\.example\.com$",
+                "/".into(),
+                "$node:web".into(),
+            )
+            .unwrap();
+        table
+            .insert_regex_rule(
+                r"^(?P<node>[a-z0-9_]+)\.example\.org$",
+                "/".into(),
+                "$node:web".into(),
+            )
+            .unwrap();
+        table.default_backend_addr.push("default:web".into());
+
+        assert_eq!(
+            lookup(&table, "api.example.com", "/").as_deref(),
+            Some("exact:web")
+        );
+        //the regex matches too, but the wildcard comes first
+        assert_eq!(
+            lookup(&table, "laptop_1.nodes.example.com", "/").as_deref(),
+            Some("wildcard:web")
+        );
+        assert_eq!(
+            lookup(&table, "laptop_1.example.org", "/").as_deref(),
+            Some("laptop_1:web")
+        );
+        assert_eq!(
+            lookup(&table, "example.net", "/").as_deref(),
+            Some("default:web")
+        );
+        //a wildcard doesn't cover the bare domain
+        assert_eq!(
+            lookup(&table, "example.com", "/").as_deref(),
+            Some("default:web")
+        );
+    }
+
+    #[test]
+    fn most_specific_wildcard_wins() {
+        let table = RoutingTable::new();
+        table.insert_rule("*.example.com".into(), "/".into(), "outer:web".into());
+        table.insert_rule("*.dev.example.com".into(), "/".into(), "inner:web".into());
+        assert_eq!(
+            lookup(&table, "a.b.dev.example.com", "/").as_deref(),
+            Some("inner:web")
+        );
+        assert_eq!(
+            lookup(&table, "a.prod.example.com", "/").as_deref(),
+            Some("outer:web")
+        );
+    }
+
+    #[test]
+    fn host_without_a_matching_path_falls_through() {
+        let mut table = RoutingTable::new();
+        table.insert_rule("api.example.com".into(), "/v1/".into(), "exact:web".into());
+        table.insert_rule("*.example.com".into(), "/v2/".into(), "wildcard:web".into());
+        table
+            .insert_regex_rule(r"^api\.example\.com$", "/v3/".into(), "regex:web".into())
+            .unwrap();
+        table.default_backend_addr.push("default:web".into());
+
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v1/a").as_deref(),
+            Some("exact:web")
+        );
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v2/a").as_deref(),
+            Some("wildcard:web")
+        );
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v3/a").as_deref(),
+            Some("regex:web")
+        );
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v4/a").as_deref(),
+            Some("default:web")
+        );
+    }
+
+    #[test]
+    fn longest_prefix_wins_within_a_host() {
+        let table = RoutingTable::new();
+        //inserted shortest last, so insertion order can't be what decides
+        table.insert_rule("api.example.com".into(), "/v1/".into(), "v1:web".into());
+        table.insert_rule(
+            "api.example.com".into(),
+            "/v1/users".into(),
+            "users:web".into(),
+        );
+        table.insert_rule("api.example.com".into(), "/".into(), "root:web".into());
+
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v1/users/7").as_deref(),
+            Some("users:web")
+        );
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v1/orders").as_deref(),
+            Some("v1:web")
+        );
+        assert_eq!(
+            lookup(&table, "api.example.com", "/v2/").as_deref(),
+            Some("root:web")
+        );
+    }
+
+    #[test]
+    fn exact_path_beats_longer_prefix() {
+        let table = RoutingTable::new();
+        table.insert_rule(
+            "api.example.com".into(),
+            "/healthz/".into(),
+            "prefix:web".into(),
+        );
+        table.insert_route(
+            "api.example.com".into(),
+            RouteRule::new(PathMatch::Exact("/healthz".into()), "exact:web".into()),
+        );
+        table.insert_rule("api.example.com".into(), "/".into(), "root:web".into());
+
+        assert_eq!(
+            lookup(&table, "api.example.com", "/healthz").as_deref(),
+            Some("exact:web")
+        );
+        assert_eq!(
+            lookup(&table, "api.example.com", "/healthz/x").as_deref(),
+            Some("prefix:web")
+        );
+    }
+
+    #[test]
+    fn protected_prefix_cannot_be_bypassed_with_odd_paths() {
+        use super::super::helper::parse_request_head;
+
+        let table = RoutingTable::new();
+        table.insert_route(
+            "api.example.com".into(),
+            RouteRule::new(PathMatch::Prefix("/admin".into()), "admin:web".into())
+                .with_access(AccessPolicy::new("admin")),
+        );
+        table.insert_rule("api.example.com".into(), "/".into(), "open:web".into());
+
+        for target in [
+            "/admin",
+            "/%61dmin",
+            "/%61%64%6D%69%6E/users",
+            "//admin",
+            "/x/../admin",
+            "/./admin",
+            "/%2e%2e/admin",
+            "/x/%2E%2E//admin/",
+        ] {
+            let head = format!("GET {} HTTP/1.1\r\nHost: api.example.com", target);
+            let request = parse_request_head(&head).unwrap();
+            let route = table.route(&request).unwrap();
+            assert_eq!(route.backend_addr, "admin:web", "{target}");
+            assert!(route.access.is_some(), "{target}");
+        }
+        //absolute URLs aren't paths and are refused before routing
+        assert!(
+            parse_request_head("GET http://api.example.com/admin HTTP/1.1\r\nHost: x").is_none()
+        );
+    }
 }
@@ -0,0 +1,60 @@
+use super::http_router::HttpRouter;
+use crate::pool::connection_guard::ConnectionGuard;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// One public HTTP(S) listener for every node, e.g. on ports 80/443 behind a DNS wildcard.
+///
+/// Requests are routed purely by Host/path, so nodes serving HTTP don't need a public
+/// port of their own. With `tls` set, TLS is terminated here using the server certificate.
+pub async fn start_shared_http_listener(
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    router: Arc<HttpRouter>,
+    guard: Arc<ConnectionGuard>,
+) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to bind shared HTTP listener on {}: {:?}", addr, e);
+            return;
+        }
+    };
+
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("Listening for public {} requests on {}", scheme, addr);
+
+    loop {
+        let (tcp_stream, remote_addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Accept error on {}: {:?}", addr, e);
+                continue;
+            }
+        };
+
+        //Turned away before the TLS handshake, a flood shouldn't cost us any crypto
+        let admitted = match guard.admit(remote_addr.ip()) {
+            Ok(admitted) => admitted,
+            Err(rejection) => {
+                eprintln!("Refused {} on {}: {}", remote_addr, addr, rejection);
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let _admitted = admitted;
+            match tls {
+                Some(acceptor) => match acceptor.accept(tcp_stream).await {
+                    Ok(tls_stream) => router.proxy(tls_stream, remote_addr).await,
+                    Err(e) => eprintln!("TLS handshake with {} failed: {:?}", remote_addr, e),
+                },
+                None => router.proxy(tcp_stream, remote_addr).await,
+            }
+        });
+    }
+}
@@ -1,22 +1,38 @@
 mod admin;
-mod forward;
+//Each binary only builds the forward handlers it uses, so there is no shared forward/mod.rs
+mod forward {
+    pub mod datagram;
+    pub mod server_tunnel_handler;
+}
 mod pool;
 mod reverse_proxy;
+mod session;
 
 use admin::node_store::NodeStore;
-use quinn::{Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig};
-use reverse_proxy::helper::{extract_host, extract_path};
-use reverse_proxy::routing_table::RoutingTable;
+use admin::session_token::SessionTokens;
+use pool::connection_guard::ConnectionGuard;
+use pool::traffic::{QuotaState, TrafficStore};
+use quinn::{Endpoint, ServerConfig, TransportConfig};
+use reverse_proxy::http_router::HttpRouter;
 use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::HashMap;
 use std::time::Duration;
 use std::{env, error::Error, fs::File, io::BufReader, net::SocketAddr, sync::Arc};
-use v_distributed_tunnel_v1::common::helper;
 //use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use v_distributed_tunnel_v1::common::admin::client_config::ServiceProtocol;
 
 use crate::reverse_proxy::routing_table;
 
+//Remote peers one public UDP port keeps a relay for, past that new peers are dropped
+const MAX_UDP_PEERS: usize = 1024;
+
 fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
@@ -48,13 +64,17 @@ fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
     Err("Failed to load private key".into())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_tcp_listener_for_port(
     port: u16,
+    protocol: ServiceProtocol,
     port_registry: Arc<pool::port_registry::PortRegistry>,
-    forward_fn: Arc<
-        dyn Fn(TcpStream, SendStream, RecvStream) -> tokio::task::JoinHandle<()> + Send + Sync,
-    >,
-    routing_table: Arc<routing_table::RoutingTable>,
+    forward_fn: forward::server_tunnel_handler::ForwardFn,
+    http_router: Arc<HttpRouter>,
+    guard: Arc<ConnectionGuard>,
+    traffic: Arc<TrafficStore>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
 ) {
     let ip = env::var("TUNNEL_IP").unwrap_or_else(|_| "0.0.0.0".to_string());
     let listener = match TcpListener::bind((ip, port)).await {
@@ -65,81 +85,249 @@ pub async fn start_tcp_listener_for_port(
         }
     };
 
-    println!("Listening for public TCP connections on port {}", port);
+    println!(
+        "Listening for public {} connections on port {}",
+        protocol, port
+    );
 
     loop {
-        let (tcp_stream, remote_addr) = match listener.accept().await {
+        let accepted = tokio::select! {
+            _ = cancel.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+        let (tcp_stream, remote_addr) = match accepted {
             Ok(x) => x,
             Err(e) => {
                 eprintln!("Accept error on port {}: {:?}", port, e);
                 continue;
             }
         };
+        //Checked before a task is even spawned, so a flood stays cheap
+        let admitted = match guard.admit(remote_addr.ip()) {
+            Ok(admitted) => admitted,
+            Err(rejection) => {
+                eprintln!("Refused {} on port {}: {}", remote_addr, port, rejection);
+                continue;
+            }
+        };
 
         //as usual, before feed routing these class into our as
         let registry_clone = port_registry.clone();
         let forward_fn = forward_fn.clone();
-        let routing_table = routing_table.clone();
+        let http_router = http_router.clone();
+        let guard = guard.clone();
+        let traffic = traffic.clone();
+
+        //Tracked so that the session can wait for this connection before giving the port back
+        tracker.spawn(async move {
+            let _admitted = admitted;
+            if protocol == ServiceProtocol::Http {
+                //HTTP goes through the routing table, the route may even point to another node
+                http_router.proxy(tcp_stream, remote_addr).await;
+                return;
+            }
 
-        tokio::spawn(async move {
-            //firstly, we take a look at top of http data
-            let mut buf = [0; 1024];
-            //we read without removing. therefore we peek 😉
-            let n = match tcp_stream.peek(&mut buf).await {
-                Ok(n) => n,
+            //Raw TCP skips the routing table, the port alone tells us the node and its service
+            let node_info = match registry_clone.get(&port) {
+                Some(info) => info,
+                None => {
+                    eprintln!("No node registered for port {}, dropping connection", port);
+                    return;
+                }
+            };
+            let service = node_info.service.clone();
+            if let Err(rejection) = guard.check_node(&node_info.node_id, remote_addr.ip()) {
+                eprintln!(
+                    "Refused {} for node {}: {}",
+                    remote_addr, node_info.node_id, rejection
+                );
+                return;
+            }
+            //Raw TCP has no way to say why, so just hang up right away
+            if !node_info.health.is_healthy(&service) {
+                eprintln!(
+                    "Service {} of node {} is unhealthy, dropping connection",
+                    service, node_info.node_id
+                );
+                return;
+            }
+
+            if let QuotaState::Exhausted { period, .. } = traffic.quota_state(&node_info.node_id) {
+                eprintln!(
+                    "Node {} used up its {} traffic quota, dropping connection",
+                    node_info.node_id, period
+                );
+                return;
+            }
+
+            let (mut send_stream, recv_stream) = match node_info.conn.open_bi().await {
+                Ok(x) => x,
                 Err(e) => {
-                    eprintln!("Failed to read from TCP stream: {}", e);
+                    eprintln!(
+                        "Failed to open QUIC stream to node {}: {:?}",
+                        node_info.node_id, e
+                    );
                     return;
                 }
             };
 
-            let http_data = String::from_utf8_lossy(&buf[..n]);
-            let host = extract_host(&http_data);
-            let path = extract_path(&http_data);
+            if let Err(e) =
+                forward::server_tunnel_handler::write_stream_header(&mut send_stream, &service)
+                    .await
+            {
+                eprintln!(
+                    "Failed to open service {} on node {}: {:?}",
+                    service, node_info.node_id, e
+                );
+                return;
+            }
 
-            println!("Host: {:?}, Path: {:?}", host, path);
+            // Start bidirectional forwarding
+            let meter = traffic.meter(&node_info.node_id, &service);
+            let _ = forward_fn(tcp_stream, send_stream, recv_stream, meter).await;
+            println!("Closed tunnel from {} on port {}", remote_addr, port);
+        });
+    }
+    println!("Stopped listening on port {}", port);
+}
 
-            //here, we use our routing table as a dictionary to look/map to our wanted backend
-            let backend = routing_table.lookup_with_path(
-                host.as_ref().unwrap().to_string(),
-                path.as_ref().unwrap().to_string(),
-            );
+pub async fn start_udp_listener_for_port(
+    port: u16,
+    port_registry: Arc<pool::port_registry::PortRegistry>,
+    guard: Arc<ConnectionGuard>,
+    traffic: Arc<TrafficStore>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
+) {
+    let ip = env::var("TUNNEL_IP").unwrap_or_else(|_| "0.0.0.0".to_string());
+    let socket = match UdpSocket::bind((ip, port)).await {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            eprintln!("Failed to bind UDP socket on port {}: {:?}", port, e);
+            return;
+        }
+    };
+
+    println!("Listening for public udp datagrams on port {}", port);
 
-            if let Some(backend_id) = backend {
-                let mut split = backend_id.split(':');
-                let node_id = split.next().unwrap();
-                let port: u16 = split.next().unwrap().parse().unwrap_or(8080);
-                let node_info = match registry_clone.get_by_node_id(node_id) {
-                    Some(info) => info,
-                    None => {
-                        eprintln!("No node registered for port {}, dropping connection", port);
-                        return;
-                    }
-                };
-
-                let (send_stream, recv_stream) = match node_info.conn.open_bi().await {
-                    Ok(x) => x,
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to open QUIC stream to node {}: {:?}",
-                            node_info.node_id, e
-                        );
-                        return;
-                    }
-                };
-
-                // Start bidirectional forwarding
-                forward_fn(tcp_stream, send_stream, recv_stream);
-                println!("Closed tunnel from {} on port {}", remote_addr, port);
-            } else {
+    //Every remote peer gets its own QUIC stream, we remember where to push its next datagrams
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; forward::datagram::MAX_DATAGRAM];
+    loop {
+        let received = tokio::select! {
+            _ = cancel.cancelled() => break,
+            received = socket.recv_from(&mut buf) => received,
+        };
+        let (n, peer) = match received {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Receive error on UDP port {}: {:?}", port, e);
+                continue;
+            }
+        };
+
+        //Never wait on one slow relay, that would stall every other peer on this port. It's UDP,
+        //a datagram the relay has no room for is simply dropped
+        if let Some(to_relay) = peers.get(&peer) {
+            match to_relay.try_send(buf[..n].to_vec()) {
+                Ok(()) | Err(TrySendError::Full(_)) => continue,
+                //relay went idle and quit, start a fresh stream for this peer
+                Err(TrySendError::Closed(_)) => {
+                    peers.remove(&peer);
+                }
+            }
+        }
+        //a new peer, forget the ones whose relay went idle so the map can't grow forever
+        peers.retain(|_, to_relay| !to_relay.is_closed());
+        if peers.len() >= MAX_UDP_PEERS {
+            eprintln!("Too many peers on UDP port {}, dropping {}", port, peer);
+            continue;
+        }
+
+        let node_info = match port_registry.get(&port) {
+            Some(info) => info,
+            None => {
                 eprintln!(
-                    "No backend found for host {:?} and path {:?}, dropping connection",
-                    host, path
+                    "No node registered for UDP port {}, dropping datagram",
+                    port
                 );
-                return;
+                continue;
+            }
+        };
+        //A new peer is a new connection as far as the limits are concerned
+        let admitted = match guard.admit(peer.ip()).and_then(|admitted| {
+            guard
+                .check_node(&node_info.node_id, peer.ip())
+                .map(|_| admitted)
+        }) {
+            Ok(admitted) => admitted,
+            Err(rejection) => {
+                eprintln!("Refused {} on UDP port {}: {}", peer, port, rejection);
+                continue;
+            }
+        };
+        if traffic.quota_state(&node_info.node_id) != QuotaState::Available {
+            continue;
+        }
+        let (mut send_stream, recv_stream) = match node_info.conn.open_bi().await {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!(
+                    "Failed to open QUIC stream to node {}: {:?}",
+                    node_info.node_id, e
+                );
+                continue;
             }
+        };
+        if let Err(e) = forward::server_tunnel_handler::write_stream_header(
+            &mut send_stream,
+            &node_info.service,
+        )
+        .await
+        {
+            eprintln!(
+                "Failed to open service {} on node {}: {:?}",
+                node_info.service, node_info.node_id, e
+            );
+            continue;
+        }
+
+        let (to_relay, from_peer) = mpsc::channel(64);
+        let _ = to_relay.try_send(buf[..n].to_vec());
+        peers.insert(peer, to_relay);
+        let relay = forward::server_tunnel_handler::relay_udp_peer(
+            socket.clone(),
+            peer,
+            send_stream,
+            recv_stream,
+            from_peer,
+            traffic.meter(&node_info.node_id, &node_info.service),
+        );
+        tracker.spawn(async move {
+            let _admitted = admitted;
+            relay.await
         });
     }
+    //Dropping the senders ends every peer relay, the socket closes once the last one is gone
+    drop(peers);
+    println!("Stopped listening on UDP port {}", port);
+}
+
+//TLS for the shared HTTPS listener and a remote admin port, terminated with the same
+//certificate as the QUIC endpoint
+fn make_tls_acceptor(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    alpn: Option<&[u8]>,
+) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let mut tls_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    tls_config.alpn_protocols = alpn.into_iter().map(<[u8]>::to_vec).collect();
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
 }
 
 #[tokio::main]
@@ -158,7 +346,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     //The key is used intentional by server to prove to client that server is the one who control the cert
     //When a client connect, server only present its cert as part of the TLS handshake.
-    let mut server_config = ServerConfig::with_single_cert(certs, key)?;
+    let mut server_config = ServerConfig::with_single_cert(certs.clone(), key.clone_key())?;
     Arc::get_mut(&mut server_config.transport)
         .unwrap()
         .max_concurrent_bidi_streams(100u32.into()); //TODO: Change the number of concurrent connections later. This 100 just for test
@@ -169,176 +357,184 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let address: SocketAddr = addr.parse()?;
     let endpoint = Endpoint::server(server_config, address)?;
 
-    //Load routing table (for our reverse proxy)
-    let routing_table = Arc::new(routing_table::setup_routing_table());
+    //Load routing table (for our reverse proxy) from ROUTES_FILE, see routes.example.toml.
+    //Without it the table starts empty and routes are added from the admin CLI
+    let routing_table = match env::var("ROUTES_FILE") {
+        Ok(path) => reverse_proxy::route_config::load_routes(path)?,
+        Err(_) => routing_table::RoutingTable::new(),
+    };
+    let routing_table = Arc::new(routing_table);
+
+    //Security relevant events (logins, lockouts, admin changes...) as JSON lines
+    let audit_file = env::var("AUDIT_LOG_FILE").unwrap_or_else(|_| "audit.log".to_string());
+    let audit = Arc::new(admin::audit::AuditLog::open(audit_file)?);
+
+    //Nodes with their chains and settings, kept in nodes.toml (or NODES_FILE) across restarts
+    let nodes_file = env::var("NODES_FILE").unwrap_or_else(|_| "nodes.toml".to_string());
+    let node_store = Arc::new(NodeStore::load(nodes_file, audit.clone())?);
 
-    //Create node store
-    let node_store = Arc::new(NodeStore::new());
+    //Load the ports admins pinned to nodes, these survive restarts
+    let lease_file = env::var("PORT_LEASE_FILE").unwrap_or_else(|_| "port_leases.toml".to_string());
+    let leases = Arc::new(pool::port_lease::LeaseStore::load(lease_file)?);
+
+    //Prepare our port pool (item to offer) before welcome our guesses (client)
+    let port_pool = Arc::new(pool::port_pool::PortPool::new(5001, 5999, leases));
+
+    //Every public connection goes through this first: ALLOW_CIDRS / DENY_CIDRS (comma separated),
+    //CONN_RATE_PER_MIN and MAX_CONNS_PER_IP (0 turns a limit off) and IPV6_LIMIT_PREFIX.
+    //The admin can change them later.
+    let global_rules = pool::connection_guard::IpRules {
+        allow: pool::connection_guard::parse_networks(
+            &env::var("ALLOW_CIDRS").unwrap_or_default(),
+        )?,
+        deny: pool::connection_guard::parse_networks(&env::var("DENY_CIDRS").unwrap_or_default())?,
+    };
+    let mut limits = pool::connection_guard::Limits::default();
+    if let Ok(rate) = env::var("CONN_RATE_PER_MIN") {
+        limits.rate_per_minute = rate.parse()?;
+    }
+    if let Ok(max) = env::var("MAX_CONNS_PER_IP") {
+        limits.max_per_ip = max.parse()?;
+    }
+    //IPv6 sources share their limits per network of this size, a /64 unless IPV6_LIMIT_PREFIX says otherwise
+    if let Ok(prefix) = env::var("IPV6_LIMIT_PREFIX") {
+        limits.ipv6_prefix = prefix.parse()?;
+        if limits.ipv6_prefix > 128 {
+            return Err("IPV6_LIMIT_PREFIX must be a prefix length from 0 to 128".into());
+        }
+    }
+    let guard = Arc::new(ConnectionGuard::new(global_rules, limits));
+    tokio::spawn(guard.clone().prune_periodically());
+
+    let auth_guard = Arc::new(admin::auth_guard::AuthGuard::new(audit.clone()));
+    //Tokens for reconnecting without a fresh preimage, signed with SESSION_TOKEN_SECRET if set.
+    //Revocations are kept in revoked_tokens.toml (or REVOKED_TOKENS_FILE) across restarts
+    let revoked_file =
+        env::var("REVOKED_TOKENS_FILE").unwrap_or_else(|_| "revoked_tokens.toml".to_string());
+    let tokens = Arc::new(SessionTokens::new(
+        env::var("SESSION_TOKEN_SECRET").ok().as_deref(),
+        revoked_file,
+    )?);
+
+    //Bandwidth limits, traffic quotas and usage per node, kept across restarts
+    let traffic_file =
+        env::var("NODE_TRAFFIC_FILE").unwrap_or_else(|_| "node_traffic.toml".to_string());
+    let traffic = Arc::new(TrafficStore::load(traffic_file)?);
+    tokio::spawn(traffic.clone().flush_periodically());
+
+    let port_registry = pool::port_registry::PortRegistry::new();
+    let port_registry = Arc::new(port_registry);
+
+    //Live sessions, one per node
+    let sessions = Arc::new(session::session_registry::SessionRegistry::new());
 
     //Start admin CLI listener
     //This help us add new node info to our memory!
+    //Only on loopback unless ADMIN_LISTEN_ADDR says otherwise, then it needs ADMIN_TOKEN and speaks TLS
+    let admin_addr: SocketAddr = env::var("ADMIN_LISTEN_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:6969".to_string())
+        .parse()?;
+    let admin_token = env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    let admin_tls = if admin_addr.ip().is_loopback() {
+        None
+    } else {
+        if admin_token.is_none() {
+            return Err("ADMIN_LISTEN_ADDR is not a loopback address, set ADMIN_TOKEN too".into());
+        }
+        Some(make_tls_acceptor(certs.clone(), key.clone_key(), None)?)
+    };
     tokio::spawn(admin::admin_listener::start_admin_listener(
-        node_store.clone(),
+        admin_addr,
+        admin_tls,
+        admin_token,
+        Arc::new(admin::admin_listener::AdminContext {
+            node_store: node_store.clone(),
+            port_pool: port_pool.clone(),
+            guard: guard.clone(),
+            traffic: traffic.clone(),
+            auth_guard: auth_guard.clone(),
+            tokens: tokens.clone(),
+            audit_log: audit.clone(),
+            routing_table: routing_table.clone(),
+            sessions: sessions.clone(),
+        }),
     ));
 
-    //Prepare our port pool (item to offer) before welcome our guesses (client)
-    let port_pool = Arc::new(pool::port_pool::PortPool::new(5001, 5999));
+    //Error pages of the HTTP edge, customizable per host with ERROR_PAGES_DIR (<host>.html / <host>.json)
+    let error_pages = match env::var("ERROR_PAGES_DIR") {
+        Ok(dir) => reverse_proxy::error_pages::ErrorPages::load(dir)?,
+        Err(_) => reverse_proxy::error_pages::ErrorPages::new(),
+    };
 
-    let port_registry = pool::port_registry::PortRegistry::new();
-    let port_registry = Arc::new(port_registry);
+    //Routes HTTP requests by Host/path to whichever node owns the route
+    let http_router = Arc::new(HttpRouter::new(
+        routing_table.clone(),
+        sessions.clone(),
+        Arc::new(error_pages),
+        guard.clone(),
+        traffic.clone(),
+    ));
+
+    //Optional shared public listeners, e.g. HTTP_LISTEN_ADDR=0.0.0.0:80 and HTTPS_LISTEN_ADDR=0.0.0.0:443.
+    //When one is on, http services are served through it and don't get a port of their own.
+    let mut shared_http = false;
+    if let Ok(addr) = env::var("HTTP_LISTEN_ADDR") {
+        let addr: SocketAddr = addr.parse()?;
+        tokio::spawn(reverse_proxy::shared_listener::start_shared_http_listener(
+            addr,
+            None,
+            http_router.clone(),
+            guard.clone(),
+        ));
+        shared_http = true;
+    }
+    if let Ok(addr) = env::var("HTTPS_LISTEN_ADDR") {
+        let addr: SocketAddr = addr.parse()?;
+        let acceptor = make_tls_acceptor(certs, key, Some(b"http/1.1"))?;
+        tokio::spawn(reverse_proxy::shared_listener::start_shared_http_listener(
+            addr,
+            Some(acceptor),
+            http_router.clone(),
+            guard.clone(),
+        ));
+        shared_http = true;
+    }
+
+    let tunnel = Arc::new(session::handshake::TunnelContext {
+        node_store,
+        port_pool,
+        port_registry,
+        http_router,
+        sessions,
+        guard,
+        traffic,
+        auth_guard,
+        tokens,
+        audit,
+        shared_http,
+    });
 
     //Welcome some new clients.
     while let Some(connecting) = endpoint.accept().await {
-        let node_store = node_store.clone();
-        //Getting another reference to use in each thread share same pool.
-        let port_pool = port_pool.clone();
-        let port_registry = port_registry.clone();
-        let routing_table = routing_table.clone();
+        //An address that kept failing to authenticate doesn't even get a handshake
+        if let Some(remaining) = tunnel
+            .auth_guard
+            .ip_lockout(connecting.remote_address().ip())
+        {
+            eprintln!(
+                "Refusing {}, locked out for another {}s",
+                connecting.remote_address(),
+                remaining.as_secs()
+            );
+            connecting.refuse();
+            continue;
+        }
+        let tunnel = tunnel.clone();
         tokio::spawn(async move {
             match connecting.await {
-                Ok(conn) => {
-                    println!("Accepted new connection from {}", conn.remote_address());
-                    while let Ok((mut send_stream, mut recv_stream)) = conn.accept_bi().await {
-                        let mut buf = vec![0; 128];
-                        let n = match recv_stream.read(&mut buf).await.unwrap() {
-                            Some(n) => n,
-                            None => continue, //Just skip if receive nothing from client
-                        };
-                        let auth_line = String::from_utf8_lossy(&buf[..n]);
-                        let parts: Vec<&str> = auth_line.trim().splitn(3, ' ').collect();
-                        //parts will have three parts: Header (AUTH), <node id> and <hex preimage>.
-                        if parts.len() < 3 {
-                            send_stream
-                                .write(b"Unauthorized: Auth line lack of arguments\n")
-                                .await
-                                .unwrap();
-                            continue;
-                        }
-
-                        if parts.len() > 3 {
-                            send_stream
-                                .write(b"Unauthorized: Auth line has too many arguments\n")
-                                .await
-                                .unwrap();
-                            continue;
-                        }
-
-                        if parts[0] != "AUTH" {
-                            send_stream
-                                .write(b"Unauthorized: Invalid auth header\n")
-                                .await
-                                .unwrap();
-                            continue;
-                        }
-
-                        let node_id = parts[1].trim();
-                        let preimage = parts[2].trim();
-
-                        let (is_authorized, new_seed) =
-                            admin::login::verify_node(&node_store, node_id, preimage);
-                        if !is_authorized {
-                            send_stream
-                                .write(b"Unauthorized: Invalid node id or preimage\n")
-                                .await
-                                .unwrap();
-                            continue;
-                        } else {
-                            send_stream.write(b"Authorized: Success\n").await.unwrap();
-                            if new_seed.is_some() {
-                                send_stream
-                                    .write(format!("ROTATE {}\n", new_seed.unwrap()).as_bytes())
-                                    .await
-                                    .unwrap();
-                            }
-                            let node_seed_opt = node_store.get_seed(node_id);
-                            let assigned_result =
-                                port_pool.assign_static_port(node_id, node_seed_opt.as_deref());
-                            match assigned_result {
-                                pool::port_pool::StaticPortAssignResult::Success(port) => {
-                                    println!("Assigned port {} to node '{}'", port, node_id);
-                                    send_stream
-                                        .write(
-                                            format!("ASSIGNED {}\n", port).as_bytes(), //Send back protocal message ASSIGNED <port>
-                                        )
-                                        .await
-                                        .unwrap();
-                                    println!("Sent port {} to node '{}'", port, node_id,);
-
-                                    //Create an instance of port guard to release the port after the client is disconnected or something go wrong.
-                                    let port_guard = pool::port_pool::PortGuard {
-                                        port_pool: port_pool.clone(),
-                                        port: port,
-                                        node_id: node_id.to_string(),
-                                    };
-
-                                    //Each assigned port will have it own tcp listener
-                                    let node_info = pool::port_registry::NodeInfo {
-                                        conn: conn.clone(),
-                                        node_id: node_id.to_string(),
-                                    };
-                                    port_registry.insert(port, node_info);
-
-                                    //Create a clone to feed into each async tcp listener
-                                    let port_registry = port_registry.clone();
-                                    let forward_fn =
-                                        forward::server_tunnel_handler::make_forward_fn();
-                                    let routing_table = routing_table.clone();
-                                    tokio::spawn(async move {
-                                        start_tcp_listener_for_port(
-                                            port,
-                                            port_registry,
-                                            forward_fn,
-                                            routing_table,
-                                        )
-                                        .await;
-                                    });
-
-                                    //MAIN SESSION LOOP
-                                    //accept new bidirectional streams from client as long as session is alive
-                                    let _guard = port_guard;
-                                    loop {
-                                        match conn.accept_bi().await {
-                                            Ok((send_stream, recv_stream)) => {
-                                                //ignore rn
-                                            }
-                                            Err(_) => {
-                                                //session end, portguard release.
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }
-                                pool::port_pool::StaticPortAssignResult::SeedMissing => {
-                                    send_stream
-                                        .write(b"Service unavailable: Seed missing\n")
-                                        .await
-                                        .unwrap();
-                                    continue;
-                                }
-                                pool::port_pool::StaticPortAssignResult::SeedHexInvalid => {
-                                    send_stream
-                                        .write(b"Service unavailable: Seed hex invalid\n")
-                                        .await
-                                        .unwrap();
-                                    continue;
-                                }
-                                pool::port_pool::StaticPortAssignResult::PortInUse(port) => {
-                                    send_stream
-                                        .write(
-                                            format!(
-                                                "Service unavailable: Port {} is in use\n",
-                                                port
-                                            )
-                                            .as_bytes(),
-                                        )
-                                        .await
-                                        .unwrap();
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                }
+                Ok(conn) => session::handshake::serve_connection(tunnel, conn).await,
                 Err(e) => eprintln!("Connection error: {e:?}"),
             }
         });
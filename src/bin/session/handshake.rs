@@ -0,0 +1,578 @@
+use super::session_registry::SessionRegistry;
+use super::tunnel_session::TunnelSession;
+use crate::admin::audit::{Actor, AuditLog, Outcome};
+use crate::admin::auth_guard::{self, AuthGuard, Refusal};
+use crate::admin::login;
+use crate::admin::node_store::NodeStore;
+use crate::admin::session_token::{SessionTokens, TokenError};
+use crate::pool::connection_guard::ConnectionGuard;
+use crate::pool::port_pool::{PortPool, StaticPortAssignResult};
+use crate::pool::port_registry::PortRegistry;
+use crate::pool::traffic::{QuotaState, TrafficStore};
+use crate::reverse_proxy::http_router::HttpRouter;
+use quinn::{Connection, SendStream};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use v_distributed_tunnel_v1::common::admin::client_config::ServiceProtocol;
+
+//Close code for a peer cut off after too many failed AUTH attempts
+const AUTH_FAILED: u32 = 2;
+
+/// Everything a node connection needs from the server, shared by all of them.
+pub struct TunnelContext {
+    pub node_store: Arc<NodeStore>,
+    pub port_pool: Arc<PortPool>,
+    pub port_registry: Arc<PortRegistry>,
+    pub http_router: Arc<HttpRouter>,
+    pub sessions: Arc<SessionRegistry>,
+    pub guard: Arc<ConnectionGuard>,
+    pub traffic: Arc<TrafficStore>,
+    pub auth_guard: Arc<AuthGuard>,
+    pub tokens: Arc<SessionTokens>,
+    pub audit: Arc<AuditLog>,
+    /// Whether a shared HTTP(S) listener is on, http services are then served through it.
+    pub shared_http: bool,
+}
+
+//How the first line of the client hello authenticates the node
+#[derive(Clone, Copy, PartialEq)]
+enum AuthMethod {
+    HashChain,
+    Password,
+    Token,
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthMethod::HashChain => write!(f, "hash chain"),
+            AuthMethod::Password => write!(f, "password"),
+            AuthMethod::Token => write!(f, "session token"),
+        }
+    }
+}
+
+//What came of one hello
+enum Hello {
+    //the node is in and was told so, its services are next
+    Authorized(String),
+    //answered and done with, e.g. an enrollment or a refusal that is nobody's failure
+    Answered,
+    //a failed attempt, it counts against the connection
+    Failed,
+}
+
+//One SERVICE line of the client hello
+struct ServiceRequest {
+    name: String,
+    protocol: ServiceProtocol,
+}
+
+/// Serve one node connection until it goes away.
+///
+/// Every bidirectional stream the client opens carries a hello, which it finishes after
+/// sending:
+/// ```text
+/// AUTH <node id> <hex preimage>      (or AUTH-PASSWORD / AUTH-TOKEN, or ENROLL)
+/// SERVICE <name> <tcp|http|udp>      (zero or more)
+/// ```
+/// A login that gets at least one service published turns into the node's tunnel session,
+/// which runs on that stream until the client disconnects.
+pub async fn serve_connection(ctx: Arc<TunnelContext>, conn: Connection) {
+    println!("Accepted new connection from {}", conn.remote_address());
+    let remote_ip = conn.remote_address().ip();
+    let mut failures = 0;
+    while let Ok((mut send_stream, mut recv_stream)) = conn.accept_bi().await {
+        let hello = match recv_stream.read_to_end(4096).await {
+            Ok(buf) if !buf.is_empty() => buf,
+            _ => continue, //Just skip if receive nothing from client
+        };
+        let hello = String::from_utf8_lossy(&hello);
+        let mut hello_lines = hello.lines();
+        let auth_line = hello_lines.next().unwrap_or_default().trim();
+        let result = match auth_line.strip_prefix("ENROLL ") {
+            Some(enroll) => ctx.enroll(enroll, &mut send_stream, remote_ip).await,
+            None => ctx.authenticate(auth_line, &mut send_stream, &conn).await,
+        };
+        match result {
+            Hello::Authorized(node_id) => {
+                let session = ctx
+                    .publish_services(&node_id, hello_lines, &conn, &mut send_stream)
+                    .await;
+                //MAIN SESSION LOOP
+                //runs until the client goes away, then closes listeners and releases everything
+                //The control stream stays open to tell the client about its traffic quota
+                if let Some(session) = session {
+                    session
+                        .run(send_stream, ctx.traffic.subscribe(&node_id))
+                        .await;
+                }
+            }
+            Hello::Answered => {}
+            Hello::Failed => {
+                if too_many_failures(&conn, &mut failures, &ctx.audit) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+//Every refused AUTH line counts against the connection, a peer that keeps guessing is cut off
+fn too_many_failures(conn: &Connection, failures: &mut u32, audit: &AuditLog) -> bool {
+    *failures += 1;
+    if *failures < auth_guard::MAX_FAILURES_PER_CONNECTION {
+        return false;
+    }
+    audit.record(
+        "auth_disconnected",
+        Actor::Client(conn.remote_address().ip()),
+        None,
+        Outcome::Refused,
+        "too many failed attempts on one connection",
+    );
+    conn.close(
+        AUTH_FAILED.into(),
+        b"too many failed authentication attempts",
+    );
+    true
+}
+
+impl TunnelContext {
+    //ENROLL <node id> <token secret> <hex anchor>, see `login::redeem_enrollment`
+    async fn enroll(&self, line: &str, send_stream: &mut SendStream, remote_ip: IpAddr) -> Hello {
+        let enrolled =
+            login::redeem_enrollment(line, &self.node_store, &self.auth_guard, remote_ip);
+        let result = match enrolled {
+            //the token is spent and the anchor set only once that is on disk
+            Ok(node_id) => match self.node_store.save_if_changed().await {
+                Ok(()) => {
+                    println!("Node '{}' enrolled", node_id);
+                    self.audit.record(
+                        "node_enrolled",
+                        Actor::Client(remote_ip),
+                        Some(&node_id),
+                        Outcome::Success,
+                        "enrollment token redeemed",
+                    );
+                    let _ = send_stream.write_all(b"Enrolled: Success\n").await;
+                    Hello::Answered
+                }
+                Err(e) => {
+                    eprintln!("Failed to save nodes: {}", e);
+                    let _ = send_stream
+                        .write_all(b"Unauthorized: The server could not save the enrollment\n")
+                        .await;
+                    Hello::Answered
+                }
+            },
+            Err((reason, known_node)) => {
+                let _ = send_stream
+                    .write_all(format!("Unauthorized: {}\n", reason).as_bytes())
+                    .await;
+                self.auth_guard
+                    .failed(known_node.as_deref(), remote_ip, &reason);
+                Hello::Failed
+            }
+        };
+        let _ = send_stream.finish();
+        result
+    }
+
+    //Check an AUTH, AUTH-PASSWORD or AUTH-TOKEN line. On success the login is saved, the client
+    //is told, gets a session token, and is asked for its next chain when this one runs out.
+    async fn authenticate(
+        &self,
+        auth_line: &str,
+        send_stream: &mut SendStream,
+        conn: &Connection,
+    ) -> Hello {
+        let remote_ip = conn.remote_address().ip();
+        //Header (AUTH), <node id> and <hex preimage>. A password node sends
+        //AUTH-PASSWORD <node id> <password> instead, and a node that logged in recently may
+        //send AUTH-TOKEN <node id> <session token>.
+        let parts: Vec<&str> = auth_line.splitn(3, ' ').collect();
+        let method = match parts[..] {
+            [_, _, _] => match parts[0] {
+                "AUTH" => Some(AuthMethod::HashChain),
+                "AUTH-PASSWORD" => Some(AuthMethod::Password),
+                "AUTH-TOKEN" => Some(AuthMethod::Token),
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(method) = method else {
+            let reply: &[u8] = if parts.len() < 3 {
+                b"Unauthorized: Auth line lack of arguments\n"
+            } else {
+                b"Unauthorized: Invalid auth header\n"
+            };
+            let _ = send_stream.write_all(reply).await;
+            self.auth_guard
+                .failed(None, remote_ip, "malformed auth line");
+            return Hello::Failed;
+        };
+        let node_id = parts[1].trim();
+        let credential = parts[2].trim();
+
+        let (is_authorized, rotate) = {
+            //held while the preimage is checked, a second attempt for the same node is refused meanwhile
+            let _attempt = match self.auth_guard.begin(node_id, remote_ip) {
+                Ok(attempt) => attempt,
+                Err(refusal) => {
+                    let (reply, reason) = match refusal {
+                        Refusal::LockedOut(remaining) => (
+                            format!(
+                                "Unauthorized: Too many failed attempts, retry in {}s\n",
+                                remaining.as_secs().max(1)
+                            ),
+                            "locked out",
+                        ),
+                        Refusal::InProgress => (
+                            "Unauthorized: Authentication already in progress\n".to_string(),
+                            "concurrent attempt for the same node",
+                        ),
+                    };
+                    let _ = send_stream.write_all(reply.as_bytes()).await;
+                    //not a failure of its own, the attempt never got to its credential
+                    self.audit.record(
+                        "auth_refused",
+                        Actor::Client(remote_ip),
+                        Some(node_id),
+                        Outcome::Refused,
+                        reason,
+                    );
+                    return Hello::Failed;
+                }
+            };
+            //Checked before the credential, so verifying never touches a node that
+            //is refused anyway (last login, anchor, rotation). It is the admin's
+            //doing, so it doesn't count as a failure either
+            if let Err(e) = self.node_store.check_access(node_id) {
+                self.audit.record(
+                    "auth_refused",
+                    Actor::Client(remote_ip),
+                    Some(node_id),
+                    Outcome::Refused,
+                    &e.to_string(),
+                );
+                let _ = send_stream
+                    .write_all(format!("Unauthorized: {}\n", e).as_bytes())
+                    .await;
+                return Hello::Answered;
+            }
+            match method {
+                AuthMethod::Password => {
+                    //Argon2 takes its time, keep it off the runtime
+                    let node_store = self.node_store.clone();
+                    let node_id = node_id.to_string();
+                    let password = credential.to_string();
+                    let authorized = tokio::task::spawn_blocking(move || {
+                        login::verify_password_node(&node_store, &node_id, &password)
+                    })
+                    .await
+                    .unwrap_or(false);
+                    (authorized, false)
+                }
+                AuthMethod::Token => {
+                    //a node that was removed takes its tokens with it
+                    let known = self.node_store.get_node(node_id.to_string()).is_some();
+                    match self.tokens.verify(node_id, credential) {
+                        Ok(()) if known => {
+                            self.node_store.set_last_login(node_id);
+                            (true, false)
+                        }
+                        Ok(()) => (false, false),
+                        //a genuine token that is just stale is no attack, the client
+                        //simply falls back to its chain or password
+                        Err(e @ (TokenError::Expired | TokenError::Revoked)) => {
+                            self.audit.record(
+                                "auth_refused",
+                                Actor::Client(remote_ip),
+                                Some(node_id),
+                                Outcome::Refused,
+                                &e.to_string(),
+                            );
+                            let _ = send_stream
+                                .write_all(format!("Unauthorized: {}\n", e).as_bytes())
+                                .await;
+                            return Hello::Answered;
+                        }
+                        Err(TokenError::Invalid) => (false, false),
+                    }
+                }
+                AuthMethod::HashChain => login::verify_node(&self.node_store, node_id, credential),
+            }
+        };
+        if !is_authorized {
+            let (reply, reason): (&[u8], _) = match method {
+                AuthMethod::Password => (
+                    b"Unauthorized: Invalid node id or password\n",
+                    "invalid node id or password",
+                ),
+                AuthMethod::Token => (
+                    b"Unauthorized: Invalid session token\n",
+                    "invalid session token",
+                ),
+                AuthMethod::HashChain => (
+                    b"Unauthorized: Invalid node id or preimage\n",
+                    "invalid node id or preimage",
+                ),
+            };
+            let _ = send_stream.write_all(reply).await;
+            let known_node = self
+                .node_store
+                .get_node(node_id.to_string())
+                .map(|_| node_id);
+            self.auth_guard.failed(known_node, remote_ip, reason);
+            return Hello::Failed;
+        }
+
+        //the new anchor has to be on disk first, a restart that forgot it
+        //would take the spent preimage again
+        if let Err(e) = self.node_store.save_if_changed().await {
+            eprintln!("Failed to save nodes: {}", e);
+            let _ = send_stream
+                .write_all(b"Unauthorized: The server could not record the login\n")
+                .await;
+            return Hello::Answered;
+        }
+        self.auth_guard.succeeded(node_id, remote_ip);
+        self.audit.record(
+            "login",
+            Actor::Client(remote_ip),
+            Some(node_id),
+            Outcome::Success,
+            &format!("logged in with {}", method),
+        );
+        if send_stream
+            .write_all(b"Authorized: Success\n")
+            .await
+            .is_err()
+        {
+            return Hello::Answered;
+        }
+        //lets the node reconnect without a fresh preimage for a while,
+        //a token login doesn't extend that
+        if method != AuthMethod::Token {
+            let _ = send_stream
+                .write_all(format!("TOKEN {}\n", self.tokens.issue(node_id)).as_bytes())
+                .await;
+        }
+        //asked again after every login until the node logs in with its next chain
+        if rotate {
+            login::request_rotation(conn, send_stream, &self.node_store, node_id, remote_ip).await;
+        }
+        Hello::Authorized(node_id.to_string())
+    }
+
+    //Answer every SERVICE line of the hello with ASSIGNED, SHARED or REFUSED, then READY.
+    //Returns the node's session, unless not a single service could be published.
+    async fn publish_services<'a>(
+        &self,
+        node_id: &str,
+        hello_lines: impl Iterator<Item = &'a str>,
+        conn: &Connection,
+        send_stream: &mut SendStream,
+    ) -> Option<TunnelSession> {
+        let remote_ip = conn.remote_address().ip();
+        let mut requests = parse_service_requests(hello_lines);
+        if requests.is_empty() {
+            //An old client that doesn't name its services publishes just one
+            requests.push(Ok(ServiceRequest {
+                name: "default".to_string(),
+                protocol: ServiceProtocol::Tcp,
+            }));
+        }
+        let quota = self.node_store.get_port_quota(node_id);
+        //Services are still published, but nothing gets relayed until the quota renews
+        let traffic_state = self.traffic.quota_state(node_id);
+        if traffic_state != QuotaState::Available {
+            let _ = send_stream
+                .write_all(format!("{}\n", traffic_state).as_bytes())
+                .await;
+        }
+
+        //The session owns the ports, registry entries and listeners of this connection
+        //and releases all of them together when the client is disconnected or something go wrong.
+        let mut session = TunnelSession::start(
+            node_id,
+            conn.clone(),
+            self.port_pool.clone(),
+            self.port_registry.clone(),
+            self.sessions.clone(),
+        )
+        .await;
+        let mut granted: Vec<String> = Vec::new();
+
+        for request in requests {
+            let request = match request {
+                Ok(request) => request,
+                Err(reason) => {
+                    let _ = send_stream
+                        .write_all(format!("REFUSED {}\n", reason).as_bytes())
+                        .await;
+                    continue;
+                }
+            };
+            if granted.contains(&request.name) {
+                let _ = send_stream
+                    .write_all(
+                        format!("REFUSED {} Duplicate service name\n", request.name).as_bytes(),
+                    )
+                    .await;
+                continue;
+            }
+            //Behind the shared listener, http services are reached by Host through
+            //the routing table, so they need no public port and don't count against the quota
+            if self.shared_http && request.protocol == ServiceProtocol::Http {
+                granted.push(request.name.clone());
+                println!(
+                    "Service '{}' of node '{}' is served by the shared HTTP listener",
+                    request.name, node_id
+                );
+                let _ = send_stream
+                    .write_all(format!("SHARED {}\n", request.name).as_bytes())
+                    .await;
+                continue;
+            }
+            if session.ports().len() >= quota {
+                self.audit.record(
+                    "port_refused",
+                    Actor::Client(remote_ip),
+                    Some(node_id),
+                    Outcome::Refused,
+                    &format!(
+                        "service '{}': port quota of {} reached",
+                        request.name, quota
+                    ),
+                );
+                let _ = send_stream
+                    .write_all(
+                        format!("REFUSED {} Port quota of {} reached\n", request.name, quota)
+                            .as_bytes(),
+                    )
+                    .await;
+                continue;
+            }
+
+            let port = match self.port_pool.assign_static_port(node_id, &request.name) {
+                StaticPortAssignResult::Success(port) => port,
+                StaticPortAssignResult::PoolExhausted => {
+                    self.audit.record(
+                        "port_refused",
+                        Actor::Client(remote_ip),
+                        Some(node_id),
+                        Outcome::Refused,
+                        &format!("service '{}': no free port left", request.name),
+                    );
+                    let _ = send_stream
+                        .write_all(
+                            format!("REFUSED {} No free port left\n", request.name).as_bytes(),
+                        )
+                        .await;
+                    continue;
+                }
+            };
+            session.add_port(port, &request.name, request.protocol);
+            granted.push(request.name.clone());
+            println!(
+                "Assigned port {} to service '{}' ({}) of node '{}'",
+                port, request.name, request.protocol, node_id
+            );
+            self.audit.record(
+                "port_assigned",
+                Actor::Client(remote_ip),
+                Some(node_id),
+                Outcome::Success,
+                &format!(
+                    "port {} to service '{}' ({})",
+                    port, request.name, request.protocol
+                ),
+            );
+
+            //Each assigned port will have it own listener
+            let cancel = session.cancel_token();
+            let tracker = session.tracker();
+            match request.protocol {
+                ServiceProtocol::Udp => {
+                    session.spawn_listener(crate::start_udp_listener_for_port(
+                        port,
+                        self.port_registry.clone(),
+                        self.guard.clone(),
+                        self.traffic.clone(),
+                        cancel,
+                        tracker,
+                    ));
+                }
+                protocol => {
+                    session.spawn_listener(crate::start_tcp_listener_for_port(
+                        port,
+                        protocol,
+                        self.port_registry.clone(),
+                        crate::forward::server_tunnel_handler::make_forward_fn(),
+                        self.http_router.clone(),
+                        self.guard.clone(),
+                        self.traffic.clone(),
+                        cancel,
+                        tracker,
+                    ));
+                }
+            }
+
+            //Send back protocal message ASSIGNED <service> <port>
+            let _ = send_stream
+                .write_all(format!("ASSIGNED {} {}\n", request.name, port).as_bytes())
+                .await;
+        }
+        //READY tells the client every service has been answered
+        let _ = send_stream.write_all(b"READY\n").await;
+
+        //nothing to serve, dropping the session gives back whatever it holds
+        (!granted.is_empty()).then_some(session)
+    }
+}
+
+//Parse the SERVICE lines following the AUTH line.
+//A malformed line becomes an Err carrying the "<name> <reason>" part of the REFUSED reply.
+fn parse_service_requests<'a>(
+    lines: impl Iterator<Item = &'a str>,
+) -> Vec<Result<ServiceRequest, String>> {
+    lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(
+            |line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["SERVICE", name, protocol] => match protocol.parse::<ServiceProtocol>() {
+                    Ok(protocol) => Ok(ServiceRequest {
+                        name: name.to_string(),
+                        protocol,
+                    }),
+                    Err(e) => Err(format!("{} {}", name, e)),
+                },
+                _ => Err(format!("- Invalid service line '{}'", line)),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_lines_are_parsed_or_refused() {
+        let hello = "SERVICE web http\n\n  SERVICE game udp \nSERVICE db postgres\nPING";
+        let requests = parse_service_requests(hello.lines());
+        assert_eq!(requests.len(), 4);
+        assert!(
+            matches!(&requests[0], Ok(r) if r.name == "web" && r.protocol == ServiceProtocol::Http)
+        );
+        assert!(
+            matches!(&requests[1], Ok(r) if r.name == "game" && r.protocol == ServiceProtocol::Udp)
+        );
+        assert!(matches!(&requests[2], Err(reason) if reason.starts_with("db ")));
+        assert!(matches!(&requests[3], Err(reason) if reason.contains("'PING'")));
+    }
+}
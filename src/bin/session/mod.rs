@@ -0,0 +1,4 @@
+pub mod handshake;
+pub mod service_health;
+pub mod session_registry;
+pub mod tunnel_session;
@@ -0,0 +1,36 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+
+/// Health of a node's services as its client last reported it.
+///
+/// The client sends `HEALTH <service> <local port> <up|down>` whenever one of its checks
+/// flips. Routes can name a service either way ("laptop_1:web" or "laptop_1:8080"), so the
+/// state is kept under both. Services without a check are never reported and count as healthy.
+#[derive(Clone, Default)]
+pub struct ServiceHealth {
+    healthy: Arc<DashMap<String, bool>>,
+}
+
+impl ServiceHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_healthy(&self, service: &str) -> bool {
+        self.healthy.get(service).is_none_or(|entry| *entry.value())
+    }
+
+    /// Apply one `HEALTH` line, returning the service name and its new state.
+    pub fn apply_report(&self, line: &str) -> Option<(String, bool)> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (name, port, healthy) = match parts.as_slice() {
+            ["HEALTH", name, port, "up"] => (*name, *port, true),
+            ["HEALTH", name, port, "down"] => (*name, *port, false),
+            _ => return None,
+        };
+        port.parse::<u16>().ok()?;
+        self.healthy.insert(name.to_string(), healthy);
+        self.healthy.insert(port.to_string(), healthy);
+        Some((name.to_string(), healthy))
+    }
+}
@@ -0,0 +1,64 @@
+use super::service_health::ServiceHealth;
+use dashmap::DashMap;
+use quinn::Connection;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// What the rest of the server may know about a live `TunnelSession`: enough to list it
+/// and to close it, without owning any of its resources.
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub id: u64,
+    pub node_id: String,
+    pub conn: Connection,
+    pub cancel: CancellationToken,
+    pub closed: CancellationToken,
+    pub tracker: TaskTracker, //Connections routed to this node are tracked here so the session can drain them
+    pub health: ServiceHealth,
+}
+
+impl SessionHandle {
+    /// Close the node's connection and wait until the session released all of its ports.
+    pub async fn close(&self, reason: &[u8]) {
+        self.conn.close(0u32.into(), reason);
+        self.cancel.cancel();
+        self.closed.cancelled().await;
+    }
+}
+
+//Live sessions by node id. A node has at most one.
+pub struct SessionRegistry {
+    sessions: DashMap<String, SessionHandle>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self {
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<SessionHandle> {
+        self.sessions
+            .get(node_id)
+            .map(|entry| entry.value().clone())
+    }
+
+    pub fn list(&self) -> Vec<SessionHandle> {
+        self.sessions
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    /// Register a new session and hand back the one it replaces, if any.
+    pub fn replace(&self, handle: SessionHandle) -> Option<SessionHandle> {
+        self.sessions.insert(handle.node_id.clone(), handle)
+    }
+
+    //Only forget the node's session if it is still this one, a newer session may have replaced it
+    pub fn remove(&self, node_id: &str, session_id: u64) {
+        self.sessions
+            .remove_if(node_id, |_, handle| handle.id == session_id);
+    }
+}
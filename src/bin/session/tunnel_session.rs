@@ -0,0 +1,242 @@
+use super::service_health::ServiceHealth;
+use super::session_registry::{SessionHandle, SessionRegistry};
+use crate::pool::port_pool::{PortGuard, PortPool};
+use crate::pool::port_registry::{NodeInfo, PortRegistry};
+use crate::pool::traffic::QuotaState;
+use quinn::{Connection, SendStream};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use v_distributed_tunnel_v1::common::admin::client_config::ServiceProtocol;
+
+//How long a finished session waits for connections that are still being relayed
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Everything one authenticated node connection holds on the server.
+///
+/// The session owns its public listeners, its `PortRegistry` entries and the `PortGuard`
+/// of its ports. `shutdown` tears them down in an order that is safe for a node that
+/// reconnects right away:
+/// 1. the registry entries and the `SessionRegistry` entry go first, so nothing new is
+///    routed to the dead connection,
+/// 2. the listeners are cancelled and awaited, so their ports are unbound,
+/// 3. connections still being relayed get `DRAIN_TIMEOUT` to finish,
+/// 4. the guard returns the ports to the pool, now that nobody is bound to them.
+///
+/// A node has at most one session. When it reconnects before the server noticed the old
+/// connection is gone, `start` closes the stale session and waits for it to finish, so the
+/// node gets its ports back instead of a `Failed to bind`.
+pub struct TunnelSession {
+    pub id: u64,
+    pub node_id: String,
+    conn: Connection,
+    port_registry: Arc<PortRegistry>,
+    sessions: Arc<SessionRegistry>,
+    port_guard: Option<PortGuard>,
+    listeners: Vec<JoinHandle<()>>,
+    cancel: CancellationToken,
+    closed: CancellationToken, //fired once everything is released
+    tracker: TaskTracker,
+    health: ServiceHealth, //updated from the client's HEALTH reports
+}
+
+impl TunnelSession {
+    /// Open the session of a node that just authenticated, replacing its previous one.
+    pub async fn start(
+        node_id: &str,
+        conn: Connection,
+        port_pool: Arc<PortPool>,
+        port_registry: Arc<PortRegistry>,
+        sessions: Arc<SessionRegistry>,
+    ) -> Self {
+        let session = Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            node_id: node_id.to_string(),
+            conn,
+            port_registry,
+            sessions,
+            port_guard: Some(PortGuard {
+                port_pool,
+                ports: Vec::new(),
+                node_id: node_id.to_string(),
+            }),
+            listeners: Vec::new(),
+            cancel: CancellationToken::new(),
+            closed: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+            health: ServiceHealth::new(),
+        };
+        if let Some(stale) = session.sessions.replace(session.handle()) {
+            println!(
+                "Node '{}' reconnected, closing its previous session {}",
+                node_id, stale.id
+            );
+            stale.close(b"superseded by a new session").await;
+        }
+        session
+    }
+
+    fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            id: self.id,
+            node_id: self.node_id.clone(),
+            conn: self.conn.clone(),
+            cancel: self.cancel.clone(),
+            closed: self.closed.clone(),
+            tracker: self.tracker.clone(),
+            health: self.health.clone(),
+        }
+    }
+
+    /// Take ownership of a port the pool just assigned and make it routable.
+    pub fn add_port(&mut self, port: u16, service: &str, protocol: ServiceProtocol) {
+        if let Some(guard) = self.port_guard.as_mut() {
+            guard.ports.push(port);
+        }
+        self.port_registry.insert(
+            port,
+            NodeInfo {
+                conn: self.conn.clone(),
+                node_id: self.node_id.clone(),
+                service: service.to_string(),
+                protocol,
+                session_id: self.id,
+                health: self.health.clone(),
+            },
+        );
+    }
+
+    pub fn ports(&self) -> &[u16] {
+        self.port_guard
+            .as_ref()
+            .map(|guard| guard.ports.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Listeners stop accepting as soon as this token is cancelled.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Listeners spawn every accepted connection on this tracker so `shutdown` can drain them.
+    pub fn tracker(&self) -> TaskTracker {
+        self.tracker.clone()
+    }
+
+    pub fn spawn_listener(&mut self, listener: impl Future<Output = ()> + Send + 'static) {
+        self.listeners.push(tokio::spawn(listener));
+    }
+
+    /// Serve the session until the node goes away, then tear everything down.
+    ///
+    /// `control` is the server's side of the control stream, every change of the node's
+    /// traffic quota is written to it as a `QUOTA ...` line.
+    pub async fn run(self, mut control: SendStream, mut quota: watch::Receiver<QuotaState>) {
+        quota.mark_unchanged(); //the client was told the current state before READY
+        let mut quota_open = true;
+        //accept new streams from client as long as session is alive
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                res = quota.changed(), if quota_open => {
+                    if res.is_err() {
+                        //the node's traffic record is gone, nothing more to report
+                        quota_open = false;
+                        continue;
+                    }
+                    let state = *quota.borrow_and_update();
+                    println!("Node '{}': {}", self.node_id, state);
+                    let _ = control.write_all(format!("{}\n", state).as_bytes()).await;
+                }
+                res = self.conn.accept_bi() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    //ignore rn
+                }
+                res = self.conn.accept_uni() => {
+                    //the client reports health check results on unidirectional streams
+                    let Ok(mut recv_stream) = res else {
+                        break;
+                    };
+                    let health = self.health.clone();
+                    let node_id = self.node_id.clone();
+                    self.tracker.spawn(async move {
+                        let Ok(report) = recv_stream.read_to_end(256).await else {
+                            return;
+                        };
+                        let report = String::from_utf8_lossy(&report);
+                        match health.apply_report(report.trim()) {
+                            Some((service, healthy)) => println!(
+                                "Service '{}' of node '{}' is {}",
+                                service,
+                                node_id,
+                                if healthy { "healthy" } else { "unhealthy" }
+                            ),
+                            None => eprintln!(
+                                "Invalid health report from node '{}': {}",
+                                node_id,
+                                report.trim()
+                            ),
+                        }
+                    });
+                }
+            }
+        }
+        self.shutdown().await;
+    }
+
+    pub async fn shutdown(mut self) {
+        let ports = self.port_registry.remove_session(self.id);
+        self.sessions.remove(&self.node_id, self.id);
+
+        self.cancel.cancel();
+        for listener in self.listeners.drain(..) {
+            let _ = listener.await;
+        }
+
+        self.tracker.close();
+        if tokio::time::timeout(DRAIN_TIMEOUT, self.tracker.wait())
+            .await
+            .is_err()
+        {
+            eprintln!(
+                "Session {} of node '{}': {} connections still open after {:?}, dropping them",
+                self.id,
+                self.node_id,
+                self.tracker.len(),
+                DRAIN_TIMEOUT
+            );
+        }
+        self.conn.close(0u32.into(), b"session closed");
+
+        //the port guard hands the ports back to the pool
+        drop(self.port_guard.take());
+        self.closed.cancel();
+        println!(
+            "Session {} of node '{}' closed, unregistered ports {:?}",
+            self.id, self.node_id, ports
+        );
+    }
+}
+
+//Safety net for a session that is dropped without `shutdown`, e.g. on panic.
+//Listeners are only told to stop, so their ports may still be bound for a moment.
+impl Drop for TunnelSession {
+    fn drop(&mut self) {
+        if !self.closed.is_cancelled() {
+            self.cancel.cancel();
+            self.port_registry.remove_session(self.id);
+            drop(self.port_guard.take());
+            self.sessions.remove(&self.node_id, self.id);
+            self.closed.cancel();
+        }
+    }
+}
@@ -1,66 +1,339 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use rustls::RootCertStore;
+use rustls_pki_types::ServerName;
+use serde_json::{Value, json};
+use std::fs::File;
 use std::io::{self, Write};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+//Exit codes for scripts, clap itself exits with 2 on a usage error
+const EXIT_ERROR: u8 = 1;
+const EXIT_NOT_FOUND: u8 = 3;
+const EXIT_UNREACHABLE: u8 = 4;
+const EXIT_CONFLICT: u8 = 5;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Tunnel admin CLI for QUIC nodes", long_about = None)]
 struct Args {
-    #[arg()]
-    command: Vec<String>,
+    /// Admin endpoint of the server, host:port
+    #[arg(
+        long,
+        env = "TUNNEL_ADMIN_ENDPOINT",
+        default_value = "127.0.0.1:6969",
+        global = true
+    )]
+    endpoint: String,
+
+    /// The server's ADMIN_TOKEN, required unless it only listens on loopback
+    #[arg(
+        long,
+        env = "TUNNEL_ADMIN_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    token: Option<String>,
+
+    /// Speak TLS to the endpoint, always on for an endpoint other than loopback
+    #[arg(long, global = true)]
+    tls: bool,
+
+    /// Certificate to trust for TLS, e.g. the server's self-signed cert.pem
+    #[arg(long, default_value = "cert.pem", global = true)]
+    ca: String,
+
+    /// Name the server's certificate is issued for
+    #[arg(long, default_value = "localhost", global = true)]
+    server_name: String,
+
+    #[arg(long, short, value_enum, default_value_t = Output::Table, global = true)]
+    output: Output,
+
+    /// Without a command the CLI starts in interactive mode
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Provision, inspect and remove nodes
+    #[command(subcommand)]
+    Node(NodeCommand),
+    /// Host/path routes of the HTTP edge
+    #[command(subcommand)]
+    Route(RouteCommand),
+    /// Live node sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Any command of the interactive mode, e.g. `tunnel_admin quota node1 3`
+    #[command(external_subcommand)]
+    Raw(Vec<String>),
+}
+
+#[derive(Subcommand, Debug)]
+enum NodeCommand {
+    /// Add a node with a hash chain and print its enrollment token, or a password node
+    Add {
+        node_id: String,
+        /// Logins the hash chain is good for before it is rotated
+        #[arg(long, conflicts_with_all = ["password", "password_hash"])]
+        chain_length: Option<usize>,
+        /// Add a password node with a generated password, shown once
+        #[arg(long, conflicts_with = "password_hash")]
+        password: bool,
+        /// Add a password node from an Argon2id hash
+        #[arg(long)]
+        password_hash: Option<String>,
+    },
+    /// Remove a node, its leases and traffic records
+    #[command(alias = "remove")]
+    Rm { node_id: String },
+    /// Show one node
+    Show { node_id: String },
+    /// List nodes, optionally only those carrying every --label key=value
+    List {
+        #[arg(long = "label")]
+        labels: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RouteCommand {
+    /// List every route
+    List,
+    /// Route a host (`*.domain` for a wildcard, `~regex` for a pattern) and path prefix to a
+    /// backend, e.g. `node1:web`
+    Add {
+        host: String,
+        path: String,
+        backend: String,
+    },
+    /// Remove the routes of a host with this path
+    #[command(alias = "remove")]
+    Rm { host: String, path: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionCommand {
+    /// List the nodes that are connected right now
+    List,
+    /// Close the session of a node
+    Kick { node_id: String },
+    /// Revoke the session tokens of a node, so it has to log in with its chain or password again
+    Revoke { node_id: String },
+}
+
+impl Command {
+    //The admin command line the server understands
+    fn admin_line(&self) -> String {
+        let words: Vec<String> = match self {
+            Command::Node(NodeCommand::Add {
+                node_id,
+                chain_length,
+                password,
+                password_hash,
+            }) => {
+                let mut words = vec!["add".to_string(), node_id.clone()];
+                if *password {
+                    words.push("password".to_string());
+                } else if let Some(hash) = password_hash {
+                    words.push(hash.clone());
+                } else if let Some(length) = chain_length {
+                    words.push(length.to_string());
+                }
+                words
+            }
+            Command::Node(NodeCommand::Rm { node_id }) => vec!["remove".into(), node_id.clone()],
+            Command::Node(NodeCommand::Show { node_id }) => vec!["view".into(), node_id.clone()],
+            Command::Node(NodeCommand::List { labels }) => std::iter::once("list".to_string())
+                .chain(labels.clone())
+                .collect(),
+            Command::Route(RouteCommand::List) => vec!["routes".into()],
+            Command::Route(RouteCommand::Add {
+                host,
+                path,
+                backend,
+            }) => vec![
+                "route".into(),
+                "add".into(),
+                host.clone(),
+                path.clone(),
+                backend.clone(),
+            ],
+            Command::Route(RouteCommand::Rm { host, path }) => {
+                vec!["route".into(), "rm".into(), host.clone(), path.clone()]
+            }
+            Command::Session(SessionCommand::List) => vec!["sessions".into()],
+            Command::Session(SessionCommand::Kick { node_id }) => {
+                vec!["kick".into(), node_id.clone()]
+            }
+            Command::Session(SessionCommand::Revoke { node_id }) => {
+                vec!["revoke".into(), node_id.clone()]
+            }
+            Command::Raw(words) => words.clone(),
+        };
+        words.join(" ")
+    }
+}
+
+trait AdminStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AdminStream for T {}
+
+//Connect, with TLS for anything but loopback
+async fn connect(args: &Args) -> anyhow::Result<BufReader<Box<dyn AdminStream>>> {
+    let tcp = TcpStream::connect(&args.endpoint).await?;
+    let stream: Box<dyn AdminStream> = if args.tls || !tcp.peer_addr()?.ip().is_loopback() {
+        let mut roots = RootCertStore::empty();
+        let mut reader = io::BufReader::new(File::open(&args.ca)?);
+        for cert in rustls_pemfile::certs(&mut reader) {
+            roots.add(cert?)?;
+        }
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let server_name = ServerName::try_from(args.server_name.clone())?;
+        Box::new(
+            TlsConnector::from(Arc::new(config))
+                .connect(server_name, tcp)
+                .await?,
+        )
+    } else {
+        Box::new(tcp)
+    };
+    Ok(BufReader::new(stream))
+}
+
+//Send one command and read its reply up to --END--
+async fn send(
+    stream: &mut BufReader<Box<dyn AdminStream>>,
+    command: &str,
+) -> anyhow::Result<String> {
+    stream.get_mut().write_all(command.as_bytes()).await?;
+    stream.get_mut().write_all(b"\n").await?;
+    stream.get_mut().flush().await?;
+    let mut reply = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            anyhow::bail!("The server closed the admin connection");
+        }
+        if line.trim_end() == "--END--" {
+            return Ok(reply);
+        }
+        reply.push_str(&line);
+    }
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     let args = Args::parse();
+    let mut stream = match connect(&args).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Cannot reach the admin endpoint {}: {}", args.endpoint, e);
+            return ExitCode::from(EXIT_UNREACHABLE);
+        }
+    };
+    if let Some(token) = &args.token {
+        match send(&mut stream, &format!("AUTH-ADMIN {}", token)).await {
+            Ok(reply) if reply.starts_with("OK:") => {}
+            Ok(reply) => {
+                eprint!("{}", reply);
+                return ExitCode::from(EXIT_ERROR);
+            }
+            Err(e) => {
+                eprintln!("Admin connection failed: {}", e);
+                return ExitCode::from(EXIT_UNREACHABLE);
+            }
+        }
+    }
+    let result = match &args.command {
+        Some(command) => run_command(&mut stream, command, args.output).await,
+        None => interactive(&mut stream, &args.endpoint).await,
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Admin connection failed: {}", e);
+            ExitCode::from(EXIT_UNREACHABLE)
+        }
+    }
+}
 
-    let mut stream = TcpStream::connect("127.0.0.1:6969").await?;
-    let (reader, mut writer) = stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
-    let mut response = String::new();
-
-    println!("Connected to admin port on 127.0.0.1:6969");
-
-    if !args.command.is_empty() {
-        let cmd = args.command.join(" ");
-        writer.write_all(cmd.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
-        loop {
-            response.clear();
-            reader.read_line(&mut response).await?;
-            if response.trim_end() == "--END--" {
-                break;
+async fn run_command(
+    stream: &mut BufReader<Box<dyn AdminStream>>,
+    command: &Command,
+    output: Output,
+) -> anyhow::Result<ExitCode> {
+    let text = send(stream, &format!("json {}", command.admin_line())).await?;
+    //e.g. the server wants an admin token and none was given
+    let Ok(reply) = serde_json::from_str::<Value>(&text) else {
+        eprint!("{}", text);
+        return Ok(ExitCode::from(EXIT_ERROR));
+    };
+    let status = reply["status"].as_str().unwrap_or("error");
+    match output {
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({
+                "status": status,
+                "message": reply["message"],
+                "data": reply["data"],
+            }))?
+        ),
+        Output::Table => {
+            let text = reply["output"].as_str().unwrap_or_default();
+            if status == "ok" {
+                print!("{}", text);
+            } else {
+                eprint!("{}", text);
             }
-            print!("{}", response);
         }
-        return Ok(());
     }
+    Ok(match status {
+        "ok" => ExitCode::SUCCESS,
+        "not_found" => ExitCode::from(EXIT_NOT_FOUND),
+        "conflict" => ExitCode::from(EXIT_CONFLICT),
+        _ => ExitCode::from(EXIT_ERROR),
+    })
+}
 
-    //Here is our interactive mode
+//Here is our interactive mode
+async fn interactive(
+    stream: &mut BufReader<Box<dyn AdminStream>>,
+    endpoint: &str,
+) -> anyhow::Result<ExitCode> {
+    println!("Connected to admin port on {}", endpoint);
     println!(
         "Cast the spell, e.g.:create/add node123 $argon2id$...\ndestroy/remove/delete node123\nview node123\nlist\nType 'exit' or 'quit' to quit.\n"
     );
 
+    let mut line = String::new();
     loop {
         print!("dugeon-master> ");
         io::stdout().flush().unwrap();
         line.clear();
-        io::stdin().read_line(&mut line)?;
+        if io::stdin().read_line(&mut line)? == 0 {
+            break;
+        }
         let spell = line.trim();
         if spell == "exit" || spell == "quit" {
             break;
         }
-        writer.write_all(spell.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
-        loop {
-            response.clear();
-            reader.read_line(&mut response).await?;
-            if response.trim_end() == "--END--" {
-                break;
-            }
-            print!("{}", response);
-        }
+        print!("{}", send(stream, spell).await?);
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
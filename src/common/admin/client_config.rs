@@ -1,24 +1,164 @@
+use crate::common::helper::seed_cipher::{ChainKey, SealedChain};
 use rand_core::RngCore;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize)]
+/// How the server exposes a service on its public port.
+///
+/// * `Tcp`  - every connection is forwarded as-is to the service.
+/// * `Http` - the request is routed by Host/path through the routing table first.
+/// * `Udp`  - datagrams are relayed, one QUIC stream per remote peer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceProtocol {
+    Tcp,
+    Http,
+    Udp,
+}
+
+impl fmt::Display for ServiceProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceProtocol::Tcp => write!(f, "tcp"),
+            ServiceProtocol::Http => write!(f, "http"),
+            ServiceProtocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for ServiceProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(ServiceProtocol::Tcp),
+            "http" => Ok(ServiceProtocol::Http),
+            "udp" => Ok(ServiceProtocol::Udp),
+            other => Err(format!("unknown protocol '{}'", other)),
+        }
+    }
+}
+
+//A local service the client wants to publish, e.g.
+// [[service]]
+// name = "web"
+// local_port = 8080
+// protocol = "http"
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub local_port: u16,
+    pub protocol: ServiceProtocol,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+//Optional check the client runs against its own local service, e.g.
+// [service.health_check]
+// http_path = "/healthz"   # leave out for a plain TCP connect
+// interval_secs = 5
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HealthCheckConfig {
+    //GET this path and expect a 2xx/3xx answer. Without it, a TCP connect is enough.
+    #[serde(default)]
+    pub http_path: Option<String>,
+    #[serde(default = "HealthCheckConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "HealthCheckConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    //consecutive successes before a down service counts as up again
+    #[serde(default = "HealthCheckConfig::default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    //consecutive failures before an up service counts as down
+    #[serde(default = "HealthCheckConfig::default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+impl HealthCheckConfig {
+    fn default_interval_secs() -> u64 {
+        10
+    }
+
+    fn default_timeout_secs() -> u64 {
+        2
+    }
+
+    fn default_healthy_threshold() -> u32 {
+        2
+    }
+
+    fn default_unhealthy_threshold() -> u32 {
+        3
+    }
+}
+
+impl ServiceConfig {
+    /// The single service every client had before services were configurable: raw TCP to 8080.
+    pub fn default_services() -> Vec<ServiceConfig> {
+        vec![ServiceConfig {
+            name: "default".to_string(),
+            local_port: 8080,
+            protocol: ServiceProtocol::Tcp,
+            health_check: None,
+        }]
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ClientConfig {
     pub node_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")] //empty on disk while sealed
     pub seed: String, //both of these two props are required for reverse hash chain
     pub current_index: usize,
     pub chain_length: usize,
+    /// Seed of the next chain, generated when the server asked for one with `ROTATE`. It
+    /// replaces `seed` once the server confirmed it has the chain's anchor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_seed: Option<String>,
+    /// hash^(k*interval)(seed) for k = 1, 2, ... up to `current_index`, with interval about
+    /// sqrt(chain_length). The next preimage is at most one interval of hashing away from one
+    /// of them, so long chains stay cheap to walk without keeping the whole chain around.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checkpoints: Vec<String>,
+    /// Token from the last login, lets us reconnect without spending a preimage until it expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+    /// `seed`, `pending_seed`, `checkpoints` and `session_token` encrypted under a passphrase, see `encrypt_with`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedChain>,
+    #[serde(default = "ServiceConfig::default_services", rename = "service")]
+    pub services: Vec<ServiceConfig>,
+    #[serde(skip)]
+    key: Option<ChainKey>,
+}
+
+//What gets sealed: everything a preimage can be computed from
+#[derive(Serialize, Deserialize)]
+struct ChainSecrets {
+    seed: String,
+    pending_seed: Option<String>,
+    checkpoints: Vec<String>,
+    #[serde(default)]
+    session_token: Option<String>,
 }
 
 impl ClientConfig {
     pub fn new(node_id: String, seed: String, current_index: usize, chain_length: usize) -> Self {
-        Self {
+        let mut config = Self {
             node_id,
             seed,
             current_index,
             chain_length,
-        }
+            pending_seed: None,
+            checkpoints: Vec::new(),
+            session_token: None,
+            sealed: None,
+            services: ServiceConfig::default_services(),
+            key: None,
+        };
+        config.build_checkpoints();
+        config
     }
 
     //This function generate 32bytes random using OS random number generator (which is not so random :'( )
@@ -33,10 +173,247 @@ impl ClientConfig {
         hex::encode(seed)
     }
 
-    pub fn write_toml_file(config: &ClientConfig) -> std::io::Result<()> {
-        let toml = toml::to_string(config).unwrap();
-        let mut file = File::create("config.toml")?;
-        file.write_all(toml.as_bytes())?;
+    /// Whether the chain on disk is encrypted and has to be `unseal`ed before use.
+    pub fn is_sealed(&self) -> bool {
+        self.sealed.is_some() && self.seed.is_empty()
+    }
+
+    /// Decrypt the chain of a config loaded from disk. The key is kept, so saves stay encrypted.
+    pub fn unseal(&mut self, passphrase: &str) -> anyhow::Result<()> {
+        let Some(sealed) = &self.sealed else {
+            return Ok(());
+        };
+        let key = ChainKey::for_sealed(passphrase, sealed)?;
+        let secrets: ChainSecrets = serde_json::from_slice(&key.open(sealed)?)?;
+        self.seed = secrets.seed;
+        self.pending_seed = secrets.pending_seed;
+        self.checkpoints = secrets.checkpoints;
+        self.session_token = secrets.session_token;
+        self.key = Some(key);
         Ok(())
     }
+
+    /// Encrypt the chain under `passphrase` from the next save on.
+    pub fn encrypt_with(&mut self, passphrase: &str) -> anyhow::Result<()> {
+        self.key = Some(ChainKey::new(passphrase)?);
+        Ok(())
+    }
+
+    /// The config as it goes to disk: with a key, the chain is sealed and left out in plain.
+    pub fn for_disk(&self) -> ClientConfig {
+        let mut disk = self.clone();
+        if let Some(key) = &self.key {
+            let secrets = ChainSecrets {
+                seed: std::mem::take(&mut disk.seed),
+                pending_seed: disk.pending_seed.take(),
+                checkpoints: std::mem::take(&mut disk.checkpoints),
+                session_token: disk.session_token.take(),
+            };
+            let plaintext = serde_json::to_vec(&secrets).expect("secrets always serialize");
+            disk.sealed = Some(key.seal(&plaintext));
+        }
+        disk
+    }
+
+    /// Whether there is a session token that has not expired yet. Its second field is the
+    /// expiry in unix milliseconds, whether it is still good is for the server to say.
+    pub fn has_session_token(&self) -> bool {
+        let Some(token) = &self.session_token else {
+            return false;
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        token
+            .split('.')
+            .nth(1)
+            .and_then(|expires| expires.parse::<u64>().ok())
+            .is_some_and(|expires| expires > now)
+    }
+
+    /// The preimage for the next login, hash^current_index(seed).
+    pub fn preimage(&self) -> String {
+        //start from the closest checkpoint below current_index, or the seed if there is none
+        let interval = self.checkpoint_interval();
+        let passed = (self.current_index / interval).min(self.checkpoints.len());
+        let start = match passed {
+            0 => &self.seed,
+            k => &self.checkpoints[k - 1],
+        };
+        hex::encode(chain_hash(start, self.current_index - passed * interval))
+    }
+
+    /// Walk the chain once to lay down the checkpoints up to `current_index`.
+    pub fn build_checkpoints(&mut self) {
+        let interval = self.checkpoint_interval();
+        self.checkpoints.clear();
+        let mut hash = hex::decode(&self.seed).expect("Invalid hex seed");
+        for step in 1..=self.current_index {
+            hash = blake3::hash(&hash).as_bytes().to_vec();
+            if step % interval == 0 {
+                self.checkpoints.push(hex::encode(&hash));
+            }
+        }
+    }
+
+    /// Whether the checkpoints are missing, e.g. in a config written by an older version.
+    pub fn needs_checkpoints(&self) -> bool {
+        self.checkpoints.len() < self.current_index / self.checkpoint_interval()
+    }
+
+    fn checkpoint_interval(&self) -> usize {
+        self.chain_length.isqrt().max(1)
+    }
+
+    /// Step back one link once `preimage` is spent.
+    pub fn advance(&mut self) {
+        self.current_index = self.current_index.saturating_sub(1);
+        //checkpoints past the current index are spent, nothing is ever hashed from them again
+        let keep = self.current_index / self.checkpoint_interval();
+        self.checkpoints.truncate(keep);
+    }
+
+    /// hash^chain_length(seed), what the server checks the first preimage against.
+    pub fn anchor(&self) -> String {
+        hex::encode(chain_hash(&self.seed, self.chain_length))
+    }
+
+    /// Generate the seed of our next chain after a `ROTATE`, unless one is still waiting for
+    /// the server's confirmation: proposing the same chain again is what makes a rotation
+    /// that was interrupted halfway safe to retry.
+    pub fn propose(&mut self) {
+        if self.pending_seed.is_none() {
+            self.pending_seed = Some(Self::encode_seed(&Self::generate_seed()));
+        }
+    }
+
+    /// hash^chain_length(pending_seed), what the server checks the first preimage of the
+    /// next chain against. Up to `chain_length` hashes, keep it off the runtime.
+    pub fn pending_anchor(&self) -> Option<String> {
+        self.pending_seed
+            .as_ref()
+            .map(|seed| hex::encode(chain_hash(seed, self.chain_length)))
+    }
+
+    /// The server has the anchor of the proposed chain, switch over to it. Nothing of it is
+    /// used yet.
+    pub fn commit_rotation(&mut self) {
+        if let Some(pending) = self.pending_seed.take() {
+            self.seed = pending;
+            self.current_index = self.chain_length - 1;
+            self.build_checkpoints();
+        }
+    }
+}
+
+//hash^steps(seed), seed given in hex
+fn chain_hash(seed: &str, steps: usize) -> Vec<u8> {
+    let mut hash = hex::decode(seed).expect("Invalid hex seed");
+    for _ in 0..steps {
+        hash = blake3::hash(&hash).as_bytes().to_vec();
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //hash^steps(seed) the slow way, what every preimage is checked against
+    fn naive(seed: &str, steps: usize) -> String {
+        hex::encode(chain_hash(seed, steps))
+    }
+
+    fn config(chain_length: usize) -> ClientConfig {
+        let seed = ClientConfig::encode_seed(&ClientConfig::generate_seed());
+        ClientConfig::new("node1".into(), seed, chain_length - 1, chain_length)
+    }
+
+    #[test]
+    fn checkpoints_sit_every_interval() {
+        let config = config(100);
+        //sqrt(100) = 10: hash^10, hash^20, ... hash^90, nothing at or past current_index 99
+        assert_eq!(config.checkpoints.len(), 9);
+        for (k, checkpoint) in config.checkpoints.iter().enumerate() {
+            assert_eq!(*checkpoint, naive(&config.seed, (k + 1) * 10));
+        }
+        assert!(!config.needs_checkpoints());
+
+        let mut old = config.clone();
+        old.checkpoints.clear();
+        assert!(old.needs_checkpoints());
+        old.build_checkpoints();
+        assert_eq!(old.checkpoints, config.checkpoints);
+    }
+
+    #[test]
+    fn every_preimage_matches_the_chain() {
+        let mut config = config(150);
+        assert_eq!(config.anchor(), naive(&config.seed, 150));
+        let mut previous = config.anchor();
+        while config.current_index > 0 {
+            let preimage = config.preimage();
+            assert_eq!(preimage, naive(&config.seed, config.current_index));
+            //each preimage hashes to the one spent before it
+            assert_eq!(
+                hex::encode(blake3::hash(&hex::decode(&preimage).unwrap()).as_bytes()),
+                previous
+            );
+            previous = preimage;
+            config.advance();
+            //spent checkpoints are dropped
+            assert!(config.checkpoints.len() <= config.current_index / 12);
+        }
+        assert_eq!(config.preimage(), config.seed);
+    }
+
+    #[test]
+    fn sealed_config_round_trips() {
+        let mut config = config(100);
+        config.session_token = Some("token".into());
+        config.encrypt_with("correct horse").unwrap();
+        let disk = config.for_disk();
+        //nothing a preimage can be computed from is left in plain
+        assert!(disk.seed.is_empty() && disk.checkpoints.is_empty());
+        assert!(disk.session_token.is_none());
+        let toml = toml::to_string(&disk).unwrap();
+        assert!(!toml.contains(&config.seed));
+
+        let mut loaded: ClientConfig = toml::from_str(&toml).unwrap();
+        assert!(loaded.is_sealed());
+        assert!(loaded.unseal("battery staple").is_err());
+        assert!(loaded.is_sealed());
+        loaded.unseal("correct horse").unwrap();
+        assert!(!loaded.is_sealed());
+        assert_eq!(loaded.seed, config.seed);
+        assert_eq!(loaded.checkpoints, config.checkpoints);
+        assert_eq!(loaded.session_token.as_deref(), Some("token"));
+        //and it stays sealed on the next save
+        assert!(loaded.for_disk().seed.is_empty());
+    }
+
+    #[test]
+    fn rotation_switches_only_once_confirmed() {
+        let mut config = config(100);
+        for _ in 0..90 {
+            config.advance();
+        }
+        let old_seed = config.seed.clone();
+        config.propose();
+        let pending = config.pending_seed.clone().unwrap();
+        //asked again before the server confirmed: the same chain is proposed
+        config.propose();
+        assert_eq!(config.pending_seed.as_ref(), Some(&pending));
+        assert_eq!(config.pending_anchor(), Some(naive(&pending, 100)));
+        //until then we keep logging in with the old chain
+        assert_eq!(config.preimage(), naive(&old_seed, 9));
+
+        config.commit_rotation();
+        assert_eq!(config.seed, pending);
+        assert!(config.pending_seed.is_none());
+        assert_eq!(config.current_index, 99);
+        assert_eq!(config.preimage(), naive(&pending, 99));
+        assert!(!config.needs_checkpoints());
+    }
 }
@@ -1,12 +1,84 @@
 use crate::common::admin::client_config::ClientConfig;
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
 
-pub fn load_config(path: &str) -> ClientConfig {
-    let data = fs::read_to_string(path).expect("Failed to read config file");
-    toml::from_str(&data).expect("Invalid config TOML")
+/// Read and parse the config file. The error names the file and what is wrong with it.
+pub fn load_config(path: &str) -> io::Result<ClientConfig> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", path, e)))?;
+    toml::from_str(&data).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid config in {}: {}", path, e),
+        )
+    })
 }
 
-pub fn save_config(path: &str, config: &ClientConfig) {
-    let toml = toml::to_string(config).expect("Failed to serialize config");
-    fs::write(path, toml).expect("Failed to write config file");
+/// Replace the config file atomically, see `write_atomic`.
+pub fn save_config(path: &str, config: &ClientConfig) -> io::Result<()> {
+    let toml = toml::to_string(&config.for_disk()).map_err(io::Error::other)?;
+    write_atomic(Path::new(path), toml.as_bytes())
+}
+
+/// Replace a file atomically: the new content goes to a temp file next to it, is synced to
+/// disk and then renamed over the old one. A crash at any point leaves either the old or the
+/// new file, never a truncated one. The file is readable by its owner only.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let _ = fs::remove_file(&tmp_path); //a leftover would keep its old mode
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    //it may hold a hash chain
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut tmp = options.open(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    drop(tmp);
+    fs::rename(&tmp_path, path)?;
+    //the rename itself only survives a power cut once the directory is synced
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Hold an exclusive lock on `<path>.lock` for as long as the returned file lives, so two
+/// clients never spend preimages of the same chain at the same time.
+pub fn lock_config(path: &str) -> io::Result<File> {
+    let lock = File::create(format!("{}.lock", path))?;
+    lock.try_lock().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is already in use by another client", path),
+        )
+    })?;
+    Ok(lock)
+}
+
+/// Refuse a config file that other users can read or write, e.g. after it was copied around
+/// with a default umask. Only checked on unix, where the mode bits say who can read it.
+pub fn check_permissions(path: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} is accessible by other users (mode {:o}), run: chmod 600 {}",
+                    path, mode, path
+                ),
+            ));
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,110 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// The secret part of a client config, encrypted under a passphrase, as it is stored on disk.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SealedChain {
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Key derived from a passphrase with Argon2id. The client keeps it in memory while it runs,
+/// so every save of the config is sealed again under a fresh nonce.
+#[derive(Clone)]
+pub struct ChainKey {
+    key: [u8; 32],
+    salt: [u8; 16],
+}
+
+impl ChainKey {
+    /// A key for sealing a config that was not encrypted before, under a fresh salt.
+    pub fn new(passphrase: &str) -> anyhow::Result<Self> {
+        let mut salt = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        Self::derive(passphrase, salt)
+    }
+
+    /// The key a sealed config was encrypted with, if `passphrase` is the right one.
+    pub fn for_sealed(passphrase: &str, sealed: &SealedChain) -> anyhow::Result<Self> {
+        let salt = hex::decode(&sealed.salt)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid salt in sealed config"))?;
+        Self::derive(passphrase, salt)
+    }
+
+    fn derive(passphrase: &str, salt: [u8; 16]) -> anyhow::Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| anyhow::anyhow!("Failed to derive key from passphrase: {}", e))?;
+        Ok(Self { key, salt })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> SealedChain {
+        let mut nonce = [0u8; 12];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("encryption into a Vec can't fail");
+        SealedChain {
+            salt: hex::encode(self.salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        }
+    }
+
+    /// Decrypt a sealed config. Fails on a wrong passphrase as well as on a tampered file.
+    pub fn open(&self, sealed: &SealedChain) -> anyhow::Result<Vec<u8>> {
+        let nonce = hex::decode(&sealed.nonce)?;
+        if nonce.len() != 12 {
+            anyhow::bail!("Invalid nonce in sealed config");
+        }
+        let ciphertext = hex::decode(&sealed.ciphertext)?;
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase or corrupted config"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_chain_opens_only_with_the_passphrase() {
+        let key = ChainKey::new("correct horse").unwrap();
+        let sealed = key.seal(b"the seed");
+        assert_eq!(key.open(&sealed).unwrap(), b"the seed");
+
+        //what the client does after a restart: derive the key again from the file's salt
+        let again = ChainKey::for_sealed("correct horse", &sealed).unwrap();
+        assert_eq!(again.open(&sealed).unwrap(), b"the seed");
+        let wrong = ChainKey::for_sealed("battery staple", &sealed).unwrap();
+        assert!(wrong.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn tampered_chain_is_refused() {
+        let key = ChainKey::new("correct horse").unwrap();
+        let sealed = key.seal(b"the seed");
+        //every save gets a fresh nonce
+        assert_ne!(key.seal(b"the seed").nonce, sealed.nonce);
+
+        let mut flipped = sealed.clone();
+        let mut ciphertext = hex::decode(&flipped.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        flipped.ciphertext = hex::encode(ciphertext);
+        assert!(key.open(&flipped).is_err());
+
+        let mut short_nonce = sealed.clone();
+        short_nonce.nonce = "00".into();
+        assert!(key.open(&short_nonce).is_err());
+        let mut bad_salt = sealed;
+        bad_salt.salt = "00".into();
+        assert!(ChainKey::for_sealed("correct horse", &bad_salt).is_err());
+    }
+}
@@ -4,5 +4,6 @@ pub mod common {
     }
     pub mod helper {
         pub mod config;
+        pub mod seed_cipher;
     }
 }